@@ -4,31 +4,108 @@
 #![deny(missing_docs)]
 #![deny(clippy::missing_docs_in_private_items)]
 
-use crossbeam::channel::{bounded, Receiver, RecvError, Sender};
+use crossbeam::channel::{bounded, Receiver, Sender, TryRecvError};
 use crossbeam::queue::ArrayQueue;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use itertools::all;
+use numpy::PyReadonlyArray2;
+use pyo3::create_exception;
 use pyo3::exceptions::{
-    PyKeyError, PyNotImplementedError, PyRuntimeError, PyTypeError, PyValueError,
+    PyIOError, PyKeyError, PyNotImplementedError, PyRuntimeError, PyRuntimeWarning,
+    PyStopAsyncIteration, PyTypeError, PyUserWarning, PyValueError,
 };
+use pyo3::once_cell::GILOnceCell;
 use pyo3::prelude::*;
-use pyo3::pyclass::IterNextOutput;
-use pyo3::types::{PyIterator, PyList, PySequence, PyTuple};
+use pyo3::types::{PyBytes, PyDict, PyIterator, PyList, PySequence, PyTuple};
 use pyo3::FromPyObject;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Display, Formatter};
-use std::sync::{Arc, Mutex};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
 use std::{mem, thread};
 
 /// Strand enum
-#[pyclass]
+/// As for mappy, the forward strand is `1` and the reverse strand `-1`, so in python `int(Strand.Reverse) == -1`,
+/// and a `Strand` compares equal to its integer, e.g. `Strand.Forward == 1`.
+#[pyclass(module = "mappy_rs")]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Strand {
     /// Maps to the forward strand
-    Forward,
+    Forward = 1,
     /// Maps to the Reverse strand
-    Reverse,
+    Reverse = -1,
+}
+
+create_exception!(
+    mappy_rs,
+    NoIndex,
+    PyRuntimeError,
+    "The `Aligner` has no index loaded."
+);
+create_exception!(
+    mappy_rs,
+    MapFailed,
+    PyRuntimeError,
+    "minimap2 failed to map a read."
+);
+create_exception!(
+    mappy_rs,
+    ThreadingNotEnabled,
+    PyRuntimeError,
+    "A multi threaded method was called before `enable_threading`."
+);
+create_exception!(
+    mappy_rs,
+    QueueFull,
+    PyRuntimeError,
+    "A read could not be added to the work queue of `map_batch`, as it is full."
+);
+
+/// Errors from mappy-rs. Each is raised in python as its own subclass of `RuntimeError`, exported by the module,
+/// so they can be caught individually, e.g. `except mappy_rs.QueueFull`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MappyError {
+    /// The `Aligner` has no index loaded
+    NoIndex,
+    /// minimap2 failed to map a read, with a description of why
+    MapFailed(String),
+    /// Threading is needed, but `enable_threading` has not been called
+    ThreadingNotEnabled,
+    /// A read could not be added to the full work queue, with a description of why
+    QueueFull(String),
+}
+
+/// Implement `Display` for `MappyError`, the message of the python exception.
+impl Display for MappyError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            MappyError::NoIndex => write!(f, "Index hasn't loaded"),
+            MappyError::MapFailed(reason) | MappyError::QueueFull(reason) => write!(f, "{reason}"),
+            MappyError::ThreadingNotEnabled => write!(
+                f,
+                "Multi threading not enabled on this instance. Please call `.enable_threading(n_threads)` on this \
+                 Aligner before mapping a batch. Threading is not carried over to copies or unpickled Aligners, \
+                 and is disabled again by `join()`"
+            ),
+        }
+    }
+}
+
+impl From<MappyError> for PyErr {
+    /// Convert into the python exception for each variant.
+    fn from(err: MappyError) -> PyErr {
+        let message = err.to_string();
+        match err {
+            MappyError::NoIndex => NoIndex::new_err(message),
+            MappyError::MapFailed(_) => MapFailed::new_err(message),
+            MappyError::ThreadingNotEnabled => ThreadingNotEnabled::new_err(message),
+            MappyError::QueueFull(_) => QueueFull::new_err(message),
+        }
+    }
 }
 
 /// Enum containing results from multithreaded Alignment
@@ -36,12 +113,27 @@ pub enum Strand {
 enum WorkQueue<T> {
     /// Lemme see you work work work work, shorty sumthin sumthin
     Work(T),
+    /// Several reads, mapped one after another by the same worker thread, see `map_batch(chunk_size=...)`
+    Chunk(Vec<T>),
     /// The threads are finished
     Done,
     /// Result of multi threaded mapping queue
     Result(T),
     /// All threads have finished
     Finished,
+    /// A read which could not be mapped, by its id and the reason why, with its dictionary if that travelled with it
+    Failed(usize, String, Option<HashMap<String, Py<PyAny>>>),
+}
+
+impl<T> WorkQueue<T> {
+    /// The reads of a `Work` or `Chunk` item, none for any other variant.
+    fn into_reads(self) -> Vec<T> {
+        match self {
+            WorkQueue::Work(read) => vec![read],
+            WorkQueue::Chunk(reads) => reads,
+            _ => vec![],
+        }
+    }
 }
 
 /// Implement `Display` for `Strand`.
@@ -93,10 +185,17 @@ impl Strand {
 ///         block_len: 10,
 ///         mapq: 69,
 ///         is_primary: true,
+///         is_supplementary: false,
 ///         cigar: vec![(10, 11)],
 ///         NM: 10,
 ///         MD: None,
-///         cs: None
+///         cs: None,
+///         trans_strand: None,
+///         s1: 0,
+///         s2: 0,
+///         query_name: None,
+///         frag_id: None,
+///         query_len: None,
 ///     };
 ///     // valid
 ///     assert!(m.target_start == 10); // also gets the mapping start
@@ -139,18 +238,595 @@ pub struct Mapping {
     /// Alignment is primary or not
     #[pyo3(get)]
     pub is_primary: bool,
+    /// Alignment is a supplementary part of a chimeric read, a primary alignment of another part of the read than
+    /// its representative alignment, flagged `0x800` in SAM. Always `false` for a secondary alignment
+    #[pyo3(get)]
+    pub is_supplementary: bool,
     /// The CIGAR operations/numbers of the alignment
     #[pyo3(get)]
     pub cigar: Vec<(u32, u8)>,
     /// Total number of matchs, mismatches and gaps in the alignment
     #[pyo3(get)]
     pub NM: i32,
-    /// MD string of the alignment
+    /// MD string of the alignment, `None` unless requested with `MD=True`, as for `mappy.Alignment.MD`
     #[pyo3(get)]
     pub MD: Option<String>,
-    /// CIGAR string
+    /// cs string of the alignment, `None` unless requested with `cs=True`, as for `mappy.Alignment.cs`
     #[pyo3(get)]
     pub cs: Option<String>,
+    /// Transcript strand of a spliced alignment (`ts:A:`), `None` if unknown or not spliced
+    pub trans_strand: Option<Strand>,
+    /// Chaining score of this mapping (`s1:i:`)
+    #[pyo3(get)]
+    pub s1: i32,
+    /// Best chaining score of the secondary chains overlapping this mapping (`s2:i:`), used to calculate mapq
+    #[pyo3(get)]
+    pub s2: i32,
+    /// Name of the query that was mapped, if known. Set by `map_batch` with `attach_query_name=True`
+    #[pyo3(get)]
+    pub query_name: Option<String>,
+    /// Id of the segment of a multi-segment read this mapping is of, for reassembling fragments.
+    /// `None` if the mapping did not come from minimap2
+    #[pyo3(get)]
+    pub frag_id: Option<i32>,
+    /// Length of the query that was mapped, set by `map` and `map_batch`. `None` if the mapping did not come from minimap2
+    #[pyo3(get)]
+    pub query_len: Option<i32>,
+}
+
+impl Mapping {
+    /// Build a `Mapping` from a hit returned by minimap2, generating the `cs` and `MD` tags if asked to.
+    ///
+    /// # Safety
+    /// `reg` must be a hit returned by `mm_map` for `seq` against `idx`.
+    unsafe fn from_reg(
+        idx: &minimap2_sys::mm_idx_t,
+        reg: &minimap2_sys::mm_reg1_t,
+        seq: &[u8],
+        cs: bool,
+        md: bool,
+    ) -> Mapping {
+        let contig = &*idx.seq.offset(reg.rid as isize);
+        let (cigar, nm, trans_strand) = match reg.p.as_ref() {
+            Some(p) => (
+                p.cigar
+                    .as_slice(p.n_cigar as usize)
+                    .iter()
+                    .map(|c| (c >> 4, (c & 0xf) as u8))
+                    .collect(),
+                reg.blen - reg.mlen + p.n_ambi() as i32,
+                match p.trans_strand() {
+                    1 => Some(Strand::Forward),
+                    2 => Some(Strand::Reverse),
+                    _ => None,
+                },
+            ),
+            None => (vec![], 0, None),
+        };
+        let (cs, md) = if !reg.p.is_null() && (cs || md) {
+            let mut buf: *mut libc::c_char = std::ptr::null_mut();
+            let mut max_len: libc::c_int = 0;
+            let seq = seq.as_ptr() as *const libc::c_char;
+            let cs = cs.then(|| {
+                minimap2_sys::mm_gen_cs(
+                    std::ptr::null_mut(),
+                    &mut buf,
+                    &mut max_len,
+                    idx,
+                    reg,
+                    seq,
+                    1,
+                );
+                std::ffi::CStr::from_ptr(buf).to_string_lossy().into_owned()
+            });
+            let md = md.then(|| {
+                minimap2_sys::mm_gen_MD(
+                    std::ptr::null_mut(),
+                    &mut buf,
+                    &mut max_len,
+                    idx,
+                    reg,
+                    seq,
+                );
+                std::ffi::CStr::from_ptr(buf).to_string_lossy().into_owned()
+            });
+            libc::free(buf as *mut libc::c_void);
+            (cs, md)
+        } else {
+            (None, None)
+        };
+        Mapping {
+            query_start: reg.qs,
+            query_end: reg.qe,
+            strand: if reg.rev() == 0 {
+                Strand::Forward
+            } else {
+                Strand::Reverse
+            },
+            target_name: std::ffi::CStr::from_ptr(contig.name)
+                .to_string_lossy()
+                .into_owned(),
+            target_len: contig.len as i32,
+            target_start: reg.rs,
+            target_end: reg.re,
+            match_len: reg.mlen,
+            block_len: reg.blen,
+            mapq: reg.mapq(),
+            is_primary: reg.id == reg.parent,
+            is_supplementary: reg.id == reg.parent && reg.sam_pri() == 0,
+            cigar,
+            NM: nm,
+            MD: md,
+            cs,
+            trans_strand,
+            s1: reg.score,
+            s2: reg.subsc,
+            query_name: None,
+            frag_id: Some(reg.seg_id() as i32),
+            query_len: Some(seq.len() as i32),
+        }
+    }
+
+    /// Columns of the TSV written by `AlignmentBatchResultIter.to_tsv`, the read id then every field of a `Mapping`
+    const TSV_COLUMNS: [&'static str; 23] = [
+        "read_id",
+        "query_name",
+        "query_start",
+        "query_end",
+        "strand",
+        "target_name",
+        "target_len",
+        "target_start",
+        "target_end",
+        "match_len",
+        "block_len",
+        "mapq",
+        "is_primary",
+        "is_supplementary",
+        "cigar",
+        "NM",
+        "MD",
+        "cs",
+        "trans_strand",
+        "s1",
+        "s2",
+        "frag_id",
+        "query_len",
+    ];
+
+    /// Format this `Mapping` as a row of `TSV_COLUMNS`, without a trailing newline. Fields which are `None` are left empty.
+    fn to_tsv_row(&self, read_id: &str) -> String {
+        let or_empty = |field: Option<String>| field.unwrap_or_default();
+        [
+            read_id.to_string(),
+            or_empty(self.query_name.clone()),
+            self.query_start.to_string(),
+            self.query_end.to_string(),
+            self.strand.to_string(),
+            self.target_name.clone(),
+            self.target_len.to_string(),
+            self.target_start.to_string(),
+            self.target_end.to_string(),
+            self.match_len.to_string(),
+            self.block_len.to_string(),
+            self.mapq.to_string(),
+            self.is_primary.to_string(),
+            self.is_supplementary.to_string(),
+            self.get_cigar_str().unwrap_or_default(),
+            self.NM.to_string(),
+            or_empty(self.MD.clone()),
+            or_empty(self.cs.clone()),
+            or_empty(self.trans_strand.map(|strand| strand.to_string())),
+            self.s1.to_string(),
+            self.s2.to_string(),
+            or_empty(self.frag_id.map(|frag_id| frag_id.to_string())),
+            or_empty(self.query_len.map(|query_len| query_len.to_string())),
+        ]
+        .join("\t")
+    }
+}
+
+/// A minimap2 thread buffer, holding the working memory `mm_map` uses while mapping a read
+struct ThreadBuffer(*mut minimap2_sys::mm_tbuf_t);
+
+impl ThreadBuffer {
+    /// Allocate a new thread buffer
+    fn new() -> ThreadBuffer {
+        ThreadBuffer(unsafe { minimap2_sys::mm_tbuf_init() })
+    }
+}
+
+impl Drop for ThreadBuffer {
+    fn drop(&mut self) {
+        unsafe { minimap2_sys::mm_tbuf_destroy(self.0) }
+    }
+}
+
+thread_local! {
+    /// Thread buffer for each rayon thread, used to map the reads of every `Aligner` using `enable_threading_rayon`
+    static RAYON_BUF: ThreadBuffer = ThreadBuffer::new();
+}
+
+/// Options applied to the mappings for a single read, after minimap2 has been called.
+/// Shared between the blocking `map` and the worker threads used by `map_batch`.
+#[derive(Debug, Clone, Default)]
+struct MapFilter {
+    /// Maximum number of mappings to keep for a read
+    max_hits: Option<usize>,
+    /// Only keep mappings to these contigs, if set
+    target_names: Option<FnvHashSet<String>>,
+    /// Reads shorter than this are reported unmapped without calling minimap2, if set
+    min_query_len: Option<usize>,
+    /// Raise the mapq of any mapping below this to it, if set
+    mapq_min: Option<u32>,
+    /// Lower the mapq of any mapping above this to it, if set, e.g. to replace minimap2's 255 for a missing mapq
+    mapq_max: Option<u32>,
+}
+
+/// Running totals of the reads mapped by an `Aligner`, updated by the blocking methods and the worker threads.
+#[derive(Debug, Default)]
+struct MapCounts {
+    /// Number of reads mapped
+    reads: AtomicUsize,
+    /// Number of those reads with no mappings
+    unmapped: AtomicUsize,
+    /// Number of primary mappings to each contig
+    contig_hits: Mutex<HashMap<String, u64>>,
+}
+
+impl MapCounts {
+    /// Count a mapped read, as unmapped if it has no `mappings`, and count its primary mappings to each contig.
+    fn record(&self, mappings: &[Mapping]) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if mappings.is_empty() {
+            self.unmapped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut contig_hits = self.contig_hits.lock().unwrap();
+        for mapping in mappings.iter().filter(|m| m.is_primary) {
+            *contig_hits.entry(mapping.target_name.clone()).or_insert(0) += 1;
+        }
+    }
+}
+
+/// State for a single call to `map_batch`, shared between the feeding thread, the worker threads
+/// and the collector thread. Each batch gets its own results queue, so that batches submitted to the
+/// same `Aligner` concurrently never see each others results.
+#[derive(Debug)]
+#[allow(clippy::type_complexity)]
+struct Batch {
+    /// Filter applied to the mappings of every read in this batch
+    filter: MapFilter,
+    /// Results of the worker threads for this batch go here
+    results_queue: ArrayQueue<WorkQueue<(Vec<Mapping>, usize, Option<HashMap<String, Py<PyAny>>>)>>,
+    /// Set when the batch is cancelled, workers skip any remaining reads and the collector thread exits
+    cancelled: AtomicBool,
+    /// Set by the collector thread once every worker thread has finished the batch, so all of its results have been
+    /// passed on to its iterator
+    collected: AtomicBool,
+    /// Workers return `no_op_mappings()` for every read instead of calling minimap2, for benchmarking
+    no_op: bool,
+    /// Workers return every read with no mappings instead of calling minimap2, to validate the input alone
+    dry_run: bool,
+    /// Most reads which can be fed but not yet returned by the iterator, if set
+    max_inflight: Option<usize>,
+    /// Number of reads fed but not yet returned, counted only if `max_inflight` is set
+    inflight: AtomicUsize,
+    /// Number of work items handed to the rayon pool but not yet mapped, plus one until the batch's `Done` is taken off
+    /// the work queue. Whichever of the dispatcher or the pool's tasks brings it to 0 passes the `Done` on, see
+    /// `enable_threading_rayon`
+    pooled: AtomicUsize,
+    /// Number of reads fed to the work queue in each work item, see `ReadFeeder`
+    chunk_size: usize,
+    /// Most bytes of sequence which can wait in the work queue, if set
+    max_queue_bytes: Option<usize>,
+    /// Bytes of sequence in the work queue not yet taken by a worker, counted only if `max_queue_bytes` is set
+    queued_bytes: AtomicUsize,
+    /// Workers panic on the read with this index, set by tests of how a panicking worker is reported
+    #[cfg(test)]
+    panic_on: Option<usize>,
+}
+
+/// What `map_batch` does with a read which fails to map, or is malformed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum OnError {
+    /// Raise an exception for the read
+    #[default]
+    Raise,
+    /// Emit a `RuntimeWarning` and return the read unmapped
+    Warn,
+    /// Drop the read without returning it
+    Skip,
+}
+
+impl OnError {
+    /// Parse the `on_error` argument of `map_batch`.
+    fn parse(on_error: &str) -> PyResult<OnError> {
+        match on_error {
+            "raise" => Ok(OnError::Raise),
+            "warn" => Ok(OnError::Warn),
+            "skip" => Ok(OnError::Skip),
+            _ => Err(PyValueError::new_err(format!(
+                "`on_error` must be one of \"raise\", \"warn\" or \"skip\", not {on_error:?}"
+            ))),
+        }
+    }
+}
+
+/// How `push_work` retries when the work queue is full.
+#[derive(Debug, Clone, Copy)]
+struct BackOff {
+    /// Number of retries before giving up on a read
+    max_attempts: u32,
+    /// Sleep before the first retry, doubled after each retry
+    initial_sleep: Duration,
+    /// Longest sleep between retries, if set, otherwise the sleep keeps doubling
+    max_sleep: Option<Duration>,
+}
+
+impl Default for BackOff {
+    fn default() -> Self {
+        BackOff {
+            max_attempts: 6,
+            initial_sleep: Duration::from_millis(50),
+            max_sleep: None,
+        }
+    }
+}
+
+impl BackOff {
+    /// Retry every millisecond until the read is added, so whether it is added never depends on timing.
+    fn wait_forever() -> BackOff {
+        BackOff {
+            max_attempts: u32::MAX,
+            initial_sleep: Duration::from_millis(1),
+            max_sleep: Some(Duration::from_millis(1)),
+        }
+    }
+}
+
+/// The keyword options of `map_batch`, also taken by `map_batch_numpy` and `map_batch_df`, see `map_batch`.
+/// The defaults are those of `map_batch`.
+#[derive(Debug)]
+struct MapBatchOptions {
+    /// Retry with a backoff if the work queue is full
+    back_off: bool,
+    /// See `MapFilter`
+    max_hits: Option<usize>,
+    /// See `MapFilter`
+    target_names: Option<Vec<String>>,
+    /// See `MapFilter`
+    min_query_len: Option<usize>,
+    /// Set the `query_name` of each mapping from the `name` or `id` of its read
+    attach_query_name: bool,
+    /// Python callable recalculating the mapq of each mapping from `(s1, s2, block_len)`
+    mapq_fn: Option<PyObject>,
+    /// See `BackOff`
+    max_backoff_attempts: u32,
+    /// See `BackOff`
+    initial_backoff_ms: u64,
+    /// See `Batch`
+    no_op: bool,
+    /// Size of the results channel and the results queue
+    result_bound: usize,
+    /// Carry each read's dictionary through the work queue, rather than holding it in the iterator
+    carry_metadata: bool,
+    /// What to do with reads which fail to map, or are malformed
+    on_error: OnError,
+    /// Yield each read's dictionary alongside its mappings
+    yield_metadata: bool,
+    /// See `Batch`
+    max_inflight: Option<usize>,
+    /// Return results in the order of the reads, and wait on a full work queue for as long as it takes
+    deterministic: bool,
+    /// See `Batch`
+    dry_run: bool,
+    /// Yield the mappings of each read as PAF lines
+    as_paf: bool,
+    /// See `Batch`
+    chunk_size: usize,
+    /// See `MapFilter`
+    mapq_min: Option<u32>,
+    /// See `MapFilter`
+    mapq_max: Option<u32>,
+    /// See `Batch`
+    max_queue_bytes: Option<usize>,
+}
+
+impl Default for MapBatchOptions {
+    fn default() -> Self {
+        MapBatchOptions {
+            back_off: true,
+            max_hits: None,
+            target_names: None,
+            min_query_len: None,
+            attach_query_name: false,
+            mapq_fn: None,
+            max_backoff_attempts: 6,
+            initial_backoff_ms: 50,
+            no_op: false,
+            result_bound: 20000,
+            carry_metadata: false,
+            on_error: OnError::Raise,
+            yield_metadata: true,
+            max_inflight: None,
+            deterministic: false,
+            dry_run: false,
+            as_paf: false,
+            chunk_size: 1,
+            mapq_min: None,
+            mapq_max: None,
+            max_queue_bytes: None,
+        }
+    }
+}
+
+impl MapBatchOptions {
+    /// Read the keyword arguments `kwargs` given to the python method `method`, leaving any not given at their
+    /// defaults. As for any python function, a `TypeError` is raised for an unknown keyword, or a value of the wrong type.
+    fn from_kwargs(method: &str, kwargs: Option<&PyDict>) -> PyResult<MapBatchOptions> {
+        let mut options = MapBatchOptions::default();
+        for (key, value) in kwargs.into_iter().flatten() {
+            let key: &str = key.extract()?;
+            let arg_err =
+                |e: PyErr| PyTypeError::new_err(format!("{method}() argument '{key}': {e}"));
+            match key {
+                "back_off" => options.back_off = value.extract().map_err(arg_err)?,
+                "max_hits" => options.max_hits = value.extract().map_err(arg_err)?,
+                "target_names" => options.target_names = value.extract().map_err(arg_err)?,
+                "min_query_len" => options.min_query_len = value.extract().map_err(arg_err)?,
+                "attach_query_name" => {
+                    options.attach_query_name = value.extract().map_err(arg_err)?
+                }
+                "mapq_fn" => options.mapq_fn = value.extract().map_err(arg_err)?,
+                "max_backoff_attempts" => {
+                    options.max_backoff_attempts = value.extract().map_err(arg_err)?
+                }
+                "initial_backoff_ms" => {
+                    options.initial_backoff_ms = value.extract().map_err(arg_err)?
+                }
+                "no_op" => options.no_op = value.extract().map_err(arg_err)?,
+                "result_bound" => options.result_bound = value.extract().map_err(arg_err)?,
+                "carry_metadata" => options.carry_metadata = value.extract().map_err(arg_err)?,
+                "on_error" => options.on_error = OnError::parse(value.extract().map_err(arg_err)?)?,
+                "yield_metadata" => options.yield_metadata = value.extract().map_err(arg_err)?,
+                "max_inflight" => options.max_inflight = value.extract().map_err(arg_err)?,
+                "deterministic" => options.deterministic = value.extract().map_err(arg_err)?,
+                "dry_run" => options.dry_run = value.extract().map_err(arg_err)?,
+                "as_paf" => options.as_paf = value.extract().map_err(arg_err)?,
+                "chunk_size" => options.chunk_size = value.extract().map_err(arg_err)?,
+                "mapq_min" => options.mapq_min = value.extract().map_err(arg_err)?,
+                "mapq_max" => options.mapq_max = value.extract().map_err(arg_err)?,
+                "max_queue_bytes" => options.max_queue_bytes = value.extract().map_err(arg_err)?,
+                _ => {
+                    return Err(PyTypeError::new_err(format!(
+                        "{method}() got an unexpected keyword argument '{key}'"
+                    )))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl Batch {
+    /// Create a new batch, with an empty results queue.
+    fn new(filter: MapFilter) -> Batch {
+        Batch {
+            filter,
+            results_queue: ArrayQueue::new(50000),
+            cancelled: AtomicBool::new(false),
+            collected: AtomicBool::new(false),
+            no_op: false,
+            dry_run: false,
+            max_inflight: None,
+            inflight: AtomicUsize::new(0),
+            pooled: AtomicUsize::new(1),
+            chunk_size: 1,
+            max_queue_bytes: None,
+            queued_bytes: AtomicUsize::new(0),
+            #[cfg(test)]
+            panic_on: None,
+        }
+    }
+
+    /// Whether `n_reads` reads must be fed from another thread, as they would wait on `max_inflight` or
+    /// `max_queue_bytes`, or for room in the results queue, so results must be returned meanwhile.
+    fn feeds_lazily(&self, n_reads: usize) -> bool {
+        self.max_inflight.is_some()
+            || self.max_queue_bytes.is_some()
+            || n_reads > self.results_queue.capacity()
+    }
+
+    /// Whether this batch has been cancelled
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Whether this batch is still being mapped, neither cancelled nor collected
+    fn is_running(&self) -> bool {
+        !self.is_cancelled() && !self.collected.load(Ordering::SeqCst)
+    }
+
+    /// Wait until fewer than `max_inflight` reads are waiting to be returned, then count one more.
+    /// Returns straight away if `max_inflight` is not set, or the batch is cancelled.
+    fn acquire_inflight(&self) {
+        if let Some(max_inflight) = self.max_inflight {
+            while self.inflight.load(Ordering::SeqCst) >= max_inflight && !self.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            self.inflight.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Count a read as returned, see `acquire_inflight`.
+    fn release_inflight(&self) {
+        if self.max_inflight.is_some() {
+            self.inflight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Wait until `n_bytes` more sequence fits in `max_queue_bytes`, then count it as queued.
+    /// A single work item larger than the budget is let through once the queue is otherwise empty, so it can't stall.
+    /// Returns straight away if `max_queue_bytes` is not set, or the batch is cancelled.
+    fn acquire_queue_bytes(&self, n_bytes: usize) {
+        if let Some(max_queue_bytes) = self.max_queue_bytes {
+            loop {
+                let queued = self.queued_bytes.load(Ordering::SeqCst);
+                if queued == 0 || queued + n_bytes <= max_queue_bytes || self.is_cancelled() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            self.queued_bytes.fetch_add(n_bytes, Ordering::SeqCst);
+        }
+    }
+
+    /// Count `n_bytes` of sequence as taken off the work queue, see `acquire_queue_bytes`.
+    fn release_queue_bytes(&self, n_bytes: usize) {
+        if self.max_queue_bytes.is_some() {
+            self.queued_bytes.fetch_sub(n_bytes, Ordering::SeqCst);
+        }
+    }
+}
+
+impl MapFilter {
+    /// Error if the mapq range is empty, with `mapq_min` above `mapq_max`.
+    fn check_mapq_range(mapq_min: Option<u32>, mapq_max: Option<u32>) -> PyResult<()> {
+        if let (Some(mapq_min), Some(mapq_max)) = (mapq_min, mapq_max) {
+            if mapq_min > mapq_max {
+                return Err(PyValueError::new_err(format!(
+                    "`mapq_min` ({mapq_min}) can't be more than `mapq_max` ({mapq_max})"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply this filter to the mappings of a single read, in place.
+    /// Mappings to contigs not in `target_names` are dropped first, then if `max_hits` is set,
+    /// the primary mappings with the highest mapq are kept, and finally each mapq is clamped to `mapq_min`/`mapq_max`.
+    fn apply(&self, mappings: &mut Vec<Mapping>) {
+        if let Some(target_names) = &self.target_names {
+            mappings.retain(|m| target_names.contains(&m.target_name));
+        }
+        if let Some(max_hits) = self.max_hits {
+            if mappings.len() > max_hits {
+                // Stable sort, so minimap2's own ordering breaks ties
+                mappings.sort_by(|a, b| b.is_primary.cmp(&a.is_primary).then(b.mapq.cmp(&a.mapq)));
+                mappings.truncate(max_hits);
+            }
+        }
+        // Clamped last, so `max_hits` still keeps the mappings minimap2 rated highest
+        if self.mapq_min.is_some() || self.mapq_max.is_some() {
+            for mapping in mappings.iter_mut() {
+                mapping.mapq = mapping
+                    .mapq
+                    .max(self.mapq_min.unwrap_or(u32::MIN))
+                    .min(self.mapq_max.unwrap_or(u32::MAX));
+            }
+        }
+    }
 }
 
 /// Implement `Display` for `Mapping`. Writes out a paf formatted Mapping result.
@@ -179,6 +855,221 @@ impl Display for Mapping {
     }
 }
 
+/// Get the SAM/PAF character for a CIGAR operation code, or `None` if the code is invalid.
+fn cigar_op_char(op: u8) -> Option<char> {
+    "MIDNSHP=X".chars().nth(op as usize)
+}
+
+/// Parse a CIGAR string, e.g. `10M2I5M`, into `(length, operation code)` pairs, or `None` if it is invalid.
+fn parse_cigar(cigar: &str) -> Option<Vec<(u32, u8)>> {
+    let mut ops = vec![];
+    let mut start = 0;
+    for (i, c) in cigar.char_indices() {
+        if !c.is_ascii_digit() {
+            let len = cigar[start..i].parse().ok()?;
+            let op = "MIDNSHP=X".find(c)? as u8;
+            ops.push((len, op));
+            start = i + 1;
+        }
+    }
+    (start == cigar.len()).then_some(ops)
+}
+
+/// Writes mappings as full PAF lines, including the query name and length, to any `Write`.
+/// Fields are written straight to the writer, without formatting each record into a `String` first.
+///
+/// # Examples
+///
+/// ```
+///     use mappy_rs::{Mapping, PafWriter, Strand};
+///     let m = Mapping {
+///         query_start: 0,
+///         query_end: 10,
+///         strand: Strand::Forward,
+///         target_name: String::from("Shark_bait"),
+///         target_len: 100,
+///         target_start: 10,
+///         target_end: 20,
+///         match_len: 10,
+///         block_len: 10,
+///         mapq: 60,
+///         is_primary: true,
+///         is_supplementary: false,
+///         cigar: vec![(10, 0)],
+///         NM: 0,
+///         MD: None,
+///         cs: None,
+///         trans_strand: None,
+///         s1: 0,
+///         s2: 0,
+///         query_name: None,
+///         frag_id: None,
+///         query_len: None,
+///     };
+///     let mut writer = PafWriter::new(vec![]);
+///     writer.write_mapping("read_1", 10, &m).unwrap();
+///     assert!(writer.into_inner().starts_with(b"read_1\t10\t0\t10\t+\tShark_bait"));
+/// ```
+pub struct PafWriter<W: Write> {
+    /// Writer the PAF lines are written to
+    writer: W,
+}
+
+impl<W: Write> PafWriter<W> {
+    /// Create a new `PafWriter`, writing to `writer`.
+    pub fn new(writer: W) -> PafWriter<W> {
+        PafWriter { writer }
+    }
+
+    /// Write a single mapping of the query `query_name`, of length `query_len`, as a PAF line.
+    /// The `cg:Z:` tag is only written if the mapping has a CIGAR.
+    pub fn write_mapping(
+        &mut self,
+        query_name: &str,
+        query_len: i32,
+        m: &Mapping,
+    ) -> std::io::Result<()> {
+        write!(
+            self.writer,
+            "{query_name}\t{query_len}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\ttp:A:{}",
+            m.query_start,
+            m.query_end,
+            m.strand,
+            m.target_name,
+            m.target_len,
+            m.target_start,
+            m.target_end,
+            m.match_len,
+            m.block_len,
+            m.mapq,
+            if m.is_primary { 'P' } else { 'S' },
+        )?;
+        if !m.cigar.is_empty() {
+            self.writer.write_all(b"\tcg:Z:")?;
+            for (len, op) in &m.cigar {
+                let op = cigar_op_char(*op).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Invalid CIGAR code `{op}`"),
+                    )
+                })?;
+                write!(self.writer, "{len}{op}")?;
+            }
+        }
+        self.writer.write_all(b"\n")
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Consume the `PafWriter`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// A `Mapping` viewed as a PAF record, alongside the name and length of the query, returned by `Aligner.map_paf`.
+/// Each of the twelve mandatory PAF columns has an accessor, and `str()` gives the PAF line.
+#[pyclass]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PafHit {
+    /// Query sequence name, column 1
+    #[pyo3(get)]
+    pub query_name: String,
+    /// Query sequence length, column 2
+    #[pyo3(get)]
+    pub query_length: i32,
+    /// The mapping this record describes
+    #[pyo3(get)]
+    pub mapping: Mapping,
+}
+
+#[pymethods]
+impl PafHit {
+    /// Query start, 0-based, column 3
+    #[getter]
+    fn query_start(&self) -> i32 {
+        self.mapping.query_start
+    }
+
+    /// Query end, 0-based exclusive, column 4
+    #[getter]
+    fn query_end(&self) -> i32 {
+        self.mapping.query_end
+    }
+
+    /// Relative strand, `+` or `-`, column 5
+    #[getter]
+    fn strand(&self) -> String {
+        self.mapping.strand.to_string()
+    }
+
+    /// Target sequence name, column 6
+    #[getter]
+    fn target_name(&self) -> String {
+        self.mapping.target_name.clone()
+    }
+
+    /// Target sequence length, column 7
+    #[getter]
+    fn target_length(&self) -> i32 {
+        self.mapping.target_len
+    }
+
+    /// Target start, 0-based, column 8
+    #[getter]
+    fn target_start(&self) -> i32 {
+        self.mapping.target_start
+    }
+
+    /// Target end, 0-based exclusive, column 9
+    #[getter]
+    fn target_end(&self) -> i32 {
+        self.mapping.target_end
+    }
+
+    /// Number of matching bases, column 10
+    #[getter]
+    fn residue_matches(&self) -> i32 {
+        self.mapping.match_len
+    }
+
+    /// Alignment block length, including gaps, column 11
+    #[getter]
+    fn alignment_block_length(&self) -> i32 {
+        self.mapping.block_len
+    }
+
+    /// Mapping quality, 0-255 with 255 for missing, column 12
+    #[getter]
+    fn mapping_quality(&self) -> u32 {
+        self.mapping.mapq
+    }
+
+    /// The optional SAM-style tags following the mandatory columns, e.g. `["tp:A:P", "cg:Z:400M"]`
+    #[getter]
+    fn tags(&self) -> PyResult<Vec<String>> {
+        Ok(self
+            .__str__()?
+            .split('\t')
+            .skip(12)
+            .map(String::from)
+            .collect())
+    }
+
+    /// The PAF line, without a trailing newline
+    fn __str__(&self) -> PyResult<String> {
+        self.mapping.to_paf(&self.query_name, self.query_length)
+    }
+
+    /// Implement the python `repr()` method.
+    fn __repr__(&self) -> String {
+        format!("{self:#?}")
+    }
+}
+
 /// Selection of python methods for an Alignment Mapping.
 #[pymethods]
 impl Mapping {
@@ -197,6 +1088,11 @@ impl Mapping {
     fn get_target_name(&self) -> PyResult<String> {
         Ok(self.target_name.clone())
     }
+    /// Get the length of the query that was mapped from a `Mapping`, if known. Alias for `query_len`
+    #[getter(q_len)]
+    fn get_query_len(&self) -> PyResult<Option<i32>> {
+        Ok(self.query_len)
+    }
     /// Get the target contig length from a `Mapping`. Alias for `mappy.Alignment.ctg_len`
     #[getter(ctg_len)]
     fn get_target_len(&self) -> PyResult<i32> {
@@ -230,10 +1126,14 @@ impl Mapping {
     /// Get the strand from a `Mapping`. Alias for `mappy.Alignment.strand`
     #[getter(strand)]
     fn get_strand(&self) -> PyResult<i32> {
-        Ok(match self.strand {
-            Strand::Forward => 1,
-            Strand::Reverse => -1,
-        })
+        Ok(self.strand as i32)
+    }
+
+    /// Get the transcript strand of a spliced alignment, 1 for forward, -1 for reverse or `None` if unknown.
+    /// Alias for `mappy.Alignment.trans_strand`
+    #[getter(trans_strand)]
+    fn get_trans_strand(&self) -> PyResult<Option<i32>> {
+        Ok(self.trans_strand.map(|strand| strand as i32))
     }
 
     /// Get the alignment block length from a `Mapping`. Alias for `mappy.Alignment.blen`
@@ -249,32 +1149,19 @@ impl Mapping {
     }
 
     /// Get the cigar string from a `Mapping`. Alias for `mappy.Alignment.cigar_str`
+    /// Built from `cigar` each time it is read, written straight into a single string, so it never goes stale.
     #[getter(cigar_str)]
     fn get_cigar_str(&self) -> PyResult<String> {
-        let strs = self
-            .cigar
-            .clone()
-            .into_iter()
-            .map(|(n, op)| {
-                let c = match op {
-                    0 => "M",
-                    1 => "I",
-                    2 => "D",
-                    3 => "N",
-                    4 => "S",
-                    5 => "H",
-                    6 => "P",
-                    7 => "=",
-                    8 => "X",
-                    _ => return Err("Invalid CIGAR code `{op}`"),
-                };
-                Ok(format!("{n}{c}"))
-            })
-            .collect::<Result<Vec<_>, _>>();
-        match strs {
-            Ok(cstr) => Ok(cstr.join("")),
-            Err(err) => Err(PyValueError::new_err(err)),
+        use std::fmt::Write as _;
+        // Most operations have a length of at most four digits
+        let mut cigar_str = String::with_capacity(self.cigar.len() * 5);
+        for &(n, op) in &self.cigar {
+            let Some(c) = cigar_op_char(op) else {
+                return Err(PyValueError::new_err(format!("Invalid CIGAR code `{op}`")));
+            };
+            write!(cigar_str, "{n}{c}").unwrap();
         }
+        Ok(cigar_str)
     }
 
     /// Return whether this `Mapping` is a primary mapping. Alias for `mappy.Alignment.is_primary`
@@ -282,108 +1169,747 @@ impl Mapping {
     fn get_is_primary(&self) -> PyResult<bool> {
         Ok(self.is_primary)
     }
-}
 
-/// Aligner struct, mimicking minimap2's python interface
-#[pyclass(unsendable)]
-#[allow(clippy::type_complexity)]
+    /// Get the `cs` string as ASCII `bytes`, or `None` if it wasn't requested. Unlike `cs`, the tag isn't decoded
+    /// to a `str`, and a `memoryview` of the `bytes` can be handed on to C code through the buffer protocol
+    /// without any further copies, which helps when post-processing very long or very many tags.
+    #[getter]
+    fn cs_bytes<'py>(&self, py: Python<'py>) -> Option<&'py PyBytes> {
+        self.cs.as_ref().map(|cs| PyBytes::new(py, cs.as_bytes()))
+    }
 
-pub struct Aligner {
-    /// Inner minimap2::Aligner
-    pub aligner: minimap2::Aligner,
-    /// Number of mapping threads
-    n_threads: usize,
-    /// thread handles
-    _handles: Arc<Mutex<Vec<std::thread::JoinHandle<()>>>>,
-    /// stop the threads
-    stop: Arc<Mutex<bool>>,
-    /// Work queue stores strings to map and ids to get the corresponding dict back
-    work_queue: Arc<ArrayQueue<WorkQueue<(usize, String)>>>,
-    /// Results of the threads go here
-    results_queue: Arc<ArrayQueue<WorkQueue<(Vec<Mapping>, usize)>>>,
-}
-// unsafe impl Send for Aligner {}
+    /// Get the `MD` string as ASCII `bytes`, or `None` if it wasn't requested, see `cs_bytes`.
+    #[getter]
+    fn md_bytes<'py>(&self, py: Python<'py>) -> Option<&'py PyBytes> {
+        self.MD.as_ref().map(|md| PyBytes::new(py, md.as_bytes()))
+    }
 
-#[pymethods]
-impl Aligner {
-    /// Initialise a new Py Class Aligner
-    /// Aligner struct, mimicking minimap2's python interface
-    #[new]
-    #[pyo3(signature = (fn_idx_in=None, preset=None, k=None, w=None, min_cnt=None, min_chain_score=None, min_dp_score=None, bw=None, best_n=None, n_threads=3, fn_idx_out=None, max_frag_len=None, extra_flags=None, seq=None, scoring=None))]
-    #[allow(clippy::too_many_arguments, unused_assignments)]
-    fn py_new(
-        fn_idx_in: Option<std::path::PathBuf>,
-        preset: Option<String>,
-        k: Option<usize>,
-        w: Option<usize>,
-        min_cnt: Option<usize>,
-        min_chain_score: Option<usize>,
-        min_dp_score: Option<usize>,
-        bw: Option<usize>,
-        best_n: Option<usize>,
-        n_threads: usize,
-        fn_idx_out: Option<std::path::PathBuf>,
-        max_frag_len: Option<usize>,
-        extra_flags: Option<usize>,
-        seq: Option<String>,
-        scoring: Option<&PyTuple>,
-    ) -> PyResult<Self> {
-        let mut mapopts = minimap2::MapOpt::default();
-        let mut idxopts = minimap2::IdxOpt::default();
-        unsafe { minimap2_sys::mm_set_opt(std::ptr::null(), &mut idxopts, &mut mapopts) };
-        if let Some(preset) = preset {
-            let _preset = std::ffi::CString::new(preset).unwrap();
-            unsafe { minimap2_sys::mm_set_opt(_preset.as_ptr(), &mut idxopts, &mut mapopts) };
+    /// Return the CIGAR with consecutive operations of the same type merged, e.g. `3M2M` becomes `5M`.
+    pub fn normalize_cigar(&self) -> Vec<(u32, u8)> {
+        let mut cigar: Vec<(u32, u8)> = Vec::with_capacity(self.cigar.len());
+        for &(len, op) in &self.cigar {
+            match cigar.last_mut() {
+                Some((last_len, last_op)) if *last_op == op => *last_len += len,
+                _ => cigar.push((len, op)),
+            }
         }
-        // For 'drop-in' mappy compatibility we should add the flag 4
-        mapopts.flag |= 4;
-        idxopts.batch_size |= 0x7fffffffffffffff_u64;
+        cigar
+    }
 
-        if let Some(k) = k {
-            idxopts.k = k as i16
+    /// Merge consecutive operations of the same type in the CIGAR of this `Mapping`, see `normalize_cigar`.
+    pub fn normalize_cigar_in_place(&mut self) {
+        self.cigar = self.normalize_cigar();
+    }
+
+    /// Key to sort mappings by their position on the reference, `(target_name, target_start, target_end)`.
+    /// For use as `sorted(mappings, key=Mapping.sort_key_position)`.
+    pub fn sort_key_position(&self) -> (String, i32, i32) {
+        (self.target_name.clone(), self.target_start, self.target_end)
+    }
+
+    /// Key to sort mappings by their score, `(mapq, s1)`, best last.
+    /// For use as `sorted(mappings, key=Mapping.sort_key_score, reverse=True)`.
+    pub fn sort_key_score(&self) -> (u32, i32) {
+        (self.mapq, self.s1)
+    }
+
+    /// Whether this and `other` map to the same target, with intersecting `[target_start, target_end)` intervals.
+    /// Mappings which only touch end to start do not overlap.
+    pub fn overlaps(&self, other: &Mapping) -> bool {
+        self.overlap_len(other) > 0
+    }
+
+    /// Number of target bases covered by both this and `other`, 0 if they map to different targets.
+    pub fn overlap_len(&self, other: &Mapping) -> i32 {
+        if self.target_name != other.target_name {
+            return 0;
         }
-        if let Some(w) = w {
-            idxopts.w = w as i16
+        let start = self.target_start.max(other.target_start);
+        let end = self.target_end.min(other.target_end);
+        (end - start).max(0)
+    }
+
+    /// Whether the alignment reaches both ends of the query, of length `query_len`, so is end-to-end rather than local.
+    pub fn is_query_spanning(&self, query_len: i32) -> bool {
+        self.query_start == 0 && self.query_end == query_len
+    }
+
+    /// Number of query bases left unaligned before the alignment, in the orientation of the reference, as the clip
+    /// at the start of its SAM CIGAR. minimap2's `cigar` never includes clipping, so this is calculated from the
+    /// query coordinates and `query_len`, and for a reverse strand mapping is the clip at the end of the read.
+    pub fn left_clip(&self, query_len: i32) -> i32 {
+        match self.strand {
+            Strand::Forward => self.query_start,
+            Strand::Reverse => query_len - self.query_end,
         }
-        if let Some(min_cnt) = min_cnt {
-            mapopts.min_cnt = min_cnt as i32
+    }
+
+    /// Number of query bases left unaligned after the alignment, in the orientation of the reference, see `left_clip`.
+    pub fn right_clip(&self, query_len: i32) -> i32 {
+        match self.strand {
+            Strand::Forward => query_len - self.query_end,
+            Strand::Reverse => self.query_start,
         }
-        if let Some(min_chain_score) = min_chain_score {
-            mapopts.min_chain_score = min_chain_score as i32
+    }
+
+    /// Format this `Mapping` as a PAF line, without a trailing newline, for the query `query_name` of length `query_len`.
+    /// This is the same line `PafWriter` writes.
+    pub fn to_paf(&self, query_name: &str, query_len: i32) -> PyResult<String> {
+        let mut writer = PafWriter::new(vec![]);
+        writer
+            .write_mapping(query_name, query_len, self)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let mut line = String::from_utf8_lossy(&writer.into_inner()).into_owned();
+        line.pop();
+        Ok(line)
+    }
+
+    /// Parse a PAF line, as written by minimap2 or `to_paf`, returning the query name, the query length and the `Mapping`.
+    /// The `tp`, `cg`, `NM`, `MD`, `cs`, `ts`, `s1` and `s2` tags are read if present, and any other tags are ignored.
+    /// Without a `tp` tag the mapping is primary, and without a `cg` tag it has no CIGAR.
+    #[staticmethod]
+    pub fn from_paf(line: &str) -> PyResult<(String, i32, Mapping)> {
+        let invalid =
+            |why: &str| PyValueError::new_err(format!("Invalid PAF line, {why}: {line:?}"));
+        let fields: Vec<&str> = line.trim_end_matches(['\n', '\r']).split('\t').collect();
+        if fields.len() < 12 {
+            return Err(invalid("expected at least 12 columns"));
         }
-        if let Some(min_dp_score) = min_dp_score {
-            mapopts.min_dp_max = min_dp_score as i32
+        let int = |column: usize| {
+            fields[column]
+                .parse::<i32>()
+                .map_err(|_| invalid(&format!("column {} is not an integer", column + 1)))
+        };
+        let strand = match fields[4] {
+            "+" => Strand::Forward,
+            "-" => Strand::Reverse,
+            _ => return Err(invalid("the strand must be + or -")),
+        };
+        let mut mapping = Mapping {
+            query_start: int(2)?,
+            query_end: int(3)?,
+            strand,
+            target_name: fields[5].to_string(),
+            target_len: int(6)?,
+            target_start: int(7)?,
+            target_end: int(8)?,
+            match_len: int(9)?,
+            block_len: int(10)?,
+            mapq: fields[11]
+                .parse()
+                .map_err(|_| invalid("the mapq is not an integer"))?,
+            is_primary: true,
+            // PAF has no supplementary tag
+            is_supplementary: false,
+            cigar: vec![],
+            NM: 0,
+            MD: None,
+            cs: None,
+            trans_strand: None,
+            s1: 0,
+            s2: 0,
+            query_name: Some(fields[0].to_string()),
+            frag_id: None,
+            query_len: Some(int(1)?),
+        };
+        for tag in &fields[12..] {
+            let mut parts = tag.splitn(3, ':');
+            let (Some(name), Some(_), Some(value)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid(&format!("{tag:?} is not a tag")));
+            };
+            let tag_int = || {
+                value
+                    .parse::<i32>()
+                    .map_err(|_| invalid(&format!("the {name} tag is not an integer")))
+            };
+            match name {
+                "tp" => mapping.is_primary = value != "S",
+                "cg" => {
+                    mapping.cigar =
+                        parse_cigar(value).ok_or_else(|| invalid("the cg tag is not a CIGAR"))?
+                }
+                "NM" => mapping.NM = tag_int()?,
+                "MD" => mapping.MD = Some(value.to_string()),
+                "cs" => mapping.cs = Some(value.to_string()),
+                "ts" => {
+                    mapping.trans_strand = match value {
+                        "+" => Some(Strand::Forward),
+                        "-" => Some(Strand::Reverse),
+                        _ => None,
+                    }
+                }
+                "s1" => mapping.s1 = tag_int()?,
+                "s2" => mapping.s2 = tag_int()?,
+                _ => {}
+            }
         }
-        if let Some(bw) = bw {
-            mapopts.bw = bw as i32
+        Ok((fields[0].to_string(), int(1)?, mapping))
+    }
+
+    /// Format this `Mapping` as a SAM record, for the query it was mapped from.
+    /// Clipped query bases are soft clipped, unless `hard_clip_secondary` is set, in which case they are hard clipped
+    /// for secondary mappings. As minimap2 does, `SEQ` and `QUAL` are `*` for secondary mappings,
+    /// and reverse complemented for mappings on the reverse strand. Supplementary mappings are flagged `0x800`.
+    #[pyo3(signature = (query_name, query_seq, query_qual=None, hard_clip_secondary=false), text_signature = "(query_name, query_seq, query_qual=None, hard_clip_secondary=False)")]
+    pub fn to_sam(
+        &self,
+        query_name: &str,
+        query_seq: &str,
+        query_qual: Option<&str>,
+        hard_clip_secondary: bool,
+    ) -> PyResult<String> {
+        let mut flag = 0;
+        if self.strand == Strand::Reverse {
+            flag |= 0x10;
         }
-        if let Some(best_n) = best_n {
-            mapopts.best_n = best_n as i32
+        if !self.is_primary {
+            flag |= 0x100;
         }
-        if let Some(max_frag_len) = max_frag_len {
-            mapopts.max_frag_len = max_frag_len as i32
+        if self.is_supplementary {
+            flag |= 0x800;
         }
-        if let Some(extra_flags) = extra_flags {
-            mapopts.flag |= extra_flags as i64
+        let (seq, qual) = if !self.is_primary {
+            (String::from("*"), String::from("*"))
+        } else if self.strand == Strand::Reverse {
+            (
+                reverse_complement(query_seq),
+                query_qual.map_or(String::from("*"), |q| q.chars().rev().collect()),
+            )
+        } else {
+            (query_seq.to_string(), query_qual.unwrap_or("*").to_string())
+        };
+        let cigar = if self.cigar.is_empty() {
+            String::from("*")
+        } else {
+            let query_len = query_seq.len() as i32;
+            let (left_clip, right_clip) = (self.left_clip(query_len), self.right_clip(query_len));
+            let clip = if hard_clip_secondary && !self.is_primary {
+                'H'
+            } else {
+                'S'
+            };
+            let mut cigar = String::new();
+            if left_clip > 0 {
+                cigar.push_str(&format!("{left_clip}{clip}"));
+            }
+            cigar.push_str(&self.get_cigar_str()?);
+            if right_clip > 0 {
+                cigar.push_str(&format!("{right_clip}{clip}"));
+            }
+            cigar
+        };
+        let tp = if self.is_primary { "tp:A:P" } else { "tp:A:S" };
+        let mut record = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t*\t0\t0\t{}\t{}\tNM:i:{}\t{}",
+            query_name,
+            flag,
+            self.target_name,
+            self.target_start + 1,
+            self.mapq,
+            cigar,
+            seq,
+            qual,
+            self.NM,
+            tp
+        );
+        if let Some(md) = &self.MD {
+            record.push_str(&format!("\tMD:Z:{md}"));
+        }
+        if let Some(cs) = &self.cs {
+            record.push_str(&format!("\tcs:Z:{cs}"));
+        }
+        Ok(record)
+    }
+
+    /// Format the query this `Mapping` was mapped from as a FASTQ record, trimmed to the aligned region
+    /// `query_start..query_end`. For mappings on the reverse strand the trimmed sequence is reverse complemented,
+    /// and the qualities reversed, so the record reads in the same direction as the reference.
+    #[pyo3(text_signature = "(name, seq, qual)")]
+    pub fn trimmed_fastq(&self, name: &str, seq: &str, qual: &str) -> PyResult<String> {
+        if seq.len() != qual.len() {
+            return Err(PyValueError::new_err(
+                "`seq` and `qual` must be the same length",
+            ));
+        }
+        let span = self.query_start as usize..self.query_end as usize;
+        let (Some(trimmed_seq), Some(trimmed_qual)) = (seq.get(span.clone()), qual.get(span))
+        else {
+            return Err(PyValueError::new_err(
+                "The mapping lies outside of `seq`, was it mapped from this query?",
+            ));
+        };
+        let (trimmed_seq, trimmed_qual) = match self.strand {
+            Strand::Forward => (trimmed_seq.to_string(), trimmed_qual.to_string()),
+            Strand::Reverse => (
+                reverse_complement(trimmed_seq),
+                trimmed_qual.chars().rev().collect(),
+            ),
+        };
+        Ok(format!("@{name}\n{trimmed_seq}\n+\n{trimmed_qual}\n"))
+    }
+
+    /// Return the reference sequence this `Mapping` aligned to, `target_name[target_start..target_end]`, fetched
+    /// from the index of `aligner`. Returns `None` if the contig or its sequence is not in that index.
+    #[pyo3(text_signature = "(aligner)")]
+    pub fn target_subseq(&self, aligner: &Aligner) -> Option<String> {
+        aligner
+            ._get_index_seq(self.target_name.clone(), self.target_start, self.target_end)
+            .ok()
+    }
+}
+
+/// Reverse complement a DNA sequence. Bases other than `ACGT` (in either case) become `N`.
+fn reverse_complement(seq: &str) -> String {
+    seq.chars()
+        .rev()
+        .map(|c| match c {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            'a' => 't',
+            'c' => 'g',
+            'g' => 'c',
+            't' => 'a',
+            _ => 'N',
+        })
+        .collect()
+}
+
+/// A single record read from a FASTA or FASTQ file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FastxRecord {
+    /// Name of the read, the header up to the first whitespace
+    name: String,
+    /// The sequence of the read
+    seq: String,
+    /// Quality string, if read from a FASTQ file
+    qual: Option<String>,
+}
+
+/// Minimal streaming FASTA/FASTQ reader, used to read queries from a file.
+/// FASTA records may be split over multiple lines, FASTQ records must be four lines each.
+struct FastxReader<R: BufRead> {
+    /// Lines of the underlying reader
+    lines: std::io::Lines<R>,
+    /// Header line of the next FASTA record, seen while reading the previous record's sequence
+    next_header: Option<String>,
+}
+
+impl<R: BufRead> FastxReader<R> {
+    /// Create a new `FastxReader` from a buffered reader
+    fn new(reader: R) -> Self {
+        FastxReader {
+            lines: reader.lines(),
+            next_header: None,
         }
-        if let Some(scoring) = scoring {
-            if scoring.len() >= 4 {
-                mapopts.a = scoring.get_item(0).unwrap().extract::<i32>().unwrap();
-                mapopts.b = scoring.get_item(1).unwrap().extract::<i32>().unwrap();
-                mapopts.q = scoring.get_item(2).unwrap().extract::<i32>().unwrap();
-                mapopts.e = scoring.get_item(3).unwrap().extract::<i32>().unwrap();
-                mapopts.q2 = mapopts.q;
-                mapopts.e2 = mapopts.e;
-                if scoring.len() >= 6 {
-                    mapopts.q2 = scoring.get_item(4).unwrap().extract::<i32>().unwrap();
-                    mapopts.e2 = scoring.get_item(5).unwrap().extract::<i32>().unwrap();
-                    if scoring.len() >= 7 {
-                        mapopts.sc_ambi = scoring.get_item(6).unwrap().extract::<i32>().unwrap();
+    }
+
+    /// Read the next line, erroring if the file ends in the middle of a record
+    fn next_line(&mut self) -> std::io::Result<String> {
+        match self.lines.next() {
+            Some(line) => line,
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Truncated FASTQ record",
+            )),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for FastxReader<R> {
+    type Item = std::io::Result<FastxRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = match self.next_header.take() {
+            Some(header) => header,
+            None => loop {
+                match self.lines.next()? {
+                    Ok(line) if line.trim().is_empty() => continue,
+                    Ok(line) => break line,
+                    Err(e) => return Some(Err(e)),
+                }
+            },
+        };
+        let Some(name) = header.strip_prefix(&['>', '@'][..]) else {
+            return Some(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Expected a FASTA or FASTQ header, got `{header}`"),
+            )));
+        };
+        let name = name
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        if header.starts_with('@') {
+            let record = (|| {
+                let seq = self.next_line()?;
+                let _plus = self.next_line()?;
+                let qual = self.next_line()?;
+                Ok(FastxRecord {
+                    name,
+                    seq: seq.trim().to_string(),
+                    qual: Some(qual.trim().to_string()),
+                })
+            })();
+            Some(record)
+        } else {
+            let mut seq = String::new();
+            for line in self.lines.by_ref() {
+                match line {
+                    Ok(line) if line.starts_with('>') => {
+                        self.next_header = Some(line);
+                        break;
                     }
+                    Ok(line) => seq.push_str(line.trim()),
+                    Err(e) => return Some(Err(e)),
                 }
             }
+            Some(Ok(FastxRecord {
+                name,
+                seq,
+                qual: None,
+            }))
+        }
+    }
+}
+
+/// Owns the parts of a minimap2 index read by `mm_idx_reader_read`, destroying them when dropped.
+/// An index has more than one part if it was built with an `index_batch_size` smaller than the reference.
+/// Shared by reference counting between `Aligner`s created with `clone_with_shared_index` and their worker threads,
+/// so the index is only freed once nothing can map against it.
+#[derive(Debug)]
+struct IndexHandle {
+    /// The parts of the index
+    parts: Vec<*mut minimap2_sys::mm_idx_t>,
+    /// Merge the mappings of a read to each part, as minimap2 does with `--split-prefix`, see `merge_part_regs`
+    merge_parts: bool,
+}
+
+// The index is never mutated after it has been loaded, so can be shared between threads.
+unsafe impl Send for IndexHandle {}
+unsafe impl Sync for IndexHandle {}
+
+impl IndexHandle {
+    /// The parts of the index, in the order they were read
+    fn parts(&self) -> impl Iterator<Item = &minimap2_sys::mm_idx_t> {
+        self.parts.iter().map(|idx| unsafe { &**idx })
+    }
+
+    /// Every sequence in the index, as the part it is in and its id within that part, in index order
+    fn seqs(&self) -> impl Iterator<Item = (&minimap2_sys::mm_idx_t, u32)> {
+        self.parts()
+            .flat_map(|idx| (0..idx.n_seq).map(move |rid| (idx, rid)))
+    }
+}
+
+impl Drop for IndexHandle {
+    fn drop(&mut self) {
+        for idx in &self.parts {
+            unsafe { minimap2_sys::mm_idx_destroy(*idx) };
+        }
+    }
+}
+
+/// Read every part of the index in `fn_idx_in`, building it with `idxopts` and `n_threads` threads if it is a FASTA/FASTQ
+/// rather than a prebuilt `.mmi`, returning the reader's final state and the index. Errors if no index could be read.
+fn read_index(
+    fn_idx_in: &std::path::Path,
+    idxopts: &minimap2::IdxOpt,
+    n_threads: usize,
+) -> PyResult<(minimap2_sys::mm_idx_reader_t, IndexHandle)> {
+    let fn_in = std::ffi::CString::new(fn_idx_in.to_str().unwrap()).unwrap();
+    let idx_reader = std::mem::MaybeUninit::new(unsafe {
+        minimap2_sys::mm_idx_reader_open(fn_in.as_ptr(), idxopts, std::ptr::null())
+    });
+
+    let idx_reader = unsafe { idx_reader.assume_init() };
+    if idx_reader.is_null() {
+        return Err(PyRuntimeError::new_err(format!(
+            "Could not open index file {}",
+            fn_idx_in.display()
+        )));
+    }
+
+    // Read every part of the index, there is more than one if it is built with a small `index_batch_size`
+    let mut parts = vec![];
+    let reader = unsafe {
+        loop {
+            let idx = minimap2_sys::mm_idx_reader_read(
+                &mut *idx_reader as *mut minimap2_sys::mm_idx_reader_t,
+                n_threads as libc::c_int,
+            );
+            if idx.is_null() {
+                break;
+            }
+            // Idx index name
+            minimap2_sys::mm_idx_index_name(idx);
+            parts.push(idx);
+        }
+        let reader = *idx_reader;
+        // Close the reader
+        minimap2_sys::mm_idx_reader_close(idx_reader);
+        reader
+    };
+    let index = IndexHandle {
+        parts,
+        merge_parts: false,
+    };
+    if index.parts.is_empty() {
+        return Err(PyRuntimeError::new_err(format!(
+            "Could not read an index from {}",
+            fn_idx_in.display()
+        )));
+    }
+    Ok((reader, index))
+}
+
+/// Aligner struct, mimicking minimap2's python interface
+#[pyclass(unsendable, module = "mappy_rs")]
+#[allow(clippy::type_complexity)]
+
+pub struct Aligner {
+    /// Inner minimap2::Aligner
+    pub aligner: minimap2::Aligner,
+    /// The index parts `aligner` maps against, which are freed when the last reference is dropped
+    index: Arc<IndexHandle>,
+    /// The preset the mapping and index options were initialised from, if any
+    preset: Option<String>,
+    /// The options this `Aligner` was constructed with, used to rebuild it when unpickling.
+    /// `None` once the index has been changed by `add_seq`, as it can no longer be rebuilt from them
+    options: Option<AlignerOptions>,
+    /// Thread buffer reused by every blocking `map` call, so minimap2's working memory isn't allocated for each read.
+    /// Not shared with copies, and never sent to another thread, as the `Aligner` is `unsendable`
+    buf: ThreadBuffer,
+    /// Number of mapping threads
+    n_threads: usize,
+    /// Whether threading was enabled by `enable_threading_rayon`, rather than `enable_threading`
+    rayon: bool,
+    /// thread handles
+    _handles: Arc<Mutex<Vec<std::thread::JoinHandle<()>>>>,
+    /// Busy flag for each worker thread, set while the thread is mapping a read
+    busy: Arc<Mutex<Vec<Arc<AtomicBool>>>>,
+    /// stop the threads
+    stop: Arc<Mutex<bool>>,
+    /// Number of work items popped by the worker threads whose results have not yet been pushed
+    in_flight: Arc<AtomicUsize>,
+    /// Totals of the reads mapped by this `Aligner` and its worker threads
+    counts: Arc<MapCounts>,
+    /// Batches submitted by `map_batch`, used to check all their results have been collected
+    batches: Arc<Mutex<Vec<Weak<Batch>>>>,
+    /// Work queue stores strings to map and ids to get the corresponding dict back, alongside the batch they belong to
+    work_queue: Arc<
+        ArrayQueue<(
+            Arc<Batch>,
+            WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+        )>,
+    >,
+}
+// unsafe impl Send for Aligner {}
+
+/// The options of `Aligner.__new__`, in order, used to construct an `Aligner` with `Aligner::new`, and kept so
+/// `__reduce__` can pickle an `Aligner` without its index. `fn_idx_out` and `seq` are not included, as they aren't
+/// implemented. The defaults are those of `Aligner.__new__`.
+#[derive(Debug, Clone)]
+struct AlignerOptions {
+    /// Index or FASTA/FASTQ file the index was read from
+    fn_idx_in: Option<std::path::PathBuf>,
+    /// See `Aligner.__new__`
+    preset: Option<String>,
+    /// See `Aligner.__new__`
+    k: Option<usize>,
+    /// See `Aligner.__new__`
+    w: Option<usize>,
+    /// See `Aligner.__new__`
+    min_cnt: Option<usize>,
+    /// See `Aligner.__new__`
+    min_chain_score: Option<usize>,
+    /// See `Aligner.__new__`
+    min_dp_score: Option<usize>,
+    /// See `Aligner.__new__`
+    bw: Option<usize>,
+    /// See `Aligner.__new__`
+    best_n: Option<usize>,
+    /// See `Aligner.__new__`
+    n_threads: usize,
+    /// See `Aligner.__new__`
+    max_frag_len: Option<usize>,
+    /// See `Aligner.__new__`
+    extra_flags: Option<usize>,
+    /// The scores of the `scoring` tuple
+    scoring: Option<Vec<i32>>,
+    /// See `Aligner.__new__`
+    min_dp_max: Option<usize>,
+    /// See `Aligner.__new__`
+    splice_flank: Option<bool>,
+    /// See `Aligner.__new__`
+    no_end_flt: Option<bool>,
+    /// See `Aligner.__new__`
+    mappy_compat: bool,
+    /// See `Aligner.__new__`
+    index_batch_size: Option<u64>,
+    /// See `Aligner.__new__`
+    occ_frac: Option<f32>,
+    /// See `Aligner.__new__`
+    for_only: bool,
+    /// See `Aligner.__new__`
+    max_chain_iter: Option<usize>,
+    /// See `Aligner.__new__`
+    max_gap: Option<usize>,
+    /// See `Aligner.__new__`
+    split_prefix: Option<String>,
+}
+
+impl Default for AlignerOptions {
+    fn default() -> Self {
+        AlignerOptions {
+            fn_idx_in: None,
+            preset: None,
+            k: None,
+            w: None,
+            min_cnt: None,
+            min_chain_score: None,
+            min_dp_score: None,
+            bw: None,
+            best_n: None,
+            n_threads: 3,
+            max_frag_len: None,
+            extra_flags: None,
+            scoring: None,
+            min_dp_max: None,
+            splice_flank: None,
+            no_end_flt: None,
+            mappy_compat: true,
+            index_batch_size: None,
+            occ_frac: None,
+            for_only: false,
+            max_chain_iter: None,
+            max_gap: None,
+            split_prefix: None,
         }
+    }
+}
+
+impl AlignerOptions {
+    /// The arguments as a tuple, to pass positionally to `Aligner.__new__`.
+    fn to_tuple(&self, py: Python<'_>) -> Py<PyTuple> {
+        let args: Vec<PyObject> = vec![
+            self.fn_idx_in.clone().into_py(py),
+            self.preset.clone().into_py(py),
+            self.k.into_py(py),
+            self.w.into_py(py),
+            self.min_cnt.into_py(py),
+            self.min_chain_score.into_py(py),
+            self.min_dp_score.into_py(py),
+            self.bw.into_py(py),
+            self.best_n.into_py(py),
+            self.n_threads.into_py(py),
+            // fn_idx_out
+            py.None(),
+            self.max_frag_len.into_py(py),
+            self.extra_flags.into_py(py),
+            // seq
+            py.None(),
+            self.scoring
+                .as_ref()
+                .map(|scores| PyTuple::new(py, scores))
+                .into_py(py),
+            self.min_dp_max.into_py(py),
+            self.splice_flank.into_py(py),
+            self.no_end_flt.into_py(py),
+            self.mappy_compat.into_py(py),
+            self.index_batch_size.into_py(py),
+            self.occ_frac.into_py(py),
+            self.for_only.into_py(py),
+            self.max_chain_iter.into_py(py),
+            self.max_gap.into_py(py),
+            self.split_prefix.clone().into_py(py),
+        ];
+        PyTuple::new(py, args).into()
+    }
+}
 
+#[pymethods]
+impl Aligner {
+    /// Initialise a new Py Class Aligner
+    /// Aligner struct, mimicking minimap2's python interface
+    ///
+    /// `min_dp_score` is the mappy name for minimap2's `min_dp_max` (`-s`), the minimal peak DP alignment score,
+    /// and sets `mapopt.min_dp_max`, exactly as mappy does. `min_dp_max` is accepted as the minimap2 name for the
+    /// same option. There is no separate DP threshold used during chaining, that is `min_chain_score` (`-m`).
+    ///
+    /// `fn_idx_in` may be a prebuilt `.mmi` index, or a FASTA/FASTQ file to build the index from.
+    /// Gzip compressed FASTA/FASTQ (e.g. `.fa.gz`) is supported, minimap2 always reads sequence files through zlib.
+    ///
+    /// `splice_flank` sets or clears `MM_F_SPLICE_FLANK` (`--splice-flank`), which assumes the next base is `G`
+    /// after a donor site `GT` and before an acceptor site `AG`, improving junction accuracy for most species.
+    /// `no_end_flt` sets or clears `MM_F_NO_END_FLT` (`--no-end-flt`), which stops minimap2 filtering out seeds
+    /// towards the ends of chains, often helping to align short exons at the ends of spliced reads.
+    /// Both are left as the preset sets them if `None`.
+    /// `for_only` sets `MM_F_FOR_ONLY` (`--for-only`), so minimap2 only maps reads to the forward strand.
+    ///
+    /// `mappy_compat`, true by default, sets `MM_F_CIGAR` (flag `4`) as mappy does, so minimap2 performs base level
+    /// alignment and every mapping has a CIGAR, `NM`, and `cs`/`MD` if asked for. Without it, mappings only have
+    /// the approximate coordinates from chaining, which is much faster when the alignment itself isn't needed.
+    ///
+    /// `scoring` is a tuple of `(a, b, q, e)`, `(a, b, q, e, q2, e2)` or `(a, b, q, e, q2, e2, sc_ambi)`, as for mappy.
+    /// `q2` and `e2` default to `q` and `e`, and a `ValueError` is raised for any other length, or a non integer score.
+    ///
+    /// `k` and `w` only apply when building the index from FASTA/FASTQ. A prebuilt `.mmi` index keeps the values
+    /// it was built with, and a `UserWarning` is emitted if different ones are given. As for minimap2, `k` must be
+    /// between 1 and 28 and `w` between 1 and 255, otherwise a `ValueError` is raised.
+    ///
+    /// `occ_frac` (`-f`) is the fraction of the most frequent minimizers ignored when seeding, masking repeats.
+    /// Once the index is read it is turned into `mid_occ`, the occurrence count above which a minimizer is ignored,
+    /// clamped to between `min_mid_occ` and `max_mid_occ`, which is reported by `index_stats`.
+    ///
+    /// `index_batch_size` (`-I`) is the number of reference bases loaded into each part of the index when building
+    /// it from FASTA, by default the whole reference is one part. Reads are mapped against every part in turn,
+    /// and as with minimap2 without `--split-prefix`, the mappings to each part are returned together, each part
+    /// having its own primary mappings, with mapqs calculated within that part alone.
+    ///
+    /// `split_prefix` (`--split-prefix`) instead merges the mappings to every part, as minimap2 does, so they are
+    /// ranked as for a single part index: a read has one primary mapping for each region of it, across the whole
+    /// index, overlapping mappings are secondary to it, and mapqs are recalculated from the merged mappings. minimap2
+    /// holds the mappings of each part in temporary files named with the prefix until they are merged, whereas
+    /// mappy-rs maps every part of a read at once, so merges them in memory and never writes any files.
+    ///
+    /// `max_chain_iter` (`--max-chain-iter`) is the most predecessors chaining tries for each anchor, and `max_gap`
+    /// (`-g`) the longest gap allowed between two anchors of a chain. Both bound the time spent chaining repetitive
+    /// reads. Lowering them cuts the worst case latency, at the cost of sensitivity: chains are broken at shorter gaps,
+    /// so reads with large insertions or deletions come back as several shorter mappings, or not at all.
+    #[new]
+    #[pyo3(signature = (fn_idx_in=None, preset=None, k=None, w=None, min_cnt=None, min_chain_score=None, min_dp_score=None, bw=None, best_n=None, n_threads=3, fn_idx_out=None, max_frag_len=None, extra_flags=None, seq=None, scoring=None, min_dp_max=None, splice_flank=None, no_end_flt=None, mappy_compat=true, index_batch_size=None, occ_frac=None, for_only=false, max_chain_iter=None, max_gap=None, split_prefix=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_new(
+        fn_idx_in: Option<std::path::PathBuf>,
+        preset: Option<String>,
+        k: Option<usize>,
+        w: Option<usize>,
+        min_cnt: Option<usize>,
+        min_chain_score: Option<usize>,
+        min_dp_score: Option<usize>,
+        bw: Option<usize>,
+        best_n: Option<usize>,
+        n_threads: usize,
+        fn_idx_out: Option<std::path::PathBuf>,
+        max_frag_len: Option<usize>,
+        extra_flags: Option<usize>,
+        seq: Option<String>,
+        scoring: Option<&PyTuple>,
+        min_dp_max: Option<usize>,
+        splice_flank: Option<bool>,
+        no_end_flt: Option<bool>,
+        mappy_compat: bool,
+        index_batch_size: Option<u64>,
+        occ_frac: Option<f32>,
+        for_only: bool,
+        max_chain_iter: Option<usize>,
+        max_gap: Option<usize>,
+        split_prefix: Option<String>,
+    ) -> PyResult<Self> {
         // TODO: The scoping rules are tricky here - maybe
         if let Some(_seq) = seq {
             return Err(PyNotImplementedError::new_err("Not Implemented"));
@@ -392,129 +1918,541 @@ impl Aligner {
             // If this is set, we create an MMI but cannot use it
             return Err(PyNotImplementedError::new_err("Not Implemented"));
         }
-        if let Some(fn_idx_in) = fn_idx_in {
-            let fn_in = std::ffi::CString::new(fn_idx_in.to_str().unwrap()).unwrap();
-            let idx_reader = std::mem::MaybeUninit::new(unsafe {
-                minimap2_sys::mm_idx_reader_open(fn_in.as_ptr(), &idxopts, std::ptr::null())
-            });
+        let scoring = scoring
+            .map(|scoring| {
+                scoring
+                    .iter()
+                    .map(|score| score.extract::<i32>())
+                    .collect::<PyResult<Vec<i32>>>()
+                    .map_err(|_| {
+                        PyValueError::new_err(format!(
+                            "`scoring` must only contain integers, not {scoring}"
+                        ))
+                    })
+            })
+            .transpose()?;
+        Aligner::new(AlignerOptions {
+            fn_idx_in,
+            preset,
+            k,
+            w,
+            min_cnt,
+            min_chain_score,
+            min_dp_score,
+            bw,
+            best_n,
+            n_threads,
+            max_frag_len,
+            extra_flags,
+            scoring,
+            min_dp_max,
+            splice_flank,
+            no_end_flt,
+            mappy_compat,
+            index_batch_size,
+            occ_frac,
+            for_only,
+            max_chain_iter,
+            max_gap,
+            split_prefix,
+        })
+    }
 
-            let mut idx: std::mem::MaybeUninit<*mut minimap2_sys::mm_idx_t> =
-                std::mem::MaybeUninit::uninit();
+    /// Create a new `Aligner` mapping against the same loaded index, without reading it again.
+    /// The new `Aligner` has its own copy of the mapping options, its own work queue and read counts. Multi threading must be
+    /// enabled on it separately. The index is freed once every `Aligner` sharing it has been dropped.
+    pub fn clone_with_shared_index(&self) -> Aligner {
+        Aligner {
+            aligner: self.aligner.clone(),
+            index: Arc::clone(&self.index),
+            preset: self.preset.clone(),
+            options: self.options.clone(),
+            buf: ThreadBuffer::new(),
+            n_threads: 0,
+            rayon: false,
+            _handles: Arc::new(Mutex::new(vec![])),
+            busy: Arc::new(Mutex::new(vec![])),
+            stop: Arc::new(Mutex::new(false)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            counts: Arc::new(MapCounts::default()),
+            batches: Arc::new(Mutex::new(vec![])),
+            work_queue: Arc::new(ArrayQueue::<(
+                Arc<Batch>,
+                WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+            )>::new(50000)),
+        }
+    }
 
-            let idx_reader = unsafe { idx_reader.assume_init() };
+    /// Implement the python `copy.copy()` protocol, see `clone_with_shared_index`.
+    fn __copy__(&self) -> Aligner {
+        self.clone_with_shared_index()
+    }
 
-            unsafe {
-                idx = std::mem::MaybeUninit::new(minimap2_sys::mm_idx_reader_read(
-                    &mut *idx_reader as *mut minimap2_sys::mm_idx_reader_t,
-                    n_threads as libc::c_int,
-                ));
-                // Close the reader
-                minimap2_sys::mm_idx_reader_close(idx_reader);
-                // Set index opts
-                minimap2_sys::mm_mapopt_update(&mut mapopts, *idx.as_ptr());
-                // Idx index name
-                minimap2_sys::mm_idx_index_name(idx.assume_init());
-            };
-            let al = Aligner {
-                aligner: minimap2::Aligner {
-                    mapopt: mapopts,
-                    idxopt: idxopts,
-                    threads: n_threads,
-                    idx: Some(unsafe { *idx.assume_init() }),
-                    idx_reader: Some(unsafe { *idx_reader }),
-                },
-                n_threads: 0,
-                _handles: Arc::new(Mutex::new(vec![])),
-                stop: Arc::new(Mutex::new(false)),
-                work_queue: Arc::new(ArrayQueue::<WorkQueue<(usize, String)>>::new(50000)),
-                results_queue: Arc::new(ArrayQueue::<WorkQueue<(Vec<Mapping>, usize)>>::new(50000)),
-            };
-            // al.setup_signal();
-            return Ok(al);
-        }
-        Err(PyRuntimeError::new_err("Did not create or open an index"))
+    /// Implement the python pickle protocol, so an `Aligner` can be sent to `multiprocessing` workers.
+    /// Only the arguments it was constructed with are pickled, and unpickling constructs a new `Aligner` from them,
+    /// reading the index again from `fn_idx_in`, which must be readable by the process unpickling it.
+    /// Multi threading is not carried over, and must be enabled again. A `TypeError` is raised if sequences have
+    /// been added with `add_seq`, as the index can't be rebuilt from the file alone.
+    fn __reduce__(&self, py: Python<'_>) -> PyResult<(PyObject, Py<PyTuple>)> {
+        let Some(options) = &self.options else {
+            return Err(PyTypeError::new_err(
+                "An Aligner with sequences added by `add_seq` can't be pickled",
+            ));
+        };
+        Ok((py.get_type::<Aligner>().into_py(py), options.to_tuple(py)))
     }
 
     /// Return the sequence names contained within an index as a list.
     #[getter]
     fn seq_names(&self) -> PyResult<Vec<String>> {
         if !self.aligner.has_index() {
-            return Err(PyRuntimeError::new_err("Index hasn't loaded"));
+            return Err(MappyError::NoIndex.into());
         }
         unsafe {
-            let ns = (self.aligner.idx.unwrap()).n_seq;
-            let mut sn = Vec::with_capacity(ns as usize);
-            for i in 0..ns {
+            let mut sn = vec![];
+            for (idx, rid) in self.index.seqs() {
                 sn.push(
-                    std::ffi::CStr::from_ptr(
-                        (*((self.aligner.idx.unwrap()).seq.offset(i as isize))).name,
-                    )
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
+                    std::ffi::CStr::from_ptr((*idx.seq.offset(rid as isize)).name)
+                        .to_str()
+                        .unwrap()
+                        .to_string(),
                 )
             }
             Ok(sn)
         }
     }
 
-    ///  Retrieves a (sub)sequence from the index and returns it as a Python string. None is
-    ///  returned if name is not present in the index or the start/end coordinates are invalid
-    ///  or if the index does not contain any sequence.
-    #[pyo3(signature = (name, start=0, end=2147483647), text_signature = "(name, start=0, end=2147483647)")]
-    fn seq(&self, name: String, start: i32, end: i32) -> PyResult<Option<String>> {
-        Ok(match self._get_index_seq(name, start, end) {
-            Ok(res) => Some(res),
-            Err(_) => None,
-        })
+    /// Return the lengths of the sequences contained within an index as a list, in the same order as `seq_names`.
+    #[getter]
+    fn seq_lens(&self) -> PyResult<Vec<u32>> {
+        if !self.aligner.has_index() {
+            return Err(MappyError::NoIndex.into());
+        }
+        unsafe {
+            let mut sl = vec![];
+            for (idx, rid) in self.index.seqs() {
+                sl.push((*idx.seq.offset(rid as isize)).len)
+            }
+            Ok(sl)
+        }
     }
 
-    /// Map a single read, blocking
-    #[pyo3(signature = (seq, seq2=None, cs=false, MD=false), text_signature = "(seq, seq2=None, cs=False, MD=False)")]
-    #[allow(non_snake_case)]
-    fn map(&self, seq: String, seq2: Option<String>, cs: bool, MD: bool) -> PyResult<Vec<Mapping>> {
-        // TODO: PyIterProtocol to map single reads and return as a generator
-        if let Some(_seq2) = seq2 {
-            return Err(PyNotImplementedError::new_err(
-                "Using `seq2` is not implemented",
-            ));
+    /// Return a `(name, length)` pair for each sequence in the index, in index order.
+    fn iter_seqs(&self) -> PyResult<Vec<(String, u32)>> {
+        Ok(self
+            .seq_names()?
+            .into_iter()
+            .zip(self.seq_lens()?)
+            .collect())
+    }
+
+    /// Return the id of the sequence `name` within the index, its position in `seq_names`, or `None` if it is not in the index.
+    fn seq_name_to_id(&self, name: &str) -> PyResult<Option<u32>> {
+        if !self.aligner.has_index() {
+            return Err(MappyError::NoIndex.into());
         }
-        match self.aligner.map(
-            seq.as_bytes(),
-            cs,
-            MD,
-            Some(self.aligner.mapopt.max_frag_len as usize),
-            None,
-        ) {
-            Ok(res) => Ok(res
-                .into_iter()
-                .map(|m| {
-                    let a = m.alignment.unwrap();
-                    Mapping {
-                        query_start: m.query_start,                // i32,
-                        query_end: m.query_end,                    // i32,
-                        strand: Strand::from_mm2_strand(m.strand), // Strand,
-                        target_name: m.target_name.unwrap(),       // String,
-                        target_len: m.target_len,                  // i32,
-                        target_start: m.target_start,              // i32,
-                        target_end: m.target_end,                  // i32,
-                        match_len: m.match_len,                    // i32,
-                        block_len: m.block_len,                    // i32,
-                        mapq: m.mapq,                              // u32,
-                        is_primary: m.is_primary,                  // bool
-                        cigar: a.cigar.unwrap_or_default(),        // Vec<(u32, u8)>
-                        NM: a.nm,                                  // i32
-                        MD: a.md,                                  // Option<String>
-                        cs: a.cs,                                  // Option<String>
-                    }
-                })
-                .collect()),
-            Err(e) => Err(PyRuntimeError::new_err(e)),
+        let Ok(name) = std::ffi::CString::new(name) else {
+            return Ok(None);
+        };
+        // Ids carry on counting from one part of the index to the next
+        let mut offset = 0;
+        for idx in self.index.parts() {
+            let id = unsafe { minimap2_sys::mm_idx_name2id(idx, name.as_ptr()) };
+            if let Ok(id) = u32::try_from(id) {
+                return Ok(Some(offset + id));
+            }
+            offset += idx.n_seq;
         }
+        Ok(None)
     }
 
-    /// Map a single read, blocking
-    #[pyo3(signature = (_seq, seq2=None, _cs=false, _MD=false), text_signature = "(_seq, seq2=None, _cs=False, _MD=False)")]
+    /// Return the name of the sequence with the id `id` within the index, or `None` if there is no such sequence.
+    fn id_to_seq_name(&self, id: u32) -> PyResult<Option<String>> {
+        if !self.aligner.has_index() {
+            return Err(MappyError::NoIndex.into());
+        }
+        let Some((idx, rid)) = self.index.seqs().nth(id as usize) else {
+            return Ok(None);
+        };
+        let name = unsafe { std::ffi::CStr::from_ptr((*idx.seq.offset(rid as isize)).name) };
+        Ok(Some(name.to_string_lossy().into_owned()))
+    }
+
+    /// Add the sequence `seq` to the index, named `name`, so reads can be mapped to it.
+    ///
+    /// minimap2 can't add to an index in place, so every call rebuilds the whole index from every sequence it holds
+    /// plus the new one, with the same `k` and `w`. Its cost grows with the size of the reference, not of `seq`, so
+    /// add many sequences by building an `Aligner` from a FASTA of them all instead. This is only possible for an
+    /// index built from FASTA/FASTQ as a single part, not a prebuilt `.mmi` or one split by `index_batch_size`,
+    /// and must be done before `enable_threading`. Other `Aligner`s sharing the index keep the old one.
+    fn add_seq(&mut self, name: String, seq: String) -> PyResult<()> {
+        if matches!(self.aligner.idx_reader, Some(reader) if reader.is_idx != 0) {
+            return Err(PyRuntimeError::new_err(
+                "Sequences can't be added to a prebuilt index",
+            ));
+        }
+        if self.index.parts.len() > 1 {
+            return Err(PyRuntimeError::new_err(format!(
+                "Sequences can't be added to an index of {} parts, as rebuilding it would merge them into one, \
+                 build the index with a larger `index_batch_size` instead",
+                self.index.parts.len()
+            )));
+        }
+        if self.n_threads > 0 {
+            return Err(PyRuntimeError::new_err(
+                "Sequences can't be added once threading is enabled",
+            ));
+        }
+        if self.seq_name_to_id(&name)?.is_some() {
+            return Err(PyValueError::new_err(format!(
+                "{name} is already in the index"
+            )));
+        }
+        let mut names = vec![];
+        let mut seqs = vec![];
+        for (ref_name, len) in self.seq_names()?.into_iter().zip(self.seq_lens()?) {
+            let ref_seq = self
+                ._get_index_seq(ref_name.clone(), 0, len as i32)
+                .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+            names.push(ref_name);
+            seqs.push(ref_seq);
+        }
+        names.push(name);
+        seqs.push(seq);
+        let to_c_strings = |strings: Vec<String>| {
+            strings
+                .into_iter()
+                .map(std::ffi::CString::new)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| PyValueError::new_err("Sequences and names can't contain NUL bytes"))
+        };
+        let names = to_c_strings(names)?;
+        let seqs = to_c_strings(seqs)?;
+        let mut name_ptrs: Vec<*const libc::c_char> =
+            names.iter().map(|name| name.as_ptr()).collect();
+        let mut seq_ptrs: Vec<*const libc::c_char> = seqs.iter().map(|seq| seq.as_ptr()).collect();
+        let old = self.aligner.idx.unwrap();
+        let idx = unsafe {
+            minimap2_sys::mm_idx_str(
+                old.w,
+                old.k,
+                old.flag & minimap2_sys::MM_I_HPC as i32,
+                self.aligner.idxopt.bucket_bits as libc::c_int,
+                seq_ptrs.len() as libc::c_int,
+                seq_ptrs.as_mut_ptr(),
+                name_ptrs.as_mut_ptr(),
+            )
+        };
+        if idx.is_null() {
+            return Err(PyRuntimeError::new_err("Could not rebuild the index"));
+        }
+        unsafe {
+            minimap2_sys::mm_idx_index_name(idx);
+            minimap2_sys::mm_mapopt_update(&mut self.aligner.mapopt, idx);
+            self.aligner.idx = Some(*idx);
+        }
+        // The old index is freed here, unless another `Aligner` is sharing it
+        self.index = Arc::new(IndexHandle {
+            parts: vec![idx],
+            merge_parts: self.index.merge_parts,
+        });
+        self.options = None;
+        Ok(())
+    }
+
+    /// Swap the index for the one in `path`, a prebuilt `.mmi` or a FASTA/FASTQ indexed with this `Aligner`'s options,
+    /// so a long running `Aligner` can switch to an updated reference without being recreated.
+    ///
+    /// If threading is enabled, the worker threads are restarted on the new index, so every read queued afterwards is
+    /// mapped against it. A `RuntimeError` is raised if a `map_batch` is still being mapped, as its remaining reads
+    /// would wait on its iterator for ever, so finish iterating it, or cancel it with `reset`, first.
+    /// The old index is freed, unless another `Aligner` is sharing it.
+    /// The mapping options are kept, except the repeat threshold `mid_occ`, which is recalculated for the new index.
+    fn reload_index(&mut self, py: Python<'_>, path: std::path::PathBuf) -> PyResult<()> {
+        let running = self
+            .batches
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|batch| batch.is_running());
+        if running {
+            return Err(PyRuntimeError::new_err(
+                "Can't reload the index while a batch is still being mapped, \
+                 finish iterating its results or call `reset` first",
+            ));
+        }
+        let (reader, mut index) = read_index(&path, &self.aligner.idxopt, self.aligner.threads)?;
+        index.merge_parts = self.index.merge_parts;
+        let (n_threads, rayon) = (self.n_threads, self.rayon);
+        if n_threads > 0 {
+            self.wait(py);
+            self.shutdown();
+            self.join()?;
+        }
+        // `mm_mapopt_update` only calculates `mid_occ` if it is unset, so reset it to the preset's value first
+        let mut idxopts = minimap2::IdxOpt::default();
+        let mut mapopts = minimap2::MapOpt::default();
+        unsafe { minimap2_sys::mm_set_opt(std::ptr::null(), &mut idxopts, &mut mapopts) };
+        if let Some(preset) = &self.preset {
+            let preset = std::ffi::CString::new(preset.as_str()).unwrap();
+            unsafe { minimap2_sys::mm_set_opt(preset.as_ptr(), &mut idxopts, &mut mapopts) };
+        }
+        self.aligner.mapopt.mid_occ = mapopts.mid_occ;
+        let idx = index.parts[0];
+        unsafe {
+            minimap2_sys::mm_mapopt_update(&mut self.aligner.mapopt, idx);
+            self.aligner.idx = Some(*idx);
+        }
+        self.aligner.idx_reader = Some(reader);
+        // The old index is freed here, unless another `Aligner` is sharing it
+        self.index = Arc::new(index);
+        if let Some(options) = &mut self.options {
+            options.fn_idx_in = Some(path);
+        }
+        if n_threads > 0 {
+            if rayon {
+                self.enable_threading_rayon(None)?;
+            } else {
+                self.enable_threading(n_threads)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Map every read in a FASTA or FASTQ file, writing the results to `output` as SAM.
+    /// The SAM header contains an `@SQ` line for each sequence in the index and a `@PG` line
+    /// with the mappy-rs and minimap2 versions. Reads which do not map are written as unmapped records.
+    /// `hard_clip_secondary` hard clips secondary mappings, see `Mapping.to_sam`.
+    /// Mapping is blocking and uses the calling thread only.
+    #[pyo3(signature = (input, output, hard_clip_secondary=false))]
+    fn align_to_sam(
+        &self,
+        input: std::path::PathBuf,
+        output: std::path::PathBuf,
+        hard_clip_secondary: bool,
+    ) -> PyResult<()> {
+        let io_err = |e: std::io::Error| PyIOError::new_err(e.to_string());
+        let reader = FastxReader::new(BufReader::new(std::fs::File::open(&input).map_err(io_err)?));
+        let mut writer = BufWriter::new(std::fs::File::create(&output).map_err(io_err)?);
+        writeln!(writer, "@HD\tVN:1.6\tSO:unsorted").map_err(io_err)?;
+        for (name, len) in self.seq_names()?.iter().zip(self.seq_lens()?) {
+            writeln!(writer, "@SQ\tSN:{name}\tLN:{len}").map_err(io_err)?;
+        }
+        writeln!(
+            writer,
+            "@PG\tID:mappy-rs\tPN:mappy-rs\tVN:{}\tDS:minimap2 {}",
+            mappy_rs_version(),
+            minimap2_version()
+        )
+        .map_err(io_err)?;
+        let buf = ThreadBuffer::new();
+        for record in reader {
+            let record = record.map_err(io_err)?;
+            let mappings = map_seq(
+                &self.aligner,
+                &self.index,
+                &buf,
+                &record.seq,
+                false,
+                false,
+                None,
+                &MapFilter::default(),
+            )
+            .map_err(|e| MappyError::MapFailed(e.to_string()))?;
+            let qual = record.qual.as_deref();
+            if mappings.is_empty() {
+                writeln!(
+                    writer,
+                    "{}\t4\t*\t0\t0\t*\t*\t0\t0\t{}\t{}",
+                    record.name,
+                    record.seq,
+                    qual.unwrap_or("*")
+                )
+                .map_err(io_err)?;
+            }
+            for mapping in mappings {
+                writeln!(
+                    writer,
+                    "{}",
+                    mapping.to_sam(&record.name, &record.seq, qual, hard_clip_secondary)?
+                )
+                .map_err(io_err)?;
+            }
+        }
+        writer.flush().map_err(io_err)?;
+        Ok(())
+    }
+
+    ///  Retrieves a (sub)sequence from the index and returns it as a Python string. None is
+    ///  returned if name is not present in the index or the start/end coordinates are invalid
+    ///  or if the index does not contain any sequence.
+    ///
+    ///  As with python slicing, a negative `start` or `end` counts back from the end of the contig, so
+    ///  `seq(name, -10, -1)` is the ten bases before the last base, and `seq(name, -10)` the last ten bases.
+    ///  An empty range returns `None`.
+    ///  Ambiguous bases are returned as `N`. Any base code minimap2 does not use is also returned as `N`, with a
+    ///  `RuntimeWarning`.
+    ///
+    ///  `mask=True` would return soft-masked bases in lowercase. minimap2 converts every base to a 2 bit code
+    ///  when building the index, so no soft-masking is stored, and this raises `NotImplementedError`.
+    #[pyo3(signature = (name, start=0, end=2147483647, mask=false), text_signature = "(name, start=0, end=2147483647, mask=False)")]
+    fn seq(&self, name: String, start: i32, end: i32, mask: bool) -> PyResult<Option<String>> {
+        if mask {
+            return Err(PyNotImplementedError::new_err(
+                "minimap2 indexes do not store soft-masking, so the original case can't be recovered",
+            ));
+        }
+        Ok(match self._get_index_seq(name, start, end) {
+            Ok(res) => Some(res),
+            Err(_) => None,
+        })
+    }
+
+    /// Map a single read, blocking.
+    /// If `max_hits` is set, at most that many mappings are returned, preferring primary mappings with the highest mapq.
+    /// If `target_names` is set, only mappings to those contigs are returned.
+    /// If `force_cigar` is true, `MM_F_CIGAR` is set for this call so the `cigar` is always populated, whatever the preset.
+    /// If `min_query_len` is set, shorter reads are returned unmapped without calling minimap2.
+    /// If `mapq_min` or `mapq_max` is set, the mapq of each mapping is clamped to at least or at most that value.
+    /// `k` and `w` are fixed when the index is built, so can't be changed per call. To map reads with different
+    /// minimizers, build an `Aligner` for each `k`/`w`.
+    #[pyo3(signature = (seq, seq2=None, cs=false, MD=false, max_hits=None, target_names=None, force_cigar=false, min_query_len=None, mapq_min=None, mapq_max=None), text_signature = "(seq, seq2=None, cs=False, MD=False, max_hits=None, target_names=None, force_cigar=False, min_query_len=None, mapq_min=None, mapq_max=None)")]
+    #[allow(non_snake_case, clippy::too_many_arguments)]
+    fn map(
+        &self,
+        seq: String,
+        seq2: Option<String>,
+        cs: bool,
+        MD: bool,
+        max_hits: Option<usize>,
+        target_names: Option<Vec<String>>,
+        force_cigar: bool,
+        min_query_len: Option<usize>,
+        mapq_min: Option<u32>,
+        mapq_max: Option<u32>,
+    ) -> PyResult<Vec<Mapping>> {
+        // TODO: PyIterProtocol to map single reads and return as a generator
+        if let Some(_seq2) = seq2 {
+            return Err(PyNotImplementedError::new_err(
+                "Using `seq2` is not implemented",
+            ));
+        }
+        MapFilter::check_mapq_range(mapq_min, mapq_max)?;
+        let filter = MapFilter {
+            max_hits,
+            target_names: target_names.map(|names| names.into_iter().collect()),
+            min_query_len,
+            mapq_min,
+            mapq_max,
+        };
+        let extra_flags = [minimap2_sys::MM_F_CIGAR as u64];
+        let extra_flags = if force_cigar {
+            Some(&extra_flags[..])
+        } else {
+            None
+        };
+        match map_seq(
+            &self.aligner,
+            &self.index,
+            &self.buf,
+            &seq,
+            cs,
+            MD,
+            extra_flags,
+            &filter,
+        ) {
+            Ok(mappings) => {
+                self.counts.record(&mappings);
+                Ok(mappings)
+            }
+            Err(e) => Err(MappyError::MapFailed(e.to_string()).into()),
+        }
+    }
+
+    /// Free the thread buffer reused by the blocking `map` calls, and allocate a new one.
+    /// minimap2 keeps the buffer's working memory sized for the largest read mapped so far, so this releases it after
+    /// mapping an unusually long read.
+    fn reset_buffer(&mut self) {
+        self.buf = ThreadBuffer::new();
+    }
+
+    /// Map a single read, blocking, as for `map`, also returning the wall clock time spent in minimap2 in
+    /// milliseconds. Useful for finding pathologically slow reads.
+    #[pyo3(signature = (seq, cs=false, MD=false), text_signature = "(seq, cs=False, MD=False)")]
+    #[allow(non_snake_case)]
+    fn map_timed(&self, seq: String, cs: bool, MD: bool) -> PyResult<(Vec<Mapping>, f64)> {
+        let start = std::time::Instant::now();
+        let mapped = map_seq(
+            &self.aligner,
+            &self.index,
+            &self.buf,
+            &seq,
+            cs,
+            MD,
+            None,
+            &MapFilter::default(),
+        );
+        let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+        match mapped {
+            Ok(mappings) => {
+                self.counts.record(&mappings);
+                Ok((mappings, elapsed_ms))
+            }
+            Err(e) => Err(MappyError::MapFailed(e.to_string()).into()),
+        }
+    }
+
+    /// Map a single read, blocking, as for `map`, returning each mapping as a `PafHit` for the query `query_name`.
+    /// `query_len` is the length of `seq` unless given.
+    #[pyo3(signature = (seq, query_name, query_len=None))]
+    fn map_paf(
+        &self,
+        seq: String,
+        query_name: String,
+        query_len: Option<i32>,
+    ) -> PyResult<Vec<PafHit>> {
+        let query_length = query_len.unwrap_or(seq.len() as i32);
+        let mappings = self.map(seq, None, false, false, None, None, false, None, None, None)?;
+        Ok(mappings
+            .into_iter()
+            .map(|mapping| PafHit {
+                query_name: query_name.clone(),
+                query_length,
+                mapping,
+            })
+            .collect())
+    }
+
+    /// Map a single read, blocking, returning only the best mapping.
+    /// This is the primary mapping with the highest mapq, or `None` if the read did not map.
+    #[pyo3(signature = (seq, cs=false, MD=false), text_signature = "(seq, cs=False, MD=False)")]
+    #[allow(non_snake_case)]
+    fn map_best(&self, seq: String, cs: bool, MD: bool) -> PyResult<Option<Mapping>> {
+        match map_seq(
+            &self.aligner,
+            &self.index,
+            &self.buf,
+            &seq,
+            cs,
+            MD,
+            None,
+            &MapFilter::default(),
+        ) {
+            // Ties are broken by minimap2's own ordering, so keep the first seen
+            Ok(mappings) => {
+                self.counts.record(&mappings);
+                Ok(mappings
+                    .into_iter()
+                    .filter(|m| m.is_primary)
+                    .reduce(|best, m| if m.mapq > best.mapq { m } else { best }))
+            }
+            Err(e) => Err(MappyError::MapFailed(e.to_string()).into()),
+        }
+    }
+
+    /// Map a single read, blocking
+    #[pyo3(signature = (_seq, seq2=None, _cs=false, _MD=false), text_signature = "(_seq, seq2=None, _cs=False, _MD=False)")]
     #[allow(non_snake_case)]
     fn map_no_op(
         &self,
@@ -533,24 +2471,36 @@ impl Aligner {
     }
 
     ///  Enable multi threading on this mappy instance.
+    ///  A `ValueError` is raised if `n_threads` is 0, as there would be no threads to map reads with.
     ///
     /// Example
     /// -------
     /// `aligner::enable_threading(8)`
     #[pyo3(signature = (n_threads), text_signature = "(n_threads=8)")]
     fn enable_threading(&mut self, n_threads: usize) -> PyResult<()> {
+        if n_threads == 0 {
+            return Err(PyValueError::new_err(
+                "`n_threads` must be at least 1 to enable threading",
+            ));
+        }
         self.n_threads = n_threads;
         let dones = Arc::new(Mutex::new(vec![false; n_threads]));
         for i in 0..n_threads {
             let _aligner = self.aligner.clone();
             let stop = Arc::clone(&self.stop);
             let wq = Arc::clone(&self.work_queue);
-            let rq = Arc::clone(&self.results_queue);
+            let in_flight = Arc::clone(&self.in_flight);
+            let counts = Arc::clone(&self.counts);
+            // Keeps the index alive for as long as this thread can map against it
+            let index = Arc::clone(&self.index);
             let thread_number = i;
             let done_ref = Arc::clone(&dones);
+            let busy = Arc::new(AtomicBool::new(false));
+            self.busy.lock().unwrap().push(Arc::clone(&busy));
 
             // start the threads
-            std::thread::spawn(move || {
+            let handle = std::thread::spawn(move || {
+                let buf = ThreadBuffer::new();
                 loop {
                     // STOP SIGNAL RECEVIED SIGINT/SIGTERM
                     if *stop.lock().unwrap() {
@@ -573,80 +2523,560 @@ impl Aligner {
                         std::thread::sleep(Duration::from_millis(1));
                         continue;
                     }
+                    // Mark this thread busy before popping, so `wait` never sees an empty queue while work is in flight
+                    in_flight.fetch_add(1, Ordering::SeqCst);
                     match wq.pop() {
-                        None => std::thread::sleep(Duration::from_millis(10)),
-                        Some(work_item) => {
+                        None => {
+                            in_flight.fetch_sub(1, Ordering::SeqCst);
+                            std::thread::sleep(Duration::from_millis(10))
+                        }
+                        Some((batch, work_item)) => {
                             match work_item {
                                 WorkQueue::Done => {
-                                    rq.push(WorkQueue::Done).unwrap();
+                                    push_result(&batch, WorkQueue::Done);
                                     {
                                         done_ref.lock().unwrap()[thread_number] = true;
                                     }
                                 }
-                                WorkQueue::Work((id_num, seq)) => {
-                                    match _aligner.map(
-                                        seq.as_bytes(),
-                                        true,
-                                        false,
-                                        Some(_aligner.mapopt.max_frag_len as usize),
-                                        None,
-                                    ) {
-                                        Ok(_mappings) => {
-                                            mem::drop(seq);
-                                            let mappings: Vec<Mapping> = _mappings
-                                                .into_iter()
-                                                .map(|m| {
-                                                    let a = m.alignment.unwrap();
-                                                    Mapping {
-                                                        query_start: m.query_start,                // i32,
-                                                        query_end: m.query_end, // i32,
-                                                        strand: Strand::from_mm2_strand(m.strand), // Strand,
-                                                        target_name: m.target_name.unwrap(), // String,
-                                                        target_len: m.target_len,            // i32,
-                                                        target_start: m.target_start,        // i32,
-                                                        target_end: m.target_end,            // i32,
-                                                        match_len: m.match_len,              // i32,
-                                                        block_len: m.block_len,              // i32,
-                                                        mapq: m.mapq,                        // u32,
-                                                        is_primary: m.is_primary,            // bool
-                                                        cigar: a.cigar.unwrap_or_default(), // Vec<(u32, u8)>
-                                                        NM: a.nm,
-                                                        MD: a.md,
-                                                        cs: a.cs,
-                                                    }
-                                                })
-                                                .collect();
-                                            rq.push(WorkQueue::Result((mappings, id_num))).unwrap();
-                                        }
-                                        Err(_) => {
-                                            eprintln!("Failed to map sequence in threaded implementation.")
-                                        }
+                                // The batch was cancelled, so drop the reads without mapping them
+                                WorkQueue::Work(_) | WorkQueue::Chunk(_)
+                                    if batch.is_cancelled() => {}
+                                work_item @ (WorkQueue::Work(_) | WorkQueue::Chunk(_)) => {
+                                    busy.store(true, Ordering::SeqCst);
+                                    for (id_num, seq, data) in work_item
+                                        .into_reads()
+                                        .into_iter()
+                                        .take_while(|_| !batch.is_cancelled())
+                                    {
+                                        map_work_item(
+                                            &_aligner, &index, &buf, &counts, &batch, id_num, seq,
+                                            data,
+                                        );
                                     }
+                                    busy.store(false, Ordering::SeqCst);
                                 }
                                 _ => {
                                     println!("What is this doing in the work queue")
                                 }
                             }
+                            in_flight.fetch_sub(1, Ordering::SeqCst);
                         }
                     }
                 }
             });
+            self._handles.lock().unwrap().push(handle);
+        }
+        Ok(())
+    }
+
+    /// Enable multi threading on this mappy instance, mapping reads on the global rayon thread pool instead of
+    /// threads dedicated to this `Aligner`, so the number of mapping threads is bounded however many `Aligner`s
+    /// are in use. A single dispatcher thread is started, which hands each queued read to the pool as a task.
+    ///
+    /// `pool` sets the number of threads in the global pool, and is only used by the first call to create it.
+    /// By default rayon uses one thread per CPU. A `ValueError` is raised if `pool` is 0, or if the pool already
+    /// exists with a different number of threads.
+    ///
+    /// `map_batch` and the other batch methods work exactly as after `enable_threading`, which remains the default.
+    #[pyo3(signature = (pool=None), text_signature = "(pool=None)")]
+    fn enable_threading_rayon(&mut self, pool: Option<usize>) -> PyResult<()> {
+        if let Some(n_threads) = pool {
+            if n_threads == 0 {
+                return Err(PyValueError::new_err(
+                    "`pool` must be at least 1 to enable threading",
+                ));
+            }
+            if rayon::ThreadPoolBuilder::new()
+                .num_threads(n_threads)
+                .build_global()
+                .is_err()
+                && rayon::current_num_threads() != n_threads
+            {
+                return Err(PyValueError::new_err(format!(
+                    "The global rayon pool already exists with {} threads, not {n_threads}",
+                    rayon::current_num_threads()
+                )));
+            }
+        }
+        // Batches are finished with a single `Done`, which is passed on once the batch's reads are mapped
+        self.n_threads = 1;
+        self.rayon = true;
+        let aligner = self.aligner.clone();
+        let stop = Arc::clone(&self.stop);
+        let wq = Arc::clone(&self.work_queue);
+        let in_flight = Arc::clone(&self.in_flight);
+        let counts = Arc::clone(&self.counts);
+        let index = Arc::clone(&self.index);
+        let handle = std::thread::spawn(move || loop {
+            if *stop.lock().unwrap() {
+                break;
+            }
+            in_flight.fetch_add(1, Ordering::SeqCst);
+            match wq.pop() {
+                None => {
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Some((batch, WorkQueue::Done)) => {
+                    // Reads are taken off the queue in order, so every read of the batch has been handed out.
+                    // If some are still being mapped, the last task to finish passes the `Done` on instead
+                    if batch.pooled.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        push_result(&batch, WorkQueue::Done);
+                    }
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+                Some((batch, WorkQueue::Work(_) | WorkQueue::Chunk(_))) if batch.is_cancelled() => {
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+                Some((batch, work_item @ (WorkQueue::Work(_) | WorkQueue::Chunk(_)))) => {
+                    batch.pooled.fetch_add(1, Ordering::SeqCst);
+                    let aligner = aligner.clone();
+                    let index = Arc::clone(&index);
+                    let counts = Arc::clone(&counts);
+                    let in_flight = Arc::clone(&in_flight);
+                    // A chunk is mapped by a single task
+                    rayon::spawn(move || {
+                        RAYON_BUF.with(|buf| {
+                            for (id_num, seq, data) in work_item
+                                .into_reads()
+                                .into_iter()
+                                .take_while(|_| !batch.is_cancelled())
+                            {
+                                map_work_item(
+                                    &aligner, &index, buf, &counts, &batch, id_num, seq, data,
+                                )
+                            }
+                        });
+                        if batch.pooled.fetch_sub(1, Ordering::SeqCst) == 1 {
+                            push_result(&batch, WorkQueue::Done);
+                        }
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                // Only reads and `Done` are pushed onto the work queue
+                Some(_) => {
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        });
+        self._handles.lock().unwrap().push(handle);
+        Ok(())
+    }
+
+    /// Signal the worker threads started by `enable_threading` to stop.
+    /// Any work still in the queue is not processed. Call `join` to wait for the threads to exit.
+    fn shutdown(&self) {
+        *self.stop.lock().unwrap() = true;
+    }
+
+    /// Block until all the worker threads have exited, after a call to `shutdown`.
+    /// Multi threading is then disabled on this instance, and can be enabled again with `enable_threading`.
+    fn join(&mut self) -> PyResult<()> {
+        let handles: Vec<std::thread::JoinHandle<()>> =
+            self._handles.lock().unwrap().drain(..).collect();
+        for handle in handles {
+            if handle.join().is_err() {
+                return Err(PyRuntimeError::new_err("A worker thread panicked"));
+            }
         }
+        self.busy.lock().unwrap().clear();
+        self.n_threads = 0;
+        self.rayon = false;
+        *self.stop.lock().unwrap() = false;
         Ok(())
     }
 
+    /// Clear out everything left over from earlier calls to `map_batch`, giving a clean slate for the next batch,
+    /// for example after abandoning iteration part way through a batch.
+    /// Every batch submitted so far is cancelled, so their iterators stop, and their queued reads and any results
+    /// not yet passed on to an iterator are discarded. Finished thread counts are kept per batch, so a new batch
+    /// always starts from zero.
+    fn reset(&self) {
+        let live_batches: Vec<Arc<Batch>> = self
+            .batches
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for batch in &live_batches {
+            batch.cancelled.store(true, Ordering::SeqCst);
+            while batch.results_queue.pop().is_some() {}
+        }
+        // Reads are dropped, but `Done`s are put back, as every worker thread waits for the others to see one
+        let mut dones = vec![];
+        while let Some((batch, work_item)) = self.work_queue.pop() {
+            if let WorkQueue::Done = work_item {
+                dones.push((batch, work_item));
+            }
+        }
+        for done in dones {
+            self.work_queue.push(done).unwrap();
+        }
+    }
+
+    /// Whether each worker thread is currently mapping a read, in the order the threads were started.
+    /// Mostly idle threads while reads are queued suggests the consumer of the results is the bottleneck,
+    /// all threads busy suggests more threads would help.
+    fn thread_utilization(&self) -> Vec<bool> {
+        self.busy
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|busy| busy.load(Ordering::SeqCst))
+            .collect()
+    }
+
+    /// Block until all work queued by `map_batch` has been mapped, and every result has been passed
+    /// on to its `AlignmentBatchResultIter`. The GIL is released while waiting.
+    ///
+    /// Results must be consumed from the iterators, either beforehand or concurrently from another thread,
+    /// if a batch returns more results than the iterator can buffer.
+    fn wait(&self, py: Python<'_>) {
+        let work_queue = Arc::clone(&self.work_queue);
+        let in_flight = Arc::clone(&self.in_flight);
+        let batches = Arc::clone(&self.batches);
+        py.allow_threads(move || loop {
+            let results_collected = {
+                let mut batches = batches.lock().unwrap();
+                batches.retain(|batch| batch.strong_count() > 0);
+                batches
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .all(|batch| batch.is_cancelled() || batch.results_queue.is_empty())
+            };
+            if work_queue.is_empty() && in_flight.load(Ordering::SeqCst) == 0 && results_collected {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        })
+    }
+
     /// Align a sequence Optionally back off if we fail to add the sequence to the queue, in the case that the work queue is full.
-    #[pyo3(signature = (seqs, back_off=true))]
-    fn map_batch(&self, seqs: &PyAny, back_off: bool) -> PyResult<AlignmentBatchResultIter> {
-        let mut res = AlignmentBatchResultIter::new();
-        // Set the number of threads
-        res.set_n_threads(self.n_threads);
-        // do the heavy work
-        self._map_batch(&mut res, seqs, back_off)?;
-        // let return_metadata: (i32, i32, String) = (metadata.read_number, metadata.channel_number, String::from("hdea"));
+    /// If `max_hits` is set, at most that many mappings are returned per read.
+    /// If `target_names` is set, only mappings to those contigs are returned.
+    /// If `min_query_len` is set, shorter reads are returned unmapped by the worker threads, without calling minimap2.
+    ///
+    /// Each read's dictionary is returned alongside its mappings. If it has an `id` key, that is returned verbatim,
+    /// otherwise `id` is set to the read's index in `seqs`, so every result can be matched back to its read.
+    /// If `attach_query_name` is true, the read's `name`, or failing that its `id`, is also set as the `query_name`
+    /// of each of its mappings, so they can still be told apart once the mappings of many reads are flattened together.
+    /// If `mapq_fn` is given, it is called as `mapq_fn(s1, s2, block_len)` for every mapping as results are returned,
+    /// and the `int` it returns replaces minimap2's mapq.
+    /// If `mapq_min` or `mapq_max` is set, the worker threads clamp the mapq of each mapping to at least or at most
+    /// that value, before any `mapq_fn` is called.
+    ///
+    /// If `back_off` is true and the work queue is full, adding a read is retried up to `max_backoff_attempts` times,
+    /// sleeping `initial_backoff_ms` milliseconds before the first retry and doubling the sleep after each one.
+    /// A `RuntimeError` is raised if the read still could not be added.
+    ///
+    /// If `no_op` is true, minimap2 is never called and every read is returned with the dummy mapping of `map_no_op`,
+    /// to benchmark the overhead of the queues and threads alone.
+    ///
+    /// `result_bound` is how many results can wait to be iterated over before the worker threads wait for the
+    /// consumer to catch up. Results wait in two queues of this size, so at most twice `result_bound` results are
+    /// held in memory for a slow consumer.
+    ///
+    /// By default each read's dictionary is held by the iterator until its result is returned. If `carry_metadata`
+    /// is true, the dictionary instead travels through the work queue with the read, so memory stays flat no matter
+    /// how large the batch is.
+    ///
+    /// Lists, tuples and sequences are added to the work queue before `map_batch` returns, unless they hold more
+    /// reads than `result_bound`, whose results could not all wait before iteration starts. Iterators and generators,
+    /// and those longer lists, are instead drained lazily on a separate thread, while the results are being iterated over, and are held back
+    /// whilst the work queue is full. Their dictionaries always travel through the work queue, as with
+    /// `carry_metadata`, and any error raised while reading them, including by the iterator itself, is raised from
+    /// the results iterator once the reads before it have been returned.
+    ///
+    /// If `yield_metadata` is false, the iterator yields just the mappings of each read, in place of the
+    /// `(mappings, dictionary)` tuple, and the dictionaries are not kept at all, unless `attach_query_name` needs them.
+    ///
+    /// If `max_inflight` is set, at most that many reads are mapped ahead of those returned by the iterator, so a slow
+    /// consumer doesn't leave the mappings of a whole batch waiting in memory. Reads are then always fed lazily, as for
+    /// an iterator, whatever the type of `seqs`.
+    ///
+    /// If `max_queue_bytes` is set, reads are held back once the sequences waiting in the work queue for a worker
+    /// thread add up to that many bytes, bounding the memory queued for very long reads rather than the number of
+    /// reads. As with `max_inflight`, reads are then always fed lazily. A single read longer than the budget is still
+    /// queued, once nothing else is waiting.
+    ///
+    /// `on_error` controls what happens to a read which fails to map, or is malformed. With `"raise"`, the default,
+    /// a malformed read raises from `map_batch` itself and a read which fails to map raises a `RuntimeError` from the
+    /// iterator. With `"warn"`, a `RuntimeWarning` is emitted and the read is returned with no mappings.
+    /// With `"skip"`, the read is silently dropped and the rest of the batch is returned as normal.
+    ///
+    /// If `as_paf` is true, the iterator yields a string for each read, in place of its mappings and dictionary,
+    /// holding a PAF line for each mapping, joined by newlines. The query name is the read's `name`, or failing
+    /// that its `id`, and the query length that of its `seq`. Reads with no mappings yield an empty string.
+    ///
+    /// `chunk_size` reads are pushed onto the work queue together, and mapped one after another by the same worker
+    /// thread, cutting the overhead of the queue for very many short reads. Each read's result is still returned
+    /// on its own. Reads from an iterator are only mapped once their chunk is full, or the iterator is exhausted,
+    /// and `chunk_size` can't be more than `max_inflight`.
+    ///
+    /// If `dry_run` is true, every read is read and validated as normal, but minimap2 is never called and every
+    /// read is returned with no mappings, so malformed input can be found quickly, without the cost of mapping.
+    ///
+    /// `deterministic` is a debugging aid, making repeated runs over the same reads return identical results.
+    /// Results are returned in the order of the reads in `seqs`, rather than the order they finish mapping, and
+    /// a full work queue is waited on, instead of backing off for a limited number of attempts, so whether a read
+    /// is added never depends on timing. Results which finish early are held in memory until their turn.
+    ///
+    #[pyo3(signature = (seqs, back_off=true, max_hits=None, target_names=None, min_query_len=None, attach_query_name=false, mapq_fn=None, max_backoff_attempts=6, initial_backoff_ms=50, no_op=false, result_bound=20000, carry_metadata=false, on_error="raise", yield_metadata=true, max_inflight=None, deterministic=false, dry_run=false, as_paf=false, chunk_size=1, mapq_min=None, mapq_max=None, max_queue_bytes=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn map_batch(
+        &self,
+        seqs: &PyAny,
+        back_off: bool,
+        max_hits: Option<usize>,
+        target_names: Option<Vec<String>>,
+        min_query_len: Option<usize>,
+        attach_query_name: bool,
+        mapq_fn: Option<PyObject>,
+        max_backoff_attempts: u32,
+        initial_backoff_ms: u64,
+        no_op: bool,
+        result_bound: usize,
+        carry_metadata: bool,
+        on_error: &str,
+        yield_metadata: bool,
+        max_inflight: Option<usize>,
+        deterministic: bool,
+        dry_run: bool,
+        as_paf: bool,
+        chunk_size: usize,
+        mapq_min: Option<u32>,
+        mapq_max: Option<u32>,
+        max_queue_bytes: Option<usize>,
+    ) -> PyResult<AlignmentBatchResultIter> {
+        let options = MapBatchOptions {
+            back_off,
+            max_hits,
+            target_names,
+            min_query_len,
+            attach_query_name,
+            mapq_fn,
+            max_backoff_attempts,
+            initial_backoff_ms,
+            no_op,
+            result_bound,
+            carry_metadata,
+            on_error: OnError::parse(on_error)?,
+            yield_metadata,
+            max_inflight,
+            deterministic,
+            dry_run,
+            as_paf,
+            chunk_size,
+            mapq_min,
+            mapq_max,
+            max_queue_bytes,
+        };
+        self.map_batch_with(seqs, options)
+    }
+
+    /// Map a batch of reads as for `map_batch`, blocking until every read has been mapped, and return all of the
+    /// mappings at once as a dictionary of each read's index in `seqs` to its mappings.
+    /// Takes the same keyword options as `map_batch`, except that `yield_metadata` and `as_paf` have no effect, as only
+    /// the mappings are returned. With the default `on_error`, a `MapFailed` is raised if any read fails to map.
+    #[pyo3(
+        signature = (seqs, **options),
+        text_signature = "(seqs, **options)"
+    )]
+    fn map_batch_collect(
+        &self,
+        py: Python,
+        seqs: &PyAny,
+        options: Option<&PyDict>,
+    ) -> PyResult<HashMap<usize, Vec<Mapping>>> {
+        let options = MapBatchOptions::from_kwargs("map_batch_collect", options)?;
+        let (mut res, batch, back_off) = self.prepare_batch(options)?;
+        res.yield_metadata = false;
+        res.as_paf = false;
+        // Only the mappings are returned, so the read dictionaries needn't be kept by `res`
+        self._map_batch(&mut res, seqs, back_off, batch, true)?;
+        let mut collected = HashMap::new();
+        while let Some((id_num, mappings, _)) = res.next_indexed(py)? {
+            collected.insert(id_num, mappings);
+        }
+        Ok(collected)
+    }
+
+    /// Map a batch of reads as for `map_batch`, calling `on_result(mappings, dictionary)` for each read as it is
+    /// mapped, instead of returning an iterator. Returns once every read has been passed to `on_result`.
+    ///
+    /// `on_result` is called on the calling thread, and the GIL is released while waiting for each result.
+    /// If it raises, the rest of the batch is cancelled and the exception is raised from `map_batch_callback`.
+    /// Takes the same keyword options as `map_batch`, except that `yield_metadata` and `as_paf` have no effect, as
+    /// `on_result` is always passed both the mappings and the dictionary.
+    #[pyo3(
+        signature = (seqs, on_result, **options),
+        text_signature = "(seqs, on_result, **options)"
+    )]
+    fn map_batch_callback(
+        &self,
+        py: Python,
+        seqs: &PyAny,
+        on_result: PyObject,
+        options: Option<&PyDict>,
+    ) -> PyResult<()> {
+        let options = MapBatchOptions::from_kwargs("map_batch_callback", options)?;
+        let mut res = self.map_batch_with(seqs, options)?;
+        while let Some((mappings, data)) = res.next_result(py)? {
+            if let Err(e) = on_result.call1(py, (mappings, data)) {
+                res.cancel();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Align the rows of a 2D numpy array of ASCII sequences (dtype `uint8`), such as fixed length basecalls.
+    /// Rows are read directly as bytes, rather than converted to python strings first.
+    /// Returns the same iterator as `map_batch`, with `{"row": <row index>, "id": <row index>}` as the data for each
+    /// read, and takes the same keyword options. The row dictionaries always travel through the work queue, as with
+    /// `carry_metadata`, and as there is no `seq` in them, `as_paf` gives a query length of 0.
+    ///
+    /// A row which isn't ASCII is malformed, and handled according to `on_error`.
+    #[pyo3(
+        signature = (arr, **options),
+        text_signature = "(arr, **options)"
+    )]
+    fn map_batch_numpy(
+        &self,
+        py: Python,
+        arr: PyReadonlyArray2<u8>,
+        options: Option<&PyDict>,
+    ) -> PyResult<AlignmentBatchResultIter> {
+        self.check_threading()?;
+        let options = MapBatchOptions::from_kwargs("map_batch_numpy", options)?;
+        let (mut res, batch, back_off) = self.prepare_batch(options)?;
+        let mut reads = vec![];
+        for (row_num, row) in arr.as_array().rows().into_iter().enumerate() {
+            let data = HashMap::from([
+                (String::from("row"), row_num.into_py(py)),
+                (String::from("id"), row_num.into_py(py)),
+            ]);
+            match String::from_utf8(row.to_vec()) {
+                Ok(seq) => reads.push((row_num, Ok(seq), res.keeps_metadata().then_some(data))),
+                Err(_) => {
+                    let reason = format!("Row {row_num} is not an ASCII sequence");
+                    match res.on_error {
+                        OnError::Raise => return Err(PyValueError::new_err(reason)),
+                        OnError::Warn => reads.push((row_num, Err(reason), Some(data))),
+                        OnError::Skip => {}
+                    }
+                }
+            }
+        }
+        let lazy = batch.feeds_lazily(reads.len());
+        let batch = self.start_batch(&mut res, batch);
+        if lazy {
+            let work_queue = Arc::clone(&self.work_queue);
+            let n_threads = self.n_threads;
+            let error = Arc::clone(&res.error);
+            std::thread::spawn(move || {
+                if let Err(e) = feed_reads(&work_queue, &batch, back_off, reads) {
+                    *error.lock().unwrap() = Some(e);
+                }
+                finish_batch(&work_queue, &batch, n_threads);
+            });
+        } else {
+            feed_reads(&self.work_queue, &batch, back_off, reads)?;
+            finish_batch(&self.work_queue, &batch, self.n_threads);
+        }
         Ok(res)
     }
 
+    /// Align the rows of a pandas or polars DataFrame, with the sequence of each read in the column `seq_col`.
+    /// Returns the same iterator as `map_batch`, with each row as a dictionary of its columns as the data for the read,
+    /// the sequence under `seq`, in place of `seq_col`, and takes the same keyword options. As for `map_batch`, an `id`
+    /// column is returned verbatim, otherwise `id` is set to the row's position in the DataFrame.
+    ///
+    /// Neither library is needed by mappy-rs. The rows are read lazily, as for an iterator passed to `map_batch`, a
+    /// slice of `DF_SLICE_ROWS` rows at a time with polars' `to_dicts()`, or pandas' `to_dict("records")`, so only the
+    /// rows waiting to be mapped are held as dictionaries, never the whole DataFrame. A `KeyError` is raised if there
+    /// is no `seq_col` column.
+    #[pyo3(
+        signature = (df, seq_col, **options),
+        text_signature = "(df, seq_col, **options)"
+    )]
+    fn map_batch_df(
+        &self,
+        py: Python,
+        df: &PyAny,
+        seq_col: String,
+        options: Option<&PyDict>,
+    ) -> PyResult<AlignmentBatchResultIter> {
+        self.check_threading()?;
+        if !df.hasattr("to_dicts")? && !df.hasattr("to_dict")? {
+            return Err(PyTypeError::new_err(
+                "Expected a pandas or polars DataFrame",
+            ));
+        }
+        if !df.getattr("columns")?.contains(&seq_col)? {
+            return Err(PyKeyError::new_err(format!(
+                "The DataFrame has no column {seq_col:?}"
+            )));
+        }
+        let options = MapBatchOptions::from_kwargs("map_batch_df", options)?;
+        let rows = helper_fn(py, &DF_ROWS, DF_ROWS_HELPER, "mappy_rs_df", "df_rows")?.call1((
+            df,
+            seq_col,
+            DF_SLICE_ROWS,
+        ))?;
+        self.map_batch_with(rows, options)
+    }
+
+    /// Align a batch of reads from an async iterable, such as an async generator, of dictionaries as for `map_batch`.
+    /// Must be called from a coroutine running in an `asyncio` event loop. The reads are pulled from `seqs` on a
+    /// separate thread, which schedules each `__anext__` on the event loop, so reads can arrive while results are
+    /// consumed. Returns an async iterator of the results, for use with `async for`, yielding what the iterator of
+    /// `map_batch` would. Takes the same keyword options as `map_batch`, each read's dictionary always travelling
+    /// through the work queue, as with `carry_metadata`.
+    #[pyo3(
+        signature = (seqs, **options),
+        text_signature = "(seqs, **options)"
+    )]
+    fn map_batch_async(
+        &self,
+        py: Python,
+        seqs: &PyAny,
+        options: Option<&PyDict>,
+    ) -> PyResult<AsyncAlignmentBatchResultIter> {
+        self.check_threading()?;
+        let options = MapBatchOptions::from_kwargs("map_batch_async", options)?;
+        let event_loop: PyObject = py
+            .import("asyncio")?
+            .call_method0("get_running_loop")?
+            .into();
+        let aiter: PyObject = seqs.call_method0("__aiter__")?.into();
+        let (mut res, batch, back_off) = self.prepare_batch(options)?;
+        let batch = self.start_batch(&mut res, batch);
+        let (on_error, keep_metadata) = (res.on_error, res.keeps_metadata());
+        let work_queue = Arc::clone(&self.work_queue);
+        let n_threads = self.n_threads;
+        let error = Arc::clone(&res.error);
+        std::thread::spawn(move || {
+            if let Err(e) = feed_async(
+                &work_queue,
+                &batch,
+                back_off,
+                &event_loop,
+                &aiter,
+                on_error,
+                keep_metadata,
+            ) {
+                // Raised from the result iterator once the reads fed so far have been returned
+                *error.lock().unwrap() = Some(e);
+            }
+            finish_batch(&work_queue, &batch, n_threads);
+        });
+        Ok(AsyncAlignmentBatchResultIter {
+            results: Py::new(py, res)?,
+        })
+    }
+
     /// Return whether or not this Aligner has an index.
     fn __bool__(&self) -> PyResult<bool> {
         Ok(self.aligner.idx.is_some())
@@ -655,150 +3085,688 @@ impl Aligner {
     /// Get the k value from the index.
     #[getter]
     fn k(&self) -> PyResult<i32> {
-        Ok(self.aligner.idx.unwrap().k)
+        Ok(self.aligner.idx.ok_or(MappyError::NoIndex)?.k)
     }
     /// Get the w value form the index.
     #[getter]
     fn w(&self) -> PyResult<i32> {
-        Ok(self.aligner.idx.unwrap().w)
+        Ok(self.aligner.idx.ok_or(MappyError::NoIndex)?.w)
+    }
+
+    /// Get the preset the `Aligner` was created with, e.g. `"map-ont"`, or `None` if there wasn't one
+    #[getter]
+    fn preset(&self) -> PyResult<Option<String>> {
+        Ok(self.preset.clone())
     }
 
     /// Get the number of sequences present in the index
     #[getter]
     fn n_seq(&self) -> PyResult<u32> {
-        Ok(self.aligner.idx.unwrap().n_seq)
+        if !self.aligner.has_index() {
+            return Err(MappyError::NoIndex.into());
+        }
+        Ok(self.index.parts().map(|idx| idx.n_seq).sum())
     }
-}
 
-impl Aligner {
-    /// Instead of calling out to the ALigner, return a predefined dummy mapping
-    pub fn no_op_map(&self) -> Vec<Mapping> {
-        vec![Mapping {
-            query_start: 0,                                             // i32,
-            query_end: 1000,                                            // i32,
-            strand: Strand::from_mm2_strand(minimap2::Strand::Forward), // Strand,
-            target_name: String::from("Hello"),                         // String,
-            target_len: 101010,                                         // i32,
-            target_start: 10,                                           // i32,
-            target_end: 1010,                                           // i32,
-            match_len: 1000,                                            // i32,
-            block_len: 1000,                                            // i32,
-            mapq: 60,                                                   // u32,
-            is_primary: true,                                           // bool
-            cigar: vec![],                                              // Vec<(u32, u8)>
-            NM: 0,
-            MD: None,
-            cs: Some(String::from("Cigar string")),
-        }]
+    /// Get the number of reads mapped by `map`, `map_best`, `map_timed` and `map_batch` so far
+    #[getter]
+    fn total_reads(&self) -> PyResult<usize> {
+        Ok(self.counts.reads.load(Ordering::Relaxed))
     }
-    /// Setup signal catching for ctrl c to stop threads
-    pub fn setup_signal(&self) {
-        let stop = Arc::clone(&self.stop);
-        ctrlc::set_handler(move || {
-            println!("Signal intercepted");
-            *stop.lock().unwrap() = true;
-            std::process::exit(0);
-        })
-        .expect("Failed to set signal listener");
+
+    /// Get the number of reads mapped so far which had no mappings, see `total_reads`
+    #[getter]
+    fn total_unmapped(&self) -> PyResult<usize> {
+        Ok(self.counts.unmapped.load(Ordering::Relaxed))
     }
-    /// Private function
-    /// Get a sequence or subsequence of a contig loaded into the index.
-    pub fn _get_index_seq(&self, name: String, start: i32, mut end: i32) -> Result<String, &str> {
-        if !self.aligner.has_index() {
-            return Err("No index");
-        }
+
+    /// Return the number of primary mappings to each contig made by `map`, `map_best`, `map_timed` and `map_batch`
+    /// so far, since the last `reset_contig_hit_counts`. Contigs with no mappings are left out.
+    fn contig_hit_counts(&self) -> HashMap<String, u64> {
+        self.counts.contig_hits.lock().unwrap().clone()
+    }
+
+    /// Set the counts returned by `contig_hit_counts` back to zero.
+    fn reset_contig_hit_counts(&self) {
+        self.counts.contig_hits.lock().unwrap().clear();
+    }
+
+    /// Get the number of reads waiting in the work queue to be mapped by the worker threads
+    #[getter]
+    fn work_queue_len(&self) -> PyResult<usize> {
+        Ok(self.work_queue.len())
+    }
+
+    /// Get the summed length of every sequence in the index, the size of the reference genome
+    #[getter]
+    fn total_ref_len(&self) -> PyResult<u64> {
+        Ok(self.seq_lens()?.into_iter().map(u64::from).sum())
+    }
+
+    /// Get the number of parts the index was read in, more than one if it was built with a small `index_batch_size`
+    #[getter]
+    fn n_parts(&self) -> PyResult<usize> {
+        Ok(self.index.parts.len())
+    }
+
+    /// Return a dictionary of statistics about the loaded index, to help tune `min_cnt` and `bw` against repetitive genomes.
+    ///
+    /// Contains the number of sequences (`n_seq`), their summed length (`total_len`), `k`, `w`,
+    /// and the occurrence cutoff above which minimizers are ignored when seeding (`mid_occ`).
+    fn index_stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let idx = self.aligner.idx.ok_or(MappyError::NoIndex)?;
+        let stats = PyDict::new(py);
+        stats.set_item("n_seq", self.n_seq()?)?;
+        stats.set_item("total_len", self.total_ref_len()?)?;
+        stats.set_item("k", idx.k)?;
+        stats.set_item("w", idx.w)?;
+        stats.set_item("mid_occ", self.aligner.mapopt.mid_occ)?;
+        Ok(stats)
+    }
+
+    /// Get the minimal chaining score (`-m`) from the mapping options.
+    #[getter]
+    fn min_chain_score(&self) -> PyResult<i32> {
+        Ok(self.aligner.mapopt.min_chain_score)
+    }
+
+    /// Get the minimal number of minimizers on a chain (`-n`) from the mapping options.
+    #[getter]
+    fn min_cnt(&self) -> PyResult<i32> {
+        Ok(self.aligner.mapopt.min_cnt)
+    }
+
+    /// Get the chaining and alignment bandwidth (`-r`) from the mapping options.
+    #[getter]
+    fn bw(&self) -> PyResult<i32> {
+        Ok(self.aligner.mapopt.bw)
+    }
+
+    /// Get the number of secondary alignments to keep (`-N`) from the mapping options.
+    #[getter]
+    fn best_n(&self) -> PyResult<i32> {
+        Ok(self.aligner.mapopt.best_n)
+    }
+
+    /// Get the max fragment length (`-F`) from the mapping options.
+    #[getter]
+    fn max_frag_len(&self) -> PyResult<i32> {
+        Ok(self.aligner.mapopt.max_frag_len)
+    }
+
+    /// Get the `MM_F_*` bit flags set on the mapping options.
+    #[getter]
+    fn flag(&self) -> PyResult<i64> {
+        Ok(self.aligner.mapopt.flag)
+    }
+
+    /// Return the names of the `MM_F_*` flags set on the mapping options, e.g. `["MM_F_CIGAR"]`,
+    /// to check which minimap2 features are enabled.
+    fn decode_flags(&self) -> Vec<String> {
+        let flag = self.aligner.mapopt.flag as u64;
+        MAP_FLAGS
+            .iter()
+            .filter(|(bit, _)| flag & bit != 0)
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+}
+
+impl Aligner {
+    /// Construct an `Aligner` from `options`, as `Aligner.__new__` does from its arguments.
+    fn new(options: AlignerOptions) -> PyResult<Aligner> {
+        let AlignerOptions {
+            fn_idx_in,
+            preset,
+            k,
+            w,
+            min_cnt,
+            min_chain_score,
+            min_dp_score,
+            bw,
+            best_n,
+            n_threads,
+            max_frag_len,
+            extra_flags,
+            scoring,
+            min_dp_max,
+            splice_flank,
+            no_end_flt,
+            mappy_compat,
+            index_batch_size,
+            occ_frac,
+            for_only,
+            max_chain_iter,
+            max_gap,
+            split_prefix,
+        } = options.clone();
+        let mut mapopts = minimap2::MapOpt::default();
+        let mut idxopts = minimap2::IdxOpt::default();
+        unsafe { minimap2_sys::mm_set_opt(std::ptr::null(), &mut idxopts, &mut mapopts) };
+        if let Some(preset) = &preset {
+            let _preset = std::ffi::CString::new(preset.as_str()).unwrap();
+            unsafe { minimap2_sys::mm_set_opt(_preset.as_ptr(), &mut idxopts, &mut mapopts) };
+        }
+        // For 'drop-in' mappy compatibility we should add the flag 4
+        if mappy_compat {
+            mapopts.flag |= minimap2_sys::MM_F_CIGAR as i64;
+        }
+        // By default the whole reference is indexed as a single part
+        idxopts.batch_size = index_batch_size.unwrap_or(0x7fffffffffffffff_u64);
+        // As minimap2 does, never read more of the reference at once than fits in a single part
+        if idxopts.mini_batch_size as u64 > idxopts.batch_size {
+            idxopts.mini_batch_size = idxopts.batch_size as i64;
+        }
+
+        // The limits minimap2 itself checks, as minimizers and their windows are packed into fixed width fields
+        for (name, value, max) in [("k", k, 28), ("w", w, 255)] {
+            if value.is_some_and(|value| !(1..=max).contains(&value)) {
+                return Err(PyValueError::new_err(format!(
+                    "`{name}` must be between 1 and {max}, as for minimap2, not {}",
+                    value.unwrap()
+                )));
+            }
+        }
+        if let Some(k) = k {
+            idxopts.k = k as i16
+        }
+        if let Some(w) = w {
+            idxopts.w = w as i16
+        }
+        if let Some(min_cnt) = min_cnt {
+            mapopts.min_cnt = min_cnt as i32
+        }
+        if let Some(min_chain_score) = min_chain_score {
+            mapopts.min_chain_score = min_chain_score as i32
+        }
+        if let (Some(min_dp_score), Some(min_dp_max)) = (min_dp_score, min_dp_max) {
+            if min_dp_score != min_dp_max {
+                return Err(PyValueError::new_err(
+                    "`min_dp_score` and `min_dp_max` set the same option, but were given different values",
+                ));
+            }
+        }
+        if let Some(min_dp_max) = min_dp_score.or(min_dp_max) {
+            mapopts.min_dp_max = min_dp_max as i32
+        }
+        if let Some(bw) = bw {
+            mapopts.bw = bw as i32
+        }
+        if let Some(occ_frac) = occ_frac {
+            if !(0.0..1.0).contains(&occ_frac) {
+                return Err(PyValueError::new_err(format!(
+                    "`occ_frac` must be a fraction between 0 and 1, not {occ_frac}"
+                )));
+            }
+            mapopts.mid_occ_frac = occ_frac;
+            // Left unset, so `mid_occ` is calculated from `occ_frac` once the index is read
+            mapopts.mid_occ = 0;
+        }
+        if let Some(best_n) = best_n {
+            mapopts.best_n = best_n as i32
+        }
+        if let Some(max_chain_iter) = max_chain_iter {
+            mapopts.max_chain_iter = max_chain_iter as i32
+        }
+        if let Some(max_gap) = max_gap {
+            mapopts.max_gap = max_gap as i32
+        }
+        if let Some(max_frag_len) = max_frag_len {
+            mapopts.max_frag_len = max_frag_len as i32
+        }
+        if let Some(extra_flags) = extra_flags {
+            mapopts.flag |= extra_flags as i64
+        }
+        if for_only {
+            mapopts.flag |= minimap2_sys::MM_F_FOR_ONLY as i64;
+        }
+        for (set, flag) in [
+            (splice_flank, minimap2_sys::MM_F_SPLICE_FLANK),
+            (no_end_flt, minimap2_sys::MM_F_NO_END_FLT),
+        ] {
+            match set {
+                Some(true) => mapopts.flag |= flag as i64,
+                Some(false) => mapopts.flag &= !(flag as i64),
+                None => {}
+            }
+        }
+        if let Some(scores) = &scoring {
+            if ![4, 6, 7].contains(&scores.len()) {
+                return Err(PyValueError::new_err(format!(
+                    "`scoring` must be a tuple of 4, 6 or 7 integers, (a, b, q, e[, q2, e2[, sc_ambi]]), not {} elements",
+                    scores.len()
+                )));
+            }
+            mapopts.a = scores[0];
+            mapopts.b = scores[1];
+            mapopts.q = scores[2];
+            mapopts.e = scores[3];
+            mapopts.q2 = scores.get(4).copied().unwrap_or(mapopts.q);
+            mapopts.e2 = scores.get(5).copied().unwrap_or(mapopts.e);
+            if let Some(&sc_ambi) = scores.get(6) {
+                mapopts.sc_ambi = sc_ambi;
+            }
+        }
+
+        if let Some(fn_idx_in) = fn_idx_in {
+            let (reader, mut index) = read_index(&fn_idx_in, &idxopts, n_threads)?;
+            index.merge_parts = split_prefix.is_some();
+            let idx = index.parts[0];
+            // A prebuilt index keeps the `k` and `w` it was built with, so any given here have no effect
+            if reader.is_idx != 0 {
+                let (idx_k, idx_w) = unsafe { ((*idx).k, (*idx).w) };
+                let ignored: Vec<String> = [("k", k, idx_k), ("w", w, idx_w)]
+                    .into_iter()
+                    .filter_map(|(name, given, built)| match given {
+                        Some(given) if given as i32 != built => Some(format!("{name}={given}")),
+                        _ => None,
+                    })
+                    .collect();
+                if !ignored.is_empty() {
+                    let message = format!(
+                        "{} ignored, {} is a prebuilt index with k={idx_k} and w={idx_w}",
+                        ignored.join(" and "),
+                        fn_idx_in.display()
+                    );
+                    Python::with_gil(|py| {
+                        PyErr::warn(py, py.get_type::<PyUserWarning>(), &message, 1)
+                    })?;
+                }
+            }
+            // Set index opts. As minimap2 does, the options set from the first part are used for every part
+            unsafe { minimap2_sys::mm_mapopt_update(&mut mapopts, idx) };
+            let al = Aligner {
+                aligner: minimap2::Aligner {
+                    mapopt: mapopts,
+                    idxopt: idxopts,
+                    threads: n_threads,
+                    idx: Some(unsafe { *idx }),
+                    idx_reader: Some(reader),
+                },
+                index: Arc::new(index),
+                preset,
+                options: Some(options),
+                buf: ThreadBuffer::new(),
+                n_threads: 0,
+                rayon: false,
+                _handles: Arc::new(Mutex::new(vec![])),
+                busy: Arc::new(Mutex::new(vec![])),
+                stop: Arc::new(Mutex::new(false)),
+                in_flight: Arc::new(AtomicUsize::new(0)),
+                counts: Arc::new(MapCounts::default()),
+                batches: Arc::new(Mutex::new(vec![])),
+                work_queue: Arc::new(ArrayQueue::<(
+                    Arc<Batch>,
+                    WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+                )>::new(50000)),
+            };
+            // al.setup_signal();
+            return Ok(al);
+        }
+        Err(PyRuntimeError::new_err("Did not create or open an index"))
+    }
+
+    /// Instead of calling out to the ALigner, return a predefined dummy mapping
+    pub fn no_op_map(&self) -> Vec<Mapping> {
+        no_op_mappings()
+    }
+    /// Map a batch of reads as for `map_batch` with its default options, first enabling threading with `n_threads`
+    /// threads if it isn't already enabled, so a batch can't be submitted without threads to map it.
+    pub fn map_batch_threaded(
+        &mut self,
+        seqs: &PyAny,
+        n_threads: NonZeroUsize,
+    ) -> PyResult<AlignmentBatchResultIter> {
+        if self.n_threads == 0 {
+            self.enable_threading(n_threads.get())?;
+        }
+        self.map_batch_with(seqs, MapBatchOptions::default())
+    }
+
+    /// Map a batch of reads as for `map_batch`, with its keyword arguments gathered into `options`.
+    pub(crate) fn map_batch_with(
+        &self,
+        seqs: &PyAny,
+        options: MapBatchOptions,
+    ) -> PyResult<AlignmentBatchResultIter> {
+        let carry_metadata = options.carry_metadata;
+        let (mut res, batch, back_off) = self.prepare_batch(options)?;
+        // do the heavy work
+        self._map_batch(&mut res, seqs, back_off, batch, carry_metadata)?;
+        Ok(res)
+    }
+
+    /// Check `options`, and set up the results iterator, the batch and the backoff for a batch mapped with them.
+    /// `carry_metadata` is left to the caller, as it depends on how the reads are fed.
+    fn prepare_batch(
+        &self,
+        options: MapBatchOptions,
+    ) -> PyResult<(AlignmentBatchResultIter, Batch, Option<BackOff>)> {
+        let mut res = AlignmentBatchResultIter::new();
+        res.on_error = options.on_error;
+        res.set_result_bound(options.result_bound)?;
+        res.attach_query_name = options.attach_query_name;
+        res.yield_metadata = options.yield_metadata;
+        res.as_paf = options.as_paf;
+        res.mapq_fn = options.mapq_fn;
+        // Set the number of threads
+        res.set_n_threads(self.n_threads);
+        MapFilter::check_mapq_range(options.mapq_min, options.mapq_max)?;
+        let filter = MapFilter {
+            max_hits: options.max_hits,
+            target_names: options
+                .target_names
+                .map(|names| names.into_iter().collect()),
+            min_query_len: options.min_query_len,
+            mapq_min: options.mapq_min,
+            mapq_max: options.mapq_max,
+        };
+        let back_off = if options.deterministic {
+            Some(BackOff::wait_forever())
+        } else {
+            options.back_off.then_some(BackOff {
+                max_attempts: options.max_backoff_attempts,
+                initial_sleep: Duration::from_millis(options.initial_backoff_ms),
+                max_sleep: None,
+            })
+        };
+        if options.deterministic {
+            res.in_order = Some(InOrder::default());
+        }
+        let mut batch = Batch::new(filter);
+        batch.results_queue = ArrayQueue::new(options.result_bound);
+        batch.no_op = options.no_op;
+        batch.dry_run = options.dry_run;
+        if options.max_inflight == Some(0) {
+            return Err(PyValueError::new_err("`max_inflight` must be at least 1"));
+        }
+        batch.max_inflight = options.max_inflight;
+        if options.chunk_size == 0 {
+            return Err(PyValueError::new_err("`chunk_size` must be at least 1"));
+        }
+        if options
+            .max_inflight
+            .is_some_and(|max_inflight| options.chunk_size > max_inflight)
+        {
+            return Err(PyValueError::new_err(
+                "`chunk_size` can't be more than `max_inflight`, the chunk would never fill",
+            ));
+        }
+        batch.chunk_size = options.chunk_size;
+        if options.max_queue_bytes == Some(0) {
+            return Err(PyValueError::new_err(
+                "`max_queue_bytes` must be at least 1",
+            ));
+        }
+        batch.max_queue_bytes = options.max_queue_bytes;
+        Ok((res, batch, back_off))
+    }
+    /// Setup signal catching for ctrl c to stop threads
+    pub fn setup_signal(&self) {
+        let stop = Arc::clone(&self.stop);
+        ctrlc::set_handler(move || {
+            println!("Signal intercepted");
+            *stop.lock().unwrap() = true;
+            std::process::exit(0);
+        })
+        .expect("Failed to set signal listener");
+    }
+    /// Private function
+    /// Get a sequence or subsequence of a contig loaded into the index.
+    pub fn _get_index_seq(
+        &self,
+        name: String,
+        mut start: i32,
+        mut end: i32,
+    ) -> Result<String, &str> {
+        if !self.aligner.has_index() {
+            return Err("No index");
+        }
         if (self.aligner.mapopt.flag & minimap2_sys::MM_F_CIGAR as i64 != 0)
             && (self.aligner.idx.unwrap().flag & minimap2_sys::MM_I_NO_SEQ as i32 != 0)
         {
             return Err("No sequence in this index");
         }
-        let ref_seq_id: i32 = unsafe {
-            minimap2_sys::mm_idx_name2id(
-                self.aligner.idx.as_ref().unwrap() as *const minimap2_sys::mm_idx_t,
-                std::ffi::CString::new(name)
-                    .unwrap()
-                    .as_bytes_with_nul()
-                    .as_ptr() as *const i8,
-            )
-        };
-        if (ref_seq_id < 0) | (ref_seq_id as u32 >= self.aligner.idx.unwrap().n_seq) {
+        let name = std::ffi::CString::new(name).unwrap();
+        // Find the part of the index the reference is in
+        let Some((idx, ref_seq_id)) = self.index.parts().find_map(|idx| {
+            let ref_seq_id = unsafe { minimap2_sys::mm_idx_name2id(idx, name.as_ptr()) };
+            ((ref_seq_id >= 0) & ((ref_seq_id as u32) < idx.n_seq)).then_some((idx, ref_seq_id))
+        }) else {
             return Err("Could not find reference in index");
-        }
-
-        let ref_seq_offset = unsafe {
-            *(self
-                .aligner
-                .idx
-                .as_ref()
-                .unwrap()
-                .seq
-                .offset(ref_seq_id as isize))
         };
+
+        let ref_seq_offset = unsafe { *(idx.seq.offset(ref_seq_id as isize)) };
         let ref_seq_len = ref_seq_offset.len as i32;
+        // As with python slicing, negative coordinates count back from the end of the contig
+        if start < 0 {
+            start = (start + ref_seq_len).max(0);
+        }
+        if end < 0 {
+            end += ref_seq_len;
+        }
+        end = end.min(ref_seq_len);
         if start >= ref_seq_len || start >= end {
             return Err("Funky start and end coords");
         }
-        if end < 0 || end > ref_seq_len {
-            end = ref_seq_len;
-        }
         let seq_len = end - start;
         let mut seq_buf: Vec<u8> = vec![0; seq_len as usize];
-        let _len = unsafe {
+        let len = unsafe {
             minimap2_sys::mm_idx_getseq(
-                self.aligner.idx.as_ref().unwrap() as *const minimap2_sys::mm_idx_t,
+                idx,
                 ref_seq_id as u32,
                 start as u32,
                 end as u32,
                 seq_buf.as_mut_ptr(),
             )
         };
+        if len <= 0 {
+            return Err("Could not retrieve sequence from index");
+        }
+        // Any of the buffer past the returned length was not filled in, and would decode to `A`
+        seq_buf.truncate(len as usize);
+        let mut n_unknown = 0;
         for c in &mut seq_buf {
             *c = match *c {
                 0 => 65, // A
                 1 => 67, // C
                 2 => 71, // G
                 3 => 84, // T
+                // minimap2 stores every IUPAC ambiguity code as 4
                 4 => 78, // N
-                _ => return Err("Got an unknown char, not {ACGTN}"),
+                _ => {
+                    n_unknown += 1;
+                    78
+                }
             }
         }
+        if n_unknown > 0 {
+            let message = format!(
+                "{n_unknown} unknown base codes in {} were returned as N",
+                name.to_string_lossy()
+            );
+            Python::with_gil(|py| {
+                // A warning escalated to an exception can't be raised from here, so report it as unraisable
+                if let Err(e) = PyErr::warn(py, py.get_type::<PyRuntimeWarning>(), &message, 1) {
+                    e.write_unraisable(py, None);
+                }
+            });
+        }
         Ok(std::string::String::from_utf8(seq_buf).unwrap())
     }
 
     /// Align a batch of reads provided in an iterator, using a threadpool with the number of threads specified by
     /// .enable_threading()
     #[allow(clippy::type_complexity)]
-    pub fn _map_batch(
+    pub(crate) fn _map_batch(
         &self,
         res: &mut AlignmentBatchResultIter,
         seqs: &PyAny,
-        back_off: bool,
+        back_off: Option<BackOff>,
+        batch: Batch,
+        carry_metadata: bool,
     ) -> PyResult<()> {
-        if self.n_threads == 0_usize {
-            return Err(PyRuntimeError::new_err(
-                "Multi threading not enabled on this instance. Please call `.enable_threading()`",
-            ));
-        }
-        match seqs.extract() {
-            Ok(SupportedTypes::List(_)) => (),
-            Ok(SupportedTypes::Tuple(_)) => (),
-            Ok(SupportedTypes::Iter(_)) => (),
-            Ok(SupportedTypes::Sequence(_)) => (),
+        self.check_threading()?;
+        let is_iter = match seqs.extract() {
+            Ok(SupportedTypes::List(_)) => false,
+            Ok(SupportedTypes::Tuple(_)) => false,
+            Ok(SupportedTypes::Iter(_)) => true,
+            Ok(SupportedTypes::Sequence(_)) => false,
             _ => {
                 return Err(PyTypeError::new_err(
                     "Unsupported batch type, pass a list, iter, generator or tuple",
                 ))
             }
         };
-        let results_queue: Arc<ArrayQueue<WorkQueue<(Vec<Mapping>, usize)>>> =
-            Arc::clone(&self.results_queue);
+        // An empty batch is finished straight away, without starting a collector thread or queueing any work
+        if !is_iter && seqs.len()? == 0 {
+            res.finished = true;
+            // For callers receiving from the channel directly, rather than through the iterator
+            res.tx
+                .send(WorkQueue::Finished)
+                .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+            return Ok(());
+        }
+        let lazy = is_iter || batch.feeds_lazily(seqs.len()?);
+        let batch = self.start_batch(res, batch);
+        let iter = match seqs.iter() {
+            Ok(it) => it,
+            _ => return Err(PyTypeError::new_err("Could not iterate batch")),
+        };
+        if lazy {
+            // Iterators are drained on their own thread, so reads are mapped while they are still being produced
+            let iter: Py<PyIterator> = iter.into();
+            let work_queue = Arc::clone(&self.work_queue);
+            let n_threads = self.n_threads;
+            let on_error = res.on_error;
+            let keep_metadata = res.keeps_metadata();
+            let error = Arc::clone(&res.error);
+            std::thread::spawn(move || {
+                if let Err(e) = feed_iter(
+                    &work_queue,
+                    &batch,
+                    back_off,
+                    &iter,
+                    on_error,
+                    keep_metadata,
+                ) {
+                    // Raised from the result iterator once the reads fed so far have been returned
+                    *error.lock().unwrap() = Some(e);
+                }
+                finish_batch(&work_queue, &batch, n_threads);
+            });
+            return Ok(());
+        }
+        let mut feeder = ReadFeeder::new(&self.work_queue, &batch, back_off);
+        for (id_num, py_dict) in iter.enumerate() {
+            let (data, seq) = match batch_item(&batch, py_dict?, id_num, res.on_error)? {
+                Some(item) => item,
+                None => continue,
+            };
+            if !res.keeps_metadata() {
+                feeder.push(id_num, seq, None)?;
+            } else if carry_metadata {
+                feeder.push(id_num, seq, Some(data))?;
+            } else {
+                res.data.insert(id_num, data);
+                feeder.push(id_num, seq, None)?;
+            }
+        }
+        feeder.flush()?;
+        finish_batch(&self.work_queue, &batch, self.n_threads);
+        Ok(())
+    }
+
+    /// Map reads from Rust through the worker threads started by `enable_threading`, without going through Python.
+    ///
+    /// Send `(id, seq)` pairs on the returned `Sender`, and receive `Ok((mappings, id))` from the returned
+    /// `Receiver` as they are mapped, in no particular order. Dropping the `Sender` ends the stream, and the
+    /// `Receiver` disconnects once every read sent has been returned.
+    ///
+    /// A read which fails to map is received as a `MappyError::MapFailed`. If a read can't be added to the work
+    /// queue, a `MappyError::QueueFull` is received, and no more reads are mapped.
+    #[allow(clippy::type_complexity)]
+    pub fn channels(
+        &self,
+    ) -> PyResult<(
+        Sender<(usize, String)>,
+        Receiver<Result<(Vec<Mapping>, usize), MappyError>>,
+    )> {
+        self.check_threading()?;
+        let mut res = AlignmentBatchResultIter::new();
+        res.set_n_threads(self.n_threads);
+        let batch = self.start_batch(&mut res, Batch::new(MapFilter::default()));
+        let (read_tx, read_rx) = bounded::<(usize, String)>(20000);
+        let (mapping_tx, mapping_rx) = bounded(20000);
+        let work_queue = Arc::clone(&self.work_queue);
+        let n_threads = self.n_threads;
+        let feeder_batch = Arc::clone(&batch);
+        let error_tx = mapping_tx.clone();
+        std::thread::spawn(move || {
+            for (id_num, seq) in read_rx {
+                if let Err(e) = push_work(
+                    &work_queue,
+                    &feeder_batch,
+                    id_num,
+                    seq,
+                    None,
+                    Some(BackOff::default()),
+                ) {
+                    // The receiver may already be gone, in which case nobody is left to tell
+                    let _ = error_tx.send(Err(e));
+                    break;
+                }
+            }
+            finish_batch(&work_queue, &feeder_batch, n_threads);
+        });
+        std::thread::spawn(move || loop {
+            let result = match res.rx.recv() {
+                Ok(WorkQueue::Result((mappings, id_num, _))) => Ok((mappings, id_num)),
+                Ok(WorkQueue::Failed(id_num, reason, _)) => Err(MappyError::MapFailed(format!(
+                    "Failed to map read {id_num}: {reason}"
+                ))),
+                _ => break,
+            };
+            if mapping_tx.send(result).is_err() {
+                // Nobody is receiving any more, so stop mapping the rest
+                batch.cancelled.store(true, Ordering::SeqCst);
+                break;
+            }
+        });
+        Ok((read_tx, mapping_rx))
+    }
+
+    /// Error if threading has not been enabled, as there are no worker threads to map a batch.
+    fn check_threading(&self) -> PyResult<()> {
+        if self.n_threads == 0_usize {
+            return Err(MappyError::ThreadingNotEnabled.into());
+        }
+        Ok(())
+    }
+
+    /// Create a new batch returning its results through `res`, and spawn the collector thread
+    /// that passes results from the worker threads on to `res`.
+    fn start_batch(&self, res: &mut AlignmentBatchResultIter, batch: Batch) -> Arc<Batch> {
+        let batch = Arc::new(batch);
+        {
+            let mut batches = self.batches.lock().unwrap();
+            batches.retain(|batch| batch.strong_count() > 0);
+            batches.push(Arc::downgrade(&batch));
+        }
+        res.batch = Some(Arc::clone(&batch));
+        let collector_batch = Arc::clone(&batch);
         let results_tx = res.tx.clone();
         let counter = Arc::clone(&res._n_finished_threads);
         let n_threads = res._n_threads;
         std::thread::spawn(move || {
             loop {
+                if collector_batch.is_cancelled() {
+                    // Let an iterator still reading this batch stop. This errors rather than blocks once it is dropped
+                    let _ = results_tx.send(WorkQueue::Finished);
+                    break;
+                }
                 //             // pop returns None if the queue is empty, which is possible at the start as data hasn't been added below
-                match results_queue.pop() {
+                match collector_batch.results_queue.pop() {
                     //                 // We
                     Some(worky) => match worky {
                         // each thread can only see one workqueue DONE
@@ -810,20 +3778,17 @@ impl Aligner {
                             // println!("{num}");
                             // ALL threads have finished
                             if *num == n_threads {
-                                results_tx.send(WorkQueue::Finished).unwrap();
+                                collector_batch.collected.store(true, Ordering::SeqCst);
+                                // Errors if the iterator has been dropped, in which case nobody is waiting for it
+                                let _ = results_tx.send(WorkQueue::Finished);
                                 // reset number of finshed threads
                                 break;
                             }
                         }
-                        WorkQueue::Result(result) => {
-                            let id = result.1;
-                            match results_tx.send(WorkQueue::Result(result)) {
-                                Ok(()) => {}
-                                Err(e) => {
-                                    eprintln!("Internal error returning data, the receiver iterator has finished. {e} {id}");
-                                    break;
-                                }
-                            }
+                        // Once the iterator is dropped, nobody receives the results, but the batch is still drained
+                        // until it finishes, so the worker threads never wait on a full results queue forever
+                        result @ (WorkQueue::Result(_) | WorkQueue::Failed(..)) => {
+                            let _ = results_tx.send(result);
                         }
                         _ => {
                             eprintln!("Wrong WorkQueue arm seen in worker thread.")
@@ -837,271 +3802,3722 @@ impl Aligner {
                 //             // (id_num, seq): (usize, String)
             }
         });
-        let iter = match seqs.iter() {
-            Ok(it) => it,
-            _ => return Err(PyTypeError::new_err("Could not iterate batch")),
+        batch
+    }
+}
+
+/// Push a sequence onto the work queue for `batch`. If the queue is full and `back_off` is set,
+/// retry with an exponential backoff, erroring once its attempts are used up, otherwise error straight away.
+#[allow(clippy::type_complexity)]
+fn push_work(
+    work_queue: &ArrayQueue<(
+        Arc<Batch>,
+        WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+    )>,
+    batch: &Arc<Batch>,
+    id_num: usize,
+    seq: String,
+    data: Option<HashMap<String, Py<PyAny>>>,
+    back_off: Option<BackOff>,
+) -> Result<(), MappyError> {
+    push_work_item(
+        work_queue,
+        batch,
+        id_num,
+        WorkQueue::Work((id_num, seq, data)),
+        back_off,
+    )
+}
+
+/// Feeds the reads of a batch onto the work queue, grouped into chunks of the batch's `chunk_size` reads.
+/// `flush` must be called once every read has been fed, to push the last chunk.
+#[allow(clippy::type_complexity)]
+struct ReadFeeder<'a> {
+    /// The queue the worker threads map reads from
+    work_queue: &'a ArrayQueue<(
+        Arc<Batch>,
+        WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+    )>,
+    /// The batch the reads belong to
+    batch: &'a Arc<Batch>,
+    /// How to retry when the work queue is full
+    back_off: Option<BackOff>,
+    /// Reads waiting for the chunk to fill up
+    chunk: Vec<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+}
+
+impl<'a> ReadFeeder<'a> {
+    /// Create a new feeder, pushing the reads of `batch` onto `work_queue`.
+    #[allow(clippy::type_complexity)]
+    fn new(
+        work_queue: &'a ArrayQueue<(
+            Arc<Batch>,
+            WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+        )>,
+        batch: &'a Arc<Batch>,
+        back_off: Option<BackOff>,
+    ) -> ReadFeeder<'a> {
+        ReadFeeder {
+            work_queue,
+            batch,
+            back_off,
+            chunk: vec![],
+        }
+    }
+
+    /// Feed a read, pushing it straight away if the batch's `chunk_size` is 1, otherwise once its chunk is full.
+    fn push(
+        &mut self,
+        id_num: usize,
+        seq: String,
+        data: Option<HashMap<String, Py<PyAny>>>,
+    ) -> PyResult<()> {
+        if self.batch.chunk_size <= 1 {
+            return Ok(push_work(
+                self.work_queue,
+                self.batch,
+                id_num,
+                seq,
+                data,
+                self.back_off,
+            )?);
+        }
+        self.chunk.push((id_num, seq, data));
+        if self.chunk.len() >= self.batch.chunk_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Push the reads waiting in the current chunk, if there are any.
+    fn flush(&mut self) -> PyResult<()> {
+        let Some(id_num) = self.chunk.first().map(|read| read.0) else {
+            return Ok(());
         };
-        let work_queue: Arc<ArrayQueue<WorkQueue<(usize, String)>>> = Arc::clone(&self.work_queue);
-        for (id_num, py_dict) in iter.enumerate() {
-            let py_dict = py_dict?;
-            let data: HashMap<String, Py<PyAny>> = match py_dict.extract() {
-                Ok(x) => x,
-                _ => {
-                    return Err(PyTypeError::new_err(
-                        "Element in iterable is not a dictionary",
-                    ))
-                }
-            };
-            res.data.insert(id_num, data);
-            let seq: String = match py_dict.get_item("seq") {
-                Ok(seq) => match seq.extract::<String>() {
-                    Ok(seq) => seq.clone(),
-                    _ => return Err(PyValueError::new_err("`seq` must be a string")),
-                },
-                _ => {
-                    return Err(PyKeyError::new_err(
-                        "AHHH Key 🗝️  not found in iterated dictionary",
-                    ))
-                }
-            };
-            match work_queue.push(WorkQueue::Work((id_num, seq))) {
-                Ok(()) => {}
-                Err(e) => {
-                    if back_off {
-                        let mut attempts = 0;
-                        let max_attempts = 6;
-                        let mut sleep_duration = Duration::from_millis(50); // Initial sleep duration (in milliseconds)
-
-                        while attempts < max_attempts {
-                            if work_queue.push(e.clone()).is_ok() {
-                                break; // Operation succeeded
-                            }
+        let chunk = mem::take(&mut self.chunk);
+        Ok(push_work_item(
+            self.work_queue,
+            self.batch,
+            id_num,
+            WorkQueue::Chunk(chunk),
+            self.back_off,
+        )?)
+    }
+}
 
-                            attempts += 1;
-                            thread::sleep(sleep_duration);
+/// Push `work_item`, a single read or a chunk of reads starting with the read `id_num`, onto the work queue for
+/// `batch`, retrying as for `push_work` if the queue is full.
+#[allow(clippy::type_complexity)]
+fn push_work_item(
+    work_queue: &ArrayQueue<(
+        Arc<Batch>,
+        WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+    )>,
+    batch: &Arc<Batch>,
+    id_num: usize,
+    work_item: WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+    back_off: Option<BackOff>,
+) -> Result<(), MappyError> {
+    let n_bytes = match &work_item {
+        WorkQueue::Work((_, seq, _)) => seq.len(),
+        WorkQueue::Chunk(reads) => reads.iter().map(|(_, seq, _)| seq.len()).sum(),
+        _ => 0,
+    };
+    batch.acquire_queue_bytes(n_bytes);
+    match work_queue.push((Arc::clone(batch), work_item)) {
+        Ok(()) => {}
+        Err(e) => {
+            if let Some(back_off) = back_off {
+                let mut attempts = 0;
+                let mut sleep_duration = back_off.initial_sleep;
+                let mut item = e;
 
-                            // Increase the sleep duration exponentially
-                            sleep_duration *= 2;
-                        }
-                        if attempts == 6 {
-                            eprintln!("Internal error adding data to work queue, with backoff. {e:#?}, {id_num}, Attempts: {attempts}");
-                        }
-                    } else {
-                        eprintln!("Internal error adding data to work queue, without backoff. {e:#?} {id_num}");
-                        return Err(PyErr::new::<PyRuntimeError, _>(format!(
-                            "Internal error adding data to work queue, without backoff. {e:#?} {id_num}. Is your fastq batch larger than 50000? Perhaps try `map_batch` with back_off=True?",
-                            e = e,
-                            id_num = id_num
+                loop {
+                    if attempts == back_off.max_attempts {
+                        // The read never made it onto the queue, so gives its bytes back
+                        batch.release_queue_bytes(n_bytes);
+                        return Err(MappyError::QueueFull(format!(
+                            "Internal error adding data to work queue, with backoff. Read {id_num} could not be added after {attempts} attempts. Perhaps try `map_batch` with a larger max_backoff_attempts?"
                         )));
                     }
+                    attempts += 1;
+                    thread::sleep(sleep_duration);
+                    match work_queue.push(item) {
+                        Ok(()) => break, // Operation succeeded
+                        Err(e) => item = e,
+                    }
+
+                    // Increase the sleep duration exponentially
+                    sleep_duration *= 2;
+                    if let Some(max_sleep) = back_off.max_sleep {
+                        sleep_duration = sleep_duration.min(max_sleep);
+                    }
+                }
+            } else {
+                batch.release_queue_bytes(n_bytes);
+                return Err(MappyError::QueueFull(format!(
+                    "Internal error adding data to work queue, without backoff. Read {id_num} could not be added as the queue is full. Perhaps try `map_batch` with back_off=True?"
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Map a single read of `batch` with `buf`, pushing its mappings, or why it failed, to the batch's results queue.
+/// A panic while mapping is caught and reported as a failure, so the batch always sees its `Done`.
+#[allow(clippy::too_many_arguments)]
+fn map_work_item(
+    aligner: &minimap2::Aligner,
+    index: &IndexHandle,
+    buf: &ThreadBuffer,
+    counts: &MapCounts,
+    batch: &Batch,
+    id_num: usize,
+    seq: String,
+    data: Option<HashMap<String, Py<PyAny>>>,
+) {
+    batch.release_queue_bytes(seq.len());
+    let mapped = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        #[cfg(test)]
+        if batch.panic_on == Some(id_num) {
+            panic!("Injected panic");
+        }
+        if batch.no_op {
+            Ok(no_op_mappings())
+        } else if batch.dry_run {
+            Ok(vec![])
+        } else {
+            map_seq(aligner, index, buf, &seq, true, false, None, &batch.filter)
+        }
+    }));
+    let result = match mapped {
+        Ok(Ok(mappings)) => {
+            mem::drop(seq);
+            if !batch.no_op && !batch.dry_run {
+                counts.record(&mappings);
+            }
+            WorkQueue::Result((mappings, id_num, data))
+        }
+        Ok(Err(reason)) => WorkQueue::Failed(id_num, reason.to_string(), data),
+        Err(panic) => WorkQueue::Failed(id_num, panic_message(&*panic), data),
+    };
+    push_result(batch, result);
+}
+
+/// Push a result, or `Done`, onto the results queue of `batch`, waiting while the queue is full, so a slow consumer
+/// holds the worker threads back rather than them failing. The collector thread drains the queue until the batch
+/// is finished, or it is cancelled, in which case the result is dropped as nobody will read it.
+#[allow(clippy::type_complexity)]
+fn push_result(
+    batch: &Batch,
+    mut result: WorkQueue<(Vec<Mapping>, usize, Option<HashMap<String, Py<PyAny>>>)>,
+) {
+    while let Err(rejected) = batch.results_queue.push(result) {
+        if batch.is_cancelled() {
+            return;
+        }
+        result = rejected;
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Push a `Done` for each of the `n_threads` worker threads, marking the end of `batch`.
+/// Waits while the work queue is full, as the worker threads are still taking reads off it, unless the batch is
+/// cancelled.
+#[allow(clippy::type_complexity)]
+fn finish_batch(
+    work_queue: &ArrayQueue<(
+        Arc<Batch>,
+        WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+    )>,
+    batch: &Arc<Batch>,
+    n_threads: usize,
+) {
+    // Now we add n_thread dones, one for each thread. When the threads see this they know to close as there is no more data
+    for _ in 0..n_threads {
+        let mut done = (Arc::clone(batch), WorkQueue::Done);
+        while let Err(rejected) = work_queue.push(done) {
+            if batch.is_cancelled() {
+                return;
+            }
+            done = rejected;
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// Read a single element of a batch, which must be a dictionary with a string `seq`, returning the dictionary
+/// and the sequence. Reads without their own `id` are given their position in the batch, `id_num`, as one.
+/// A `(name, seq)` tuple of strings is also accepted, and read as the dictionary `{"name": name, "seq": seq}`.
+fn read_batch_item(
+    py_dict: &PyAny,
+    id_num: usize,
+) -> PyResult<(HashMap<String, Py<PyAny>>, String)> {
+    let py = py_dict.py();
+    if let Ok((name, seq)) = py_dict.extract::<(String, String)>() {
+        let data = HashMap::from([
+            (String::from("name"), name.into_py(py)),
+            (String::from("seq"), seq.clone().into_py(py)),
+            (String::from("id"), id_num.into_py(py)),
+        ]);
+        return Ok((data, seq));
+    }
+    let mut data: HashMap<String, Py<PyAny>> = match py_dict.extract() {
+        Ok(x) => x,
+        _ => {
+            return Err(PyTypeError::new_err(
+                "Element in iterable is not a dictionary or a (name, seq) tuple",
+            ))
+        }
+    };
+    // Reads without their own id are identified by their position in the batch
+    data.entry(String::from("id"))
+        .or_insert_with(|| id_num.into_py(py));
+    let seq: String = match py_dict.get_item("seq") {
+        Ok(seq) => match seq.extract::<String>() {
+            Ok(seq) => seq.clone(),
+            _ => return Err(PyValueError::new_err("`seq` must be a string")),
+        },
+        _ => {
+            return Err(PyKeyError::new_err(
+                "AHHH Key 🗝️  not found in iterated dictionary",
+            ))
+        }
+    };
+    Ok((data, seq))
+}
+
+/// The predefined dummy mapping returned instead of calling out to minimap2 by `no_op_map` and no-op batches
+fn no_op_mappings() -> Vec<Mapping> {
+    vec![Mapping {
+        query_start: 0,                                             // i32,
+        query_end: 1000,                                            // i32,
+        strand: Strand::from_mm2_strand(minimap2::Strand::Forward), // Strand,
+        target_name: String::from("Hello"),                         // String,
+        target_len: 101010,                                         // i32,
+        target_start: 10,                                           // i32,
+        target_end: 1010,                                           // i32,
+        match_len: 1000,                                            // i32,
+        block_len: 1000,                                            // i32,
+        mapq: 60,                                                   // u32,
+        is_primary: true,                                           // bool
+        is_supplementary: false,                                    // bool
+        cigar: vec![],                                              // Vec<(u32, u8)>
+        NM: 0,
+        MD: None,
+        cs: Some(String::from("Cigar string")),
+        trans_strand: None,
+        s1: 0,
+        s2: 0,
+        query_name: None,
+        frag_id: None,
+        query_len: None,
+    }]
+}
+
+/// Every `MM_F_*` mapping option flag, with its name, in bit order.
+/// bindgen types flags past bit 31 as `u64` and the rest as `u32`, so all are cast.
+#[allow(clippy::unnecessary_cast)]
+const MAP_FLAGS: &[(u64, &str)] = &[
+    (minimap2_sys::MM_F_NO_DIAG as u64, "MM_F_NO_DIAG"),
+    (minimap2_sys::MM_F_NO_DUAL as u64, "MM_F_NO_DUAL"),
+    (minimap2_sys::MM_F_CIGAR as u64, "MM_F_CIGAR"),
+    (minimap2_sys::MM_F_OUT_SAM as u64, "MM_F_OUT_SAM"),
+    (minimap2_sys::MM_F_NO_QUAL as u64, "MM_F_NO_QUAL"),
+    (minimap2_sys::MM_F_OUT_CG as u64, "MM_F_OUT_CG"),
+    (minimap2_sys::MM_F_OUT_CS as u64, "MM_F_OUT_CS"),
+    (minimap2_sys::MM_F_SPLICE as u64, "MM_F_SPLICE"),
+    (minimap2_sys::MM_F_SPLICE_FOR as u64, "MM_F_SPLICE_FOR"),
+    (minimap2_sys::MM_F_SPLICE_REV as u64, "MM_F_SPLICE_REV"),
+    (minimap2_sys::MM_F_NO_LJOIN as u64, "MM_F_NO_LJOIN"),
+    (minimap2_sys::MM_F_OUT_CS_LONG as u64, "MM_F_OUT_CS_LONG"),
+    (minimap2_sys::MM_F_SR as u64, "MM_F_SR"),
+    (minimap2_sys::MM_F_FRAG_MODE as u64, "MM_F_FRAG_MODE"),
+    (minimap2_sys::MM_F_NO_PRINT_2ND as u64, "MM_F_NO_PRINT_2ND"),
+    (minimap2_sys::MM_F_2_IO_THREADS as u64, "MM_F_2_IO_THREADS"),
+    (minimap2_sys::MM_F_LONG_CIGAR as u64, "MM_F_LONG_CIGAR"),
+    (minimap2_sys::MM_F_INDEPEND_SEG as u64, "MM_F_INDEPEND_SEG"),
+    (minimap2_sys::MM_F_SPLICE_FLANK as u64, "MM_F_SPLICE_FLANK"),
+    (minimap2_sys::MM_F_SOFTCLIP as u64, "MM_F_SOFTCLIP"),
+    (minimap2_sys::MM_F_FOR_ONLY as u64, "MM_F_FOR_ONLY"),
+    (minimap2_sys::MM_F_REV_ONLY as u64, "MM_F_REV_ONLY"),
+    (minimap2_sys::MM_F_HEAP_SORT as u64, "MM_F_HEAP_SORT"),
+    (minimap2_sys::MM_F_ALL_CHAINS as u64, "MM_F_ALL_CHAINS"),
+    (minimap2_sys::MM_F_OUT_MD as u64, "MM_F_OUT_MD"),
+    (minimap2_sys::MM_F_COPY_COMMENT as u64, "MM_F_COPY_COMMENT"),
+    (minimap2_sys::MM_F_EQX as u64, "MM_F_EQX"),
+    (minimap2_sys::MM_F_PAF_NO_HIT as u64, "MM_F_PAF_NO_HIT"),
+    (minimap2_sys::MM_F_NO_END_FLT as u64, "MM_F_NO_END_FLT"),
+    (minimap2_sys::MM_F_HARD_MLEVEL as u64, "MM_F_HARD_MLEVEL"),
+    (minimap2_sys::MM_F_SAM_HIT_ONLY as u64, "MM_F_SAM_HIT_ONLY"),
+    (minimap2_sys::MM_F_RMQ as u64, "MM_F_RMQ"),
+    (minimap2_sys::MM_F_QSTRAND as u64, "MM_F_QSTRAND"),
+    (minimap2_sys::MM_F_NO_INV as u64, "MM_F_NO_INV"),
+    (minimap2_sys::MM_F_NO_HASH_NAME as u64, "MM_F_NO_HASH_NAME"),
+    (minimap2_sys::MM_F_SPLICE_OLD as u64, "MM_F_SPLICE_OLD"),
+    (
+        minimap2_sys::MM_F_SECONDARY_SEQ as u64,
+        "MM_F_SECONDARY_SEQ",
+    ),
+    (minimap2_sys::MM_F_OUT_DS as u64, "MM_F_OUT_DS"),
+];
+
+/// Python helper coroutine, awaiting the next item of an async iterator.
+/// `asyncio.run_coroutine_threadsafe` only accepts coroutines, which an async generator's `__anext__()` is not.
+const ANEXT_HELPER: &str = "async def anext_item(aiter):\n    return await aiter.__anext__()\n";
+
+/// `anext_item` of `ANEXT_HELPER`, compiled on the first call of `map_batch_async`.
+static ANEXT_ITEM: GILOnceCell<PyObject> = GILOnceCell::new();
+
+/// Number of rows of a DataFrame read into dictionaries at once by `map_batch_df`.
+const DF_SLICE_ROWS: usize = 10000;
+
+/// Python helper generator, yielding the rows of a polars or pandas DataFrame as dictionaries for `map_batch_df`,
+/// with the sequence moved from `seq_col` to `seq`. Only one slice of `n_rows` rows is held as dictionaries at a time.
+const DF_ROWS_HELPER: &str = concat!(
+    "def df_rows(df, seq_col, n_rows):\n",
+    "    for start in range(0, len(df), n_rows):\n",
+    "        if hasattr(df, \"to_dicts\"):\n",
+    "            rows = df.slice(start, n_rows).to_dicts()\n",
+    "        else:\n",
+    "            rows = df.iloc[start : start + n_rows].to_dict(\"records\")\n",
+    "        for row in rows:\n",
+    "            row[\"seq\"] = row.pop(seq_col)\n",
+    "            yield row\n",
+);
+
+/// `df_rows` of `DF_ROWS_HELPER`, compiled on the first call of `map_batch_df`.
+static DF_ROWS: GILOnceCell<PyObject> = GILOnceCell::new();
+
+/// Return the function `name` of the python helper module `code`, compiling the module as `module` only on the first
+/// call for `cell`, so its cost is not paid by every batch.
+fn helper_fn<'py>(
+    py: Python<'py>,
+    cell: &'static GILOnceCell<PyObject>,
+    code: &str,
+    module: &str,
+    name: &str,
+) -> PyResult<&'py PyAny> {
+    let function = cell.get_or_try_init(py, || -> PyResult<PyObject> {
+        let helper = PyModule::from_code(py, code, &format!("{module}.py"), module)?;
+        Ok(helper.getattr(name)?.into())
+    })?;
+    Ok(function.as_ref(py))
+}
+
+/// Read a single element of a batch with `read_batch_item`, applying the `on_error` policy if it is malformed.
+/// With `OnError::Warn` the read is pushed onto the results queue of `batch` as failed with `push_result`, and like
+/// a skipped read, `None` is returned.
+#[allow(clippy::type_complexity)]
+fn batch_item(
+    batch: &Batch,
+    py_dict: &PyAny,
+    id_num: usize,
+    on_error: OnError,
+) -> PyResult<Option<(HashMap<String, Py<PyAny>>, String)>> {
+    match read_batch_item(py_dict, id_num) {
+        Ok(item) => Ok(Some(item)),
+        Err(e) if on_error == OnError::Raise => Err(e),
+        Err(e) => {
+            if on_error == OnError::Warn {
+                // Returned unmapped through the results iterator, alongside the mapped reads
+                let mut data: HashMap<String, Py<PyAny>> = py_dict.extract().unwrap_or_default();
+                data.entry(String::from("id"))
+                    .or_insert_with(|| id_num.into_py(py_dict.py()));
+                push_result(batch, WorkQueue::Failed(id_num, e.to_string(), Some(data)));
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Drain the python iterator `iter` into the work queue for `batch`, holding the GIL only while each read is taken
+/// from it. Each read's dictionary is carried through the work queue if `keep_metadata` is set, and a full queue
+/// holds back the iterator.
+#[allow(clippy::type_complexity)]
+fn feed_iter(
+    work_queue: &ArrayQueue<(
+        Arc<Batch>,
+        WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+    )>,
+    batch: &Arc<Batch>,
+    back_off: Option<BackOff>,
+    iter: &Py<PyIterator>,
+    on_error: OnError,
+    keep_metadata: bool,
+) -> PyResult<()> {
+    let mut feeder = ReadFeeder::new(work_queue, batch, back_off);
+    for id_num in 0.. {
+        if batch.is_cancelled() {
+            break;
+        }
+        // Wait for a result to be returned if `max_inflight` reads already are waiting, without holding the GIL
+        batch.acquire_inflight();
+        let item = Python::with_gil(|py| match iter.as_ref(py).next() {
+            Some(py_dict) => batch_item(batch, py_dict?, id_num, on_error).map(Some),
+            None => Ok(None),
+        })?;
+        match item {
+            Some(Some((data, seq))) => feeder.push(id_num, seq, keep_metadata.then_some(data))?,
+            // No result is returned for a skipped read
+            Some(None) if on_error == OnError::Skip => batch.release_inflight(),
+            // Already returned as failed
+            Some(None) => {}
+            None => break,
+        }
+    }
+    feeder.flush()
+}
+
+/// Feed `reads` which have already been read from python into the work queue for `batch`, as `feed_iter` does.
+/// A read with an `Err` in place of its sequence is malformed, and is returned as failed with that reason.
+#[allow(clippy::type_complexity)]
+fn feed_reads(
+    work_queue: &ArrayQueue<(
+        Arc<Batch>,
+        WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+    )>,
+    batch: &Arc<Batch>,
+    back_off: Option<BackOff>,
+    reads: Vec<(
+        usize,
+        Result<String, String>,
+        Option<HashMap<String, Py<PyAny>>>,
+    )>,
+) -> PyResult<()> {
+    let mut feeder = ReadFeeder::new(work_queue, batch, back_off);
+    for (id_num, seq, data) in reads {
+        if batch.is_cancelled() {
+            break;
+        }
+        batch.acquire_inflight();
+        match seq {
+            Ok(seq) => feeder.push(id_num, seq, data)?,
+            // Returned unmapped through the results iterator, alongside the mapped reads
+            Err(reason) => push_result(batch, WorkQueue::Failed(id_num, reason, data)),
+        }
+    }
+    feeder.flush()
+}
+
+/// Drain the async iterator `aiter` into the work queue for `batch`, as `feed_iter` does for a python iterator.
+/// Each `__anext__` is scheduled on `event_loop` from this thread, and the GIL is released while it is awaited.
+#[allow(clippy::type_complexity)]
+fn feed_async(
+    work_queue: &ArrayQueue<(
+        Arc<Batch>,
+        WorkQueue<(usize, String, Option<HashMap<String, Py<PyAny>>>)>,
+    )>,
+    batch: &Arc<Batch>,
+    back_off: Option<BackOff>,
+    event_loop: &PyObject,
+    aiter: &PyObject,
+    on_error: OnError,
+    keep_metadata: bool,
+) -> PyResult<()> {
+    let anext_item: PyObject = Python::with_gil(|py| -> PyResult<_> {
+        Ok(helper_fn(
+            py,
+            &ANEXT_ITEM,
+            ANEXT_HELPER,
+            "mappy_rs_anext",
+            "anext_item",
+        )?
+        .into())
+    })?;
+    let mut feeder = ReadFeeder::new(work_queue, batch, back_off);
+    for id_num in 0.. {
+        if batch.is_cancelled() {
+            break;
+        }
+        // Wait for a result to be returned if `max_inflight` reads already are waiting, without holding the GIL
+        batch.acquire_inflight();
+        let item = Python::with_gil(|py| -> PyResult<_> {
+            let coroutine = anext_item.call1(py, (aiter,))?;
+            let future = py
+                .import("asyncio")?
+                .call_method1("run_coroutine_threadsafe", (coroutine, event_loop))?;
+            // `Future.result` releases the GIL while it waits, so the event loop can run the coroutine
+            match future.call_method0("result") {
+                Ok(py_dict) => batch_item(batch, py_dict, id_num, on_error).map(Some),
+                Err(e) if e.is_instance_of::<PyStopAsyncIteration>(py) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })?;
+        match item {
+            Some(Some((data, seq))) => feeder.push(id_num, seq, keep_metadata.then_some(data))?,
+            // No result is returned for a skipped read
+            Some(None) if on_error == OnError::Skip => batch.release_inflight(),
+            // Already returned as failed
+            Some(None) => {}
+            None => break,
+        }
+    }
+    feeder.flush()
+}
+
+/// Map a single sequence with the given minimap2 aligner, converting the results to `Mapping`s
+/// and applying the `MapFilter`. Used by both the blocking `map` and the worker threads.
+/// `buf` is the minimap2 thread buffer to map with, and must not be used by another thread at the same time.
+/// `extra_flags` are OR'd onto the aligner's `mapopt.flag` for this call only.
+/// The read is mapped against each part of `index` in turn. Unless the index merges its parts, see `merge_part_regs`,
+/// the mappings to each part are returned together as for minimap2 without `--split-prefix`, and a read can have
+/// a primary mapping in each part.
+#[allow(clippy::too_many_arguments)]
+fn map_seq(
+    aligner: &minimap2::Aligner,
+    index: &IndexHandle,
+    buf: &ThreadBuffer,
+    seq: &str,
+    cs: bool,
+    md: bool,
+    extra_flags: Option<&[u64]>,
+    filter: &MapFilter,
+) -> Result<Vec<Mapping>, &'static str> {
+    aligner.idx.as_ref().ok_or("No index")?;
+    if filter
+        .min_query_len
+        .is_some_and(|min_query_len| seq.len() < min_query_len)
+    {
+        return Ok(vec![]);
+    }
+    if seq.is_empty() {
+        return Err("Sequence is empty");
+    }
+    let mut mapopt = aligner.mapopt;
+    for flag in extra_flags.unwrap_or_default() {
+        mapopt.flag |= *flag as i64;
+    }
+    let mut part_regs = vec![];
+    for idx in index.parts() {
+        let mut n_regs: libc::c_int = 0;
+        unsafe {
+            let regs = minimap2_sys::mm_map(
+                idx,
+                seq.len() as libc::c_int,
+                seq.as_ptr() as *const libc::c_char,
+                &mut n_regs,
+                buf.0,
+                &mapopt,
+                std::ptr::null(),
+            );
+            part_regs.extend((0..n_regs as usize).map(|i| (idx, *regs.add(i))));
+            libc::free(regs as *mut libc::c_void);
+        }
+    }
+    if index.merge_parts && index.parts.len() > 1 {
+        let k = index.parts().next().map_or(0, |idx| idx.k);
+        merge_part_regs(&mut part_regs, &mapopt, k);
+    }
+    let mut mappings: Vec<Mapping> = part_regs
+        .into_iter()
+        .map(|(idx, reg)| unsafe {
+            let mapping = Mapping::from_reg(idx, &reg, seq.as_bytes(), cs, md);
+            libc::free(reg.p as *mut libc::c_void);
+            mapping
+        })
+        .collect();
+    filter.apply(&mut mappings);
+    Ok(mappings)
+}
+
+/// Merge the mappings of a read to every part of a multi-part index, each paired with its part, in place, as minimap2
+/// does with `--split-prefix` (`mm_split_merge`), so they are ranked as if the index were a single part.
+/// The mappings are sorted by score, and each is made secondary to the best primary mapping overlapping it on the
+/// read, or primary if there is none. Secondary mappings are then dropped as `pri_ratio` and `best_n` would for a
+/// single part, only the first primary mapping is kept as the SAM primary, the rest are supplementary, and mapqs
+/// are recalculated from the merged scores. minimap2 doesn't return the repetitive length of a read, so the mapq
+/// isn't lowered for repeats, as if the read had none. `k` is that of the index.
+fn merge_part_regs(
+    regs: &mut Vec<(&minimap2_sys::mm_idx_t, minimap2_sys::mm_reg1_t)>,
+    mapopt: &minimap2::MapOpt,
+    k: i32,
+) {
+    if regs.is_empty() {
+        return;
+    }
+    // Mappings to different parts may share a contig id, so the parts are compared too
+    let same_hit =
+        |(idx_a, a): &(&minimap2_sys::mm_idx_t, minimap2_sys::mm_reg1_t),
+         (idx_b, b): &(&minimap2_sys::mm_idx_t, minimap2_sys::mm_reg1_t)| {
+            std::ptr::eq(*idx_a, *idx_b) && a.rid == b.rid && a.rs == b.rs && a.re == b.re
+        };
+    regs.sort_by_key(|(_, reg)| std::cmp::Reverse(reg.score));
+
+    // `mm_set_parent`
+    let hard_mask_level = mapopt.flag & minimap2_sys::MM_F_HARD_MLEVEL as i64 != 0;
+    let sub_diff = mapopt.a * 2 + mapopt.b;
+    let mut primaries: Vec<usize> = vec![];
+    for i in 0..regs.len() {
+        regs[i].1.id = i as i32;
+        let (si, ei) = (regs[i].1.qs, regs[i].1.qe);
+        // The length of this mapping not covered by any primary mapping before it
+        let mut uncov_len = 0;
+        if !hard_mask_level {
+            let mut cov: Vec<(i32, i32)> = primaries
+                .iter()
+                .map(|&j| (regs[j].1.qs.max(si), regs[j].1.qe.min(ei)))
+                .filter(|(start, end)| start < end)
+                .collect();
+            cov.sort_unstable();
+            let mut x = si;
+            for (start, end) in cov {
+                if start > x {
+                    uncov_len += start - x;
+                }
+                x = x.max(end);
+            }
+            if ei > x {
+                uncov_len += ei - x;
+            }
+        }
+        let parent = primaries.iter().find_map(|&j| {
+            let (sj, ej) = (regs[j].1.qs, regs[j].1.qe);
+            if ej <= si || sj >= ei {
+                return None;
+            }
+            let min = (ej - sj).min(ei - si);
+            let max = (ej - sj).max(ei - si);
+            let ol = ej.min(ei) - sj.max(si);
+            (ol as f32 / min as f32 - uncov_len as f32 / max as f32 > mapopt.mask_level
+                && uncov_len <= mapopt.mask_len)
+                .then_some((j, ol == min))
+        });
+        let Some((j, contained)) = parent else {
+            primaries.push(i);
+            regs[i].1.parent = i as i32;
+            regs[i].1.n_sub = 0;
+            continue;
+        };
+        let identical = contained && same_hit(&regs[i], &regs[j]);
+        let (head, tail) = regs.split_at_mut(i);
+        let (rp, ri) = (&mut head[j].1, &mut tail[0].1);
+        ri.parent = rp.parent;
+        rp.subsc = rp.subsc.max(ri.score);
+        let mut cnt_sub = ri.cnt >= rp.cnt;
+        // Identical mappings after DP don't count towards the parent's second best score
+        if !rp.p.is_null() && !ri.p.is_null() && !identical {
+            let (pp, pi) = unsafe { (&mut *rp.p, &*ri.p) };
+            pp.dp_max2 = pp.dp_max2.max(pi.dp_max);
+            if pp.dp_max - pi.dp_max <= sub_diff {
+                cnt_sub = true;
+            }
+        }
+        if cnt_sub {
+            rp.n_sub += 1;
+        }
+    }
+
+    // `mm_select_sub`
+    if mapopt.pri_ratio > 0.0 {
+        let mut n_2nd = 0;
+        let keep: Vec<bool> = (0..regs.len())
+            .map(|i| {
+                let (r, p) = (&regs[i].1, &regs[regs[i].1.parent as usize].1);
+                if r.parent == r.id || r.inv() != 0 {
+                    true
+                } else if (r.score as f32 >= p.score as f32 * mapopt.pri_ratio
+                    || r.score + k * 2 >= p.score)
+                    && n_2nd < mapopt.best_n
+                {
+                    let identical = r.qs == p.qs
+                        && r.qe == p.qe
+                        && same_hit(&regs[i], &regs[r.parent as usize]);
+                    if !identical {
+                        n_2nd += 1;
+                    }
+                    !identical
+                } else {
+                    false
+                }
+            })
+            .collect();
+        // Renumber the mappings kept, as `mm_sync_regs` does
+        let mut new_ids = vec![0; regs.len()];
+        let mut n_kept = 0;
+        for (i, &kept) in keep.iter().enumerate() {
+            new_ids[i] = n_kept;
+            n_kept += kept as i32;
+        }
+        let mut i = 0;
+        regs.retain(|(_, reg)| {
+            let kept = keep[i];
+            i += 1;
+            if !kept {
+                unsafe { libc::free(reg.p as *mut libc::c_void) };
+            }
+            kept
+        });
+        for (_, reg) in regs.iter_mut() {
+            reg.id = new_ids[reg.id as usize];
+            reg.parent = new_ids[reg.parent as usize];
+        }
+    }
+
+    // `mm_set_sam_pri`
+    let mut n_pri = 0;
+    for (_, reg) in regs.iter_mut() {
+        if reg.id == reg.parent {
+            n_pri += 1;
+            reg.set_sam_pri((n_pri == 1) as u32);
+        } else {
+            reg.set_sam_pri(0);
+        }
+    }
+
+    // `mm_set_mapq`, with no repetitive length
+    let q_coef = 40.0_f32;
+    let match_sc = mapopt.a as f32;
+    let is_sr = mapopt.flag & minimap2_sys::MM_F_SR as i64 != 0;
+    for (_, r) in regs.iter_mut() {
+        if r.inv() != 0 || r.parent != r.id {
+            r.set_mapq(0);
+            continue;
+        }
+        let pen_s1 = if r.score > 100 {
+            1.0
+        } else {
+            0.01 * r.score as f32
+        };
+        let pen_cm = if r.cnt > 10 { 1.0 } else { 0.1 * r.cnt as f32 }.min(pen_s1);
+        let subsc = r.subsc.max(mapopt.min_chain_score) as f32;
+        let extra = unsafe { r.p.as_ref() };
+        let mut mapq = match extra {
+            Some(p) if p.dp_max2 > 0 && p.dp_max > 0 => {
+                let identity = r.mlen as f32 / r.blen as f32;
+                let x = p.dp_max2 as f32 * subsc / p.dp_max as f32 / r.score0 as f32;
+                let mapq = (identity
+                    * pen_cm
+                    * q_coef
+                    * (1.0 - x * x)
+                    * (p.dp_max as f32 / match_sc).ln()) as i32;
+                if is_sr {
+                    mapq
+                } else {
+                    // BWA-MEM like mapq, in case the long read heuristic fails
+                    let mapq_alt = (6.02 * identity * identity * (p.dp_max - p.dp_max2) as f32
+                        / match_sc
+                        + 0.499) as i32;
+                    mapq.min(mapq_alt)
+                }
+            }
+            Some(p) => {
+                let identity = r.mlen as f32 / r.blen as f32;
+                let x = subsc / r.score0 as f32;
+                (identity * pen_cm * q_coef * (1.0 - x) * (p.dp_max as f32 / match_sc).ln()) as i32
+            }
+            None => {
+                let x = subsc / r.score0 as f32;
+                (pen_cm * q_coef * (1.0 - x) * (r.score as f32).ln()) as i32
+            }
+        };
+        mapq -= (4.343 * ((r.n_sub + 1) as f32).ln() + 0.499) as i32;
+        let mut mapq = mapq.clamp(0, 60) as u32;
+        if mapq == 0 && extra.is_some_and(|p| p.dp_max > p.dp_max2) {
+            mapq = 1;
+        }
+        r.set_mapq(mapq);
+    }
+}
+
+/// Python iterable types that are accepted by the `Aligner.map_batch()` function
+#[derive(FromPyObject)]
+enum SupportedTypes<'py> {
+    /// PyList wrapper
+    List(&'py PyList),
+    /// PyTuple wrapper
+    Tuple(&'py PyTuple),
+    /// PyIter wrapper
+    Iter(&'py PyIterator),
+    /// PySequence wrapper
+    Sequence(&'py PySequence),
+}
+
+/// Struct for returning data to the python runtime as an iterabled.
+#[pyclass]
+#[allow(clippy::type_complexity)]
+pub struct AlignmentBatchResultIter {
+    /// Sender of results into this scope
+    tx: Sender<WorkQueue<(Vec<Mapping>, usize, Option<HashMap<String, Py<PyAny>>>)>>,
+    /// Receive the sent data
+    rx: Receiver<WorkQueue<(Vec<Mapping>, usize, Option<HashMap<String, Py<PyAny>>>)>>,
+    /// HashMap for caching sent data
+    data: FnvHashMap<usize, HashMap<String, Py<PyAny>>>,
+    /// Number of threads, which checks against the number offinished threads
+    _n_threads: usize,
+    /// Number of finished threads, used to know when to close the receiver. Is unlocked in the worker threads.
+    _n_finished_threads: Arc<Mutex<usize>>,
+    /// Whether the `Finished` sentinel has been received, so no more results will arrive
+    finished: bool,
+    /// The batch whose results this iterator returns, set by `map_batch`
+    batch: Option<Arc<Batch>>,
+    /// Set the `query_name` of each mapping from the `name` or `id` of its read
+    attach_query_name: bool,
+    /// Yield each read's dictionary alongside its mappings
+    yield_metadata: bool,
+    /// Yield the mappings of each read as PAF lines, in place of the mappings and dictionary
+    as_paf: bool,
+    /// Python callable recalculating the mapq of each mapping from `(s1, s2, block_len)`
+    mapq_fn: Option<PyObject>,
+    /// What to do with reads which fail to map
+    on_error: OnError,
+    /// Error hit while feeding the batch from another thread, raised once the results fed before it have been returned
+    error: Arc<Mutex<Option<PyErr>>>,
+    /// Reorders results to match the order of the reads, if set, see `map_batch(deterministic=True)`
+    in_order: Option<InOrder>,
+    /// If true (the default), dropping this iterator before it is exhausted lets the batch run to `Finished`,
+    /// mapping every remaining read. If false, the batch is cancelled on drop, so the collector thread exits straight away.
+    #[pyo3(get, set)]
+    flush_on_drop: bool,
+}
+
+impl Drop for AlignmentBatchResultIter {
+    /// Cancel the batch on drop unless `flush_on_drop` is set, so the collector thread stops waiting for `Finished`.
+    fn drop(&mut self) {
+        if !self.flush_on_drop {
+            if let Some(batch) = &self.batch {
+                batch.cancelled.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Results received ahead of their turn, held back so `AlignmentBatchResultIter` returns them in the order of the reads.
+#[derive(Debug, Default)]
+#[allow(clippy::type_complexity)]
+struct InOrder {
+    /// Position in the batch of the read whose result is returned next
+    next_id: usize,
+    /// Results of later reads, by their position in the batch
+    held: BTreeMap<usize, WorkQueue<(Vec<Mapping>, usize, Option<HashMap<String, Py<PyAny>>>)>>,
+    /// Whether the batch has finished, so no more results will arrive
+    finished: bool,
+}
+
+impl Default for AlignmentBatchResultIter {
+    /// Impl default for the `AlignmentBatchResultIter.`
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Iterator for the batch results from a multi threaded call to mapper
+#[pymethods]
+impl AlignmentBatchResultIter {
+    /// Initialise a new `AlignmentBatchResultIter`. Spawns the Send And Receive channels.
+    #[new]
+    pub fn new() -> Self {
+        let (tx, rx) = bounded(20000);
+        AlignmentBatchResultIter {
+            tx,
+            rx,
+            data: FnvHashMap::default(),
+            _n_threads: 0_usize,
+            _n_finished_threads: Arc::new(Mutex::new(0_usize)),
+            finished: false,
+            batch: None,
+            attach_query_name: false,
+            yield_metadata: true,
+            as_paf: false,
+            mapq_fn: None,
+            on_error: OnError::Raise,
+            error: Arc::new(Mutex::new(None)),
+            in_order: None,
+            flush_on_drop: true,
+        }
+    }
+
+    /// Set the number of threads on this mappy_rs instance
+    pub fn set_n_threads(&mut self, n_threads: usize) {
+        self._n_threads = n_threads;
+    }
+
+    /// Set how many results can wait in this iterator before the collector thread blocks, 20000 by default.
+    /// Replaces the results channel, so must be set before any results are sent.
+    pub fn set_result_bound(&mut self, result_bound: usize) -> PyResult<()> {
+        if result_bound == 0 {
+            return Err(PyValueError::new_err("`result_bound` must be at least 1"));
+        }
+        (self.tx, self.rx) = bounded(result_bound);
+        Ok(())
+    }
+
+    /// Returns the Iterable, in this case the struct itself.
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    /// Returns the next element in the Iterator, a `(mappings, dictionary)` tuple, or just the mappings if
+    /// `yield_metadata` is false, or a string of PAF lines if `as_paf` is true.
+    /// Returning `None` raises `StopIteration` once every result has been returned.
+    fn __next__(&mut self, py: Python) -> PyResult<Option<PyObject>> {
+        let Some((mappings, data)) = self.next_result(py)? else {
+            return Ok(None);
+        };
+        Ok(Some(if self.as_paf {
+            paf_lines(py, &mappings, &data)?.into_py(py)
+        } else if self.yield_metadata {
+            (mappings, data).into_py(py)
+        } else {
+            mappings.into_py(py)
+        }))
+    }
+
+    /// Exhaust the iterator, returning every remaining result, as yielded by `__next__`, as a list.
+    /// This is faster than iterating in python, and the GIL is released while waiting for each result.
+    fn collect(&mut self, py: Python) -> PyResult<Vec<PyObject>> {
+        let mut results = vec![];
+        while let Some(result) = self.__next__(py)? {
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// Exhaust the iterator, writing every remaining mapping to `path` as a tab-separated file, returning the number of
+    /// mappings written. The first row is a header naming the columns, the read's `id` then each field of `Mapping`,
+    /// followed by one row per mapping. Reads which did not map have no rows.
+    #[allow(clippy::wrong_self_convention)]
+    fn to_tsv(&mut self, py: Python, path: std::path::PathBuf) -> PyResult<usize> {
+        let io_err = |e: std::io::Error| PyIOError::new_err(e.to_string());
+        let mut writer = BufWriter::new(std::fs::File::create(&path).map_err(io_err)?);
+        writeln!(writer, "{}", Mapping::TSV_COLUMNS.join("\t")).map_err(io_err)?;
+        let mut n_rows = 0;
+        while let Some((mappings, data)) = self.next_result(py)? {
+            let read_id = match data.get("id") {
+                Some(id) => id.as_ref(py).str()?.to_string(),
+                None => String::new(),
+            };
+            for mapping in &mappings {
+                writeln!(writer, "{}", mapping.to_tsv_row(&read_id)).map_err(io_err)?;
+                n_rows += 1;
+            }
+        }
+        writer.flush().map_err(io_err)?;
+        Ok(n_rows)
+    }
+
+    /// Cancel the batch this iterator is returning results for.
+    /// Reads which have not been mapped yet are skipped by the worker threads, any results not yet returned are
+    /// discarded, and iteration stops.
+    fn cancel(&mut self) {
+        if let Some(batch) = &self.batch {
+            batch.cancelled.store(true, Ordering::SeqCst);
+        }
+        self.finished = true;
+        // Free space in the channel, so the collector thread can't stay blocked sending to it
+        while self.rx.try_recv().is_ok() {}
+        self.data.clear();
+    }
+
+    /// Returns the next result if one is ready, without blocking.
+    /// Returns `None` if no result is ready yet, or once every result has been returned.
+    #[allow(clippy::type_complexity)]
+    fn try_next(
+        &mut self,
+        py: Python,
+    ) -> PyResult<Option<(Vec<Mapping>, HashMap<String, Py<PyAny>>)>> {
+        if self.finished {
+            return Ok(None);
+        }
+        // Loop past any failed reads that are skipped
+        loop {
+            let received = self.receive(py, false);
+            if let (Some(batch), Ok(WorkQueue::Result(_) | WorkQueue::Failed(..))) =
+                (&self.batch, &received)
+            {
+                batch.release_inflight();
+            }
+            match received {
+                Ok(WorkQueue::Result((mappings, id_num, data))) => {
+                    return self.take_result(py, mappings, id_num, data).map(Some)
+                }
+                Ok(WorkQueue::Finished) => {
+                    self.finished = true;
+                    return match self.error.lock().unwrap().take() {
+                        Some(e) => Err(e),
+                        None => Ok(None),
+                    };
+                }
+                Ok(WorkQueue::Failed(id_num, reason, data)) => {
+                    if let Some(result) = self.take_failure(py, id_num, reason, data)? {
+                        return Ok(Some(result));
+                    }
+                }
+                Ok(_) => {
+                    return Err(PyRuntimeError::new_err(
+                        "Received wrong variant as a Result",
+                    ))
+                }
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(PyRuntimeError::new_err(
+                        "Receiver error - channel was closed",
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl AlignmentBatchResultIter {
+    /// Wait for the next result of the batch, returning the mappings of a read with its dictionary, or `None` once
+    /// every result has been returned.
+    #[allow(clippy::type_complexity)]
+    pub fn next_result(
+        &mut self,
+        py: Python,
+    ) -> PyResult<Option<(Vec<Mapping>, HashMap<String, Py<PyAny>>)>> {
+        Ok(self
+            .next_indexed(py)?
+            .map(|(_, mappings, data)| (mappings, data)))
+    }
+
+    /// As `next_result`, with the index of the read in the batch as well.
+    #[allow(clippy::type_complexity)]
+    fn next_indexed(
+        &mut self,
+        py: Python,
+    ) -> PyResult<Option<(usize, Vec<Mapping>, HashMap<String, Py<PyAny>>)>> {
+        if self.finished {
+            return Ok(None);
+        }
+        // Loop past any failed reads that are skipped
+        loop {
+            let received = self.receive(py, true);
+            if let (Some(batch), Ok(WorkQueue::Result(_) | WorkQueue::Failed(..))) =
+                (&self.batch, &received)
+            {
+                batch.release_inflight();
+            }
+            match received {
+                Ok(WorkQueue::Result((mappings, id_num, data))) => {
+                    let (mappings, data) = self.take_result(py, mappings, id_num, data)?;
+                    return Ok(Some((id_num, mappings, data)));
+                }
+                Ok(WorkQueue::Finished) => {
+                    self.finished = true;
+                    return match self.error.lock().unwrap().take() {
+                        Some(e) => Err(e),
+                        None => Ok(None),
+                    };
+                }
+                Ok(WorkQueue::Failed(id_num, reason, data)) => {
+                    if let Some((mappings, data)) = self.take_failure(py, id_num, reason, data)? {
+                        return Ok(Some((id_num, mappings, data)));
+                    }
+                }
+                Ok(_) => {
+                    return Err(PyRuntimeError::new_err(
+                        "Received wrong variant as a Result",
+                    ))
+                }
+                Err(_) => {
+                    return Err(PyRuntimeError::new_err(
+                        "Receiver error - channel was closed",
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Receive the next result, waiting for one if `block` is set, otherwise returning `TryRecvError::Empty` if there
+    /// is none ready. The GIL is released while waiting, a thread feeding this batch from a python iterator needs it.
+    ///
+    /// With `in_order` set, results of later reads are held back until the result of the next read arrives. Reads
+    /// can be missing from the batch, having been skipped as they were fed, so once the batch finishes any held back
+    /// results are returned in order regardless.
+    #[allow(clippy::type_complexity)]
+    fn receive(
+        &mut self,
+        py: Python,
+        block: bool,
+    ) -> Result<WorkQueue<(Vec<Mapping>, usize, Option<HashMap<String, Py<PyAny>>>)>, TryRecvError>
+    {
+        let rx = &self.rx;
+        let recv = || {
+            if block {
+                py.allow_threads(|| rx.recv())
+                    .map_err(|_| TryRecvError::Disconnected)
+            } else {
+                rx.try_recv()
+            }
+        };
+        let Some(in_order) = &mut self.in_order else {
+            return recv();
+        };
+        loop {
+            if let Some(result) = in_order.held.remove(&in_order.next_id) {
+                in_order.next_id += 1;
+                return Ok(result);
+            }
+            if in_order.finished {
+                return Ok(match in_order.held.pop_first() {
+                    Some((id_num, result)) => {
+                        in_order.next_id = id_num + 1;
+                        result
+                    }
+                    None => WorkQueue::Finished,
+                });
+            }
+            match recv()? {
+                WorkQueue::Finished => in_order.finished = true,
+                result @ (WorkQueue::Result((_, id_num, _)) | WorkQueue::Failed(id_num, ..)) => {
+                    in_order.held.insert(id_num, result);
+                }
+                other => return Ok(other),
+            }
+        }
+    }
+
+    /// Pair the mappings of the read `id_num` with its dictionary, ready to be returned,
+    /// setting each mapping's `query_name` if `attach_query_name` is set, and its mapq if there is a `mapq_fn`.
+    /// The dictionary is `carried` with the result if it travelled through the work queue, otherwise it is cached here.
+    #[allow(clippy::type_complexity)]
+    fn take_result(
+        &mut self,
+        py: Python,
+        mut mappings: Vec<Mapping>,
+        id_num: usize,
+        carried: Option<HashMap<String, Py<PyAny>>>,
+    ) -> PyResult<(Vec<Mapping>, HashMap<String, Py<PyAny>>)> {
+        let data = match carried {
+            Some(data) => data,
+            // Not kept if the dictionary isn't needed, see `keeps_metadata`
+            None => self.data.remove(&id_num).unwrap_or_default(),
+        };
+        if self.attach_query_name {
+            if let Some(name) = data.get("name").or_else(|| data.get("id")) {
+                let name = name.as_ref(py).str()?.to_string();
+                for mapping in &mut mappings {
+                    mapping.query_name = Some(name.clone());
+                }
+            }
+        }
+        if let Some(mapq_fn) = &self.mapq_fn {
+            for mapping in &mut mappings {
+                mapping.mapq = mapq_fn
+                    .call1(py, (mapping.s1, mapping.s2, mapping.block_len))?
+                    .extract(py)?;
+            }
+        }
+        Ok((mappings, data))
+    }
+
+    /// Whether each read's dictionary is needed once it is mapped, to be yielded or to name its mappings
+    fn keeps_metadata(&self) -> bool {
+        self.yield_metadata || self.attach_query_name || self.as_paf
+    }
+
+    /// Handle the read `id_num`, which failed to map, according to `on_error`. Returns the read unmapped
+    /// with its dictionary for `OnError::Warn`, or `None` if it is skipped.
+    #[allow(clippy::type_complexity)]
+    fn take_failure(
+        &mut self,
+        py: Python,
+        id_num: usize,
+        reason: String,
+        carried: Option<HashMap<String, Py<PyAny>>>,
+    ) -> PyResult<Option<(Vec<Mapping>, HashMap<String, Py<PyAny>>)>> {
+        let data = carried.or_else(|| self.data.remove(&id_num));
+        let message = format!("Failed to map read {id_num}: {reason}");
+        match self.on_error {
+            OnError::Raise => Err(MappyError::MapFailed(message).into()),
+            OnError::Warn => {
+                PyErr::warn(py, py.get_type::<PyRuntimeWarning>(), &message, 1)?;
+                let mut data = data.unwrap_or_default();
+                data.entry(String::from("id"))
+                    .or_insert_with(|| id_num.into_py(py));
+                Ok(Some((vec![], data)))
+            }
+            OnError::Skip => Ok(None),
+        }
+    }
+}
+
+/// Format the `mappings` of a read as PAF lines, joined by newlines, without a trailing newline.
+/// The query name is the `name`, or failing that the `id`, from the read's dictionary `data`,
+/// and the query length is the length of its `seq`. A read with no mappings gives an empty string.
+fn paf_lines(
+    py: Python,
+    mappings: &[Mapping],
+    data: &HashMap<String, Py<PyAny>>,
+) -> PyResult<String> {
+    let query_name = match data.get("name").or_else(|| data.get("id")) {
+        Some(name) => name.as_ref(py).str()?.to_string(),
+        None => String::from("*"),
+    };
+    let query_len = match data.get("seq") {
+        Some(seq) => seq.as_ref(py).len()? as i32,
+        None => 0,
+    };
+    let lines = mappings
+        .iter()
+        .map(|mapping| mapping.to_paf(&query_name, query_len))
+        .collect::<PyResult<Vec<String>>>()?;
+    Ok(lines.join("\n"))
+}
+
+/// Describe the payload of a caught panic, which is usually a `&str` or `String` message.
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("worker thread panicked")
+    }
+}
+
+/// Async iterator over the results of `Aligner.map_batch_async`, for use with `async for`.
+#[pyclass]
+pub struct AsyncAlignmentBatchResultIter {
+    /// Iterator over the results of the batch, each `__anext__` takes the next result from it
+    results: Py<AlignmentBatchResultIter>,
+}
+
+/// Async iterator for the batch results from a call to `map_batch_async`
+#[pymethods]
+impl AsyncAlignmentBatchResultIter {
+    /// Returns the async iterable, in this case the struct itself.
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    /// Returns an awaitable resolving to the next result.
+    /// The result is waited for in the event loop's default executor, without the GIL, so the event loop isn't blocked.
+    fn __anext__(&self, py: Python) -> PyResult<Option<PyObject>> {
+        let results = self.results.clone_ref(py);
+        let next = pyo3::types::PyCFunction::new_closure(
+            py,
+            None,
+            None,
+            move |args: &PyTuple, _kwargs: Option<&pyo3::types::PyDict>| -> PyResult<PyObject> {
+                let py = args.py();
+                // A second `__anext__` run before this one resolves raises, as the iterator is already borrowed
+                match results.try_borrow_mut(py)?.__next__(py)? {
+                    Some(result) => Ok(result),
+                    None => Err(PyStopAsyncIteration::new_err(())),
+                }
+            },
+        )?;
+        let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+        Ok(Some(
+            event_loop
+                .call_method1("run_in_executor", (py.None(), next))?
+                .into(),
+        ))
+    }
+}
+
+/// Return the version string of the minimap2 library linked into mappy-rs, e.g. `2.26-r1175`.
+#[pyfunction]
+pub fn minimap2_version() -> String {
+    std::ffi::CStr::from_bytes_with_nul(minimap2_sys::MM_VERSION)
+        .unwrap()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Return the version of mappy-rs.
+#[pyfunction]
+pub fn mappy_rs_version() -> String {
+    String::from(env!("CARGO_PKG_VERSION"))
+}
+
+/// Load the index `index_path` with the default options, map the single read `seq` against it, and return the mappings.
+/// The index is loaded afresh on every call, so this is only a convenience for one off mapping, construct an
+/// `Aligner` to map more than one read.
+#[pyfunction]
+pub fn map_one(index_path: std::path::PathBuf, seq: String) -> PyResult<Vec<Mapping>> {
+    let aligner = Aligner::new(AlignerOptions {
+        fn_idx_in: Some(index_path),
+        ..Default::default()
+    })?;
+    aligner.map(seq, None, false, false, None, None, false, None, None, None)
+}
+
+/// Initialise the python module and add the Aligner and Mapping classes, and the exceptions of `MappyError`.
+#[pymodule]
+fn mappy_rs(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Aligner>()?;
+    m.add_class::<Mapping>()?;
+    m.add_class::<Strand>()?;
+    m.add_class::<PafHit>()?;
+    m.add_function(wrap_pyfunction!(minimap2_version, m)?)?;
+    m.add_function(wrap_pyfunction!(mappy_rs_version, m)?)?;
+    m.add_function(wrap_pyfunction!(map_one, m)?)?;
+    m.add("NoIndex", py.get_type::<NoIndex>())?;
+    m.add("MapFailed", py.get_type::<MapFailed>())?;
+    m.add("ThreadingNotEnabled", py.get_type::<ThreadingNotEnabled>())?;
+    m.add("QueueFull", py.get_type::<QueueFull>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn get_resource_dir() -> PathBuf {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/test");
+        path
+    }
+
+    fn get_test_file(file: &str) -> PathBuf {
+        let mut path = get_resource_dir();
+        path.push(file);
+        path
+    }
+
+    /// Read a single contig out of the test fasta file
+    fn get_test_contig(name: &str) -> String {
+        let fasta = std::fs::read_to_string(get_test_file("test.fa")).unwrap();
+        let mut seq = String::new();
+        let mut in_contig = false;
+        for line in fasta.lines() {
+            if let Some(header) = line.strip_prefix('>') {
+                in_contig = header.split_whitespace().next() == Some(name);
+                continue;
+            }
+            if in_contig {
+                seq.push_str(line.trim());
+            }
+        }
+        seq
+    }
+
+    fn get_test_aligner() -> Result<Aligner, PyErr> {
+        let path = get_test_file("test.mmi");
+        Aligner::new(AlignerOptions {
+            fn_idx_in: Some(path),
+            n_threads: 4,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn load_index() {
+        let al = get_test_aligner().unwrap();
+        assert!(al.aligner.has_index());
+    }
+
+    #[test]
+    fn load_index_gzipped_fasta() {
+        let al = Aligner::new(AlignerOptions {
+            fn_idx_in: Some(get_test_file("test.fa.gz")),
+            n_threads: 4,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(al.aligner.has_index());
+        let mappings = al
+            .map(
+                get_test_contig("Bacillus_subtilis"),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.len() == 1);
+        assert!(mappings[0].target_name == "Bacillus_subtilis");
+    }
+
+    #[test]
+    fn load_index_missing_file() {
+        let al = Aligner::new(AlignerOptions {
+            fn_idx_in: Some(get_test_file("does_not_exist.fa")),
+            n_threads: 4,
+            ..Default::default()
+        });
+        assert!(al.is_err());
+    }
+
+    #[test]
+    fn load_index_multi_part() {
+        let al = Aligner::new(AlignerOptions {
+            fn_idx_in: Some(get_test_file("test.fa")),
+            n_threads: 1,
+            index_batch_size: Some(500),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(al.n_parts().unwrap() > 1);
+        assert!(al.n_seq().unwrap() == 4);
+        let mut seq_names = al.seq_names().unwrap();
+        for (id, name) in seq_names.iter().enumerate() {
+            assert!(al.seq_name_to_id(name).unwrap() == Some(id as u32));
+            let contig = get_test_contig(name);
+            let len = contig.len() as i32;
+            assert!(al._get_index_seq(name.clone(), 0, len).unwrap() == contig);
+            let mappings = al
+                .map(
+                    contig, None, false, false, None, None, false, None, None, None,
+                )
+                .unwrap();
+            assert!(mappings
+                .iter()
+                .any(|m| m.is_primary && &m.target_name == name));
+        }
+        seq_names.sort();
+        assert!(
+            seq_names
+                == vec![
+                    "Bacillus_subtilis",
+                    "Enterococcus_faecalis",
+                    "Escherichia_coli_1",
+                    "Escherichia_coli_2",
+                ]
+        );
+        assert!(get_test_aligner().unwrap().n_parts().unwrap() == 1);
+    }
+
+    #[test]
+    fn split_prefix() {
+        // Each part holds at most two of the contigs, so these two are in different parts
+        let chimera = get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1");
+        for split_prefix in [None, Some(String::from("mappy_rs_split"))] {
+            let al = Aligner::new(AlignerOptions {
+                fn_idx_in: Some(get_test_file("test.fa")),
+                n_threads: 1,
+                index_batch_size: Some(500),
+                split_prefix: split_prefix.clone(),
+                ..Default::default()
+            })
+            .unwrap();
+            assert!(al.n_parts().unwrap() > 1);
+            let mappings = al
+                .map(
+                    chimera.clone(),
+                    None,
+                    false,
+                    false,
+                    None,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            let mut targets: Vec<&str> = mappings
+                .iter()
+                .filter(|m| m.is_primary)
+                .map(|m| m.target_name.as_str())
+                .collect();
+            targets.sort();
+            assert!(targets == ["Bacillus_subtilis", "Escherichia_coli_1"]);
+            // Each part has its own primary mapping, unless they are merged, when only one can be
+            let n_supplementary = mappings.iter().filter(|m| m.is_supplementary).count();
+            assert!(n_supplementary == split_prefix.is_some() as usize);
+            assert!(mappings.iter().all(|m| m.mapq <= 60));
+        }
+    }
+
+    #[test]
+    fn test_property_k() {
+        let al = get_test_aligner().unwrap();
+        assert!(al.k().unwrap() == 15);
+    }
+
+    #[test]
+    fn test_property_w() {
+        let al = get_test_aligner().unwrap();
+        assert!(al.w().unwrap() == 10);
+    }
+
+    #[test]
+    fn test_property_n_seq() {
+        let al = get_test_aligner().unwrap();
+        assert!(al.n_seq().unwrap() == 4);
+    }
+
+    #[test]
+    fn test_index_stats() {
+        let al = get_test_aligner().unwrap();
+        Python::with_gil(|py| {
+            let stats = al.index_stats(py).unwrap();
+            let get = |key: &str| stats.get_item(key).unwrap().extract::<i64>().unwrap();
+            assert!(get("n_seq") == 4);
+            assert!(get("k") == 15);
+            assert!(get("w") == 10);
+            assert!(
+                get("total_len")
+                    == al
+                        .seq_lens()
+                        .unwrap()
+                        .iter()
+                        .map(|&l| l as i64)
+                        .sum::<i64>()
+            );
+            assert!(get("mid_occ") > 0);
+        });
+    }
+
+    #[test]
+    fn iter_seqs_in_index_order() {
+        let al = get_test_aligner().unwrap();
+        let seqs = al.iter_seqs().unwrap();
+        assert!(seqs.len() == 4);
+        for (id, (name, len)) in seqs.into_iter().enumerate() {
+            assert!(al.id_to_seq_name(id as u32).unwrap() == Some(name.clone()));
+            assert!(len as usize == get_test_contig(&name).len());
+        }
+    }
+
+    #[test]
+    fn seq_name_id_round_trip() {
+        let al = get_test_aligner().unwrap();
+        for name in al.seq_names().unwrap() {
+            let id = al.seq_name_to_id(&name).unwrap().unwrap();
+            assert!(al.id_to_seq_name(id).unwrap() == Some(name));
+        }
+        assert!(al.seq_name_to_id("not_a_contig").unwrap().is_none());
+        assert!(al.id_to_seq_name(al.n_seq().unwrap()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_property_seq_names() {
+        let al = get_test_aligner().unwrap();
+        let expected = vec![
+            "Bacillus_subtilis",
+            "Enterococcus_faecalis",
+            "Escherichia_coli_1",
+            "Escherichia_coli_2",
+        ];
+        let mut seq_names = al.seq_names().unwrap();
+        seq_names.sort();
+        assert!(seq_names == expected);
+    }
+
+    #[test]
+    fn test_get_seq() {
+        let al = get_test_aligner().unwrap();
+        let contig = "Bacillus_subtilis";
+        let expected = "AGAGTGAAGCCAATATTCCGATAACGATTGCTTTCATGATATCCCTCATTCTGGCATTATTTTTTTATACTATACTATTC\
+                        GATATCGCACAGATCAATGGAGTCGTGAGAAAATAAACATGTTTTGCGAACCGCTATGTGTGGAAGACAAAAAATGGAGG\
+                        TGAAATTGATGGAAGCAAAGACACAGGCGTACTTTTTTCAGGATGATGGCAGGATTCCGAATCACCCTGATTTTCCGCTC\
+                        GTTGTGTATCAAAACGCACTCAAGGACACCGGTCAGGCAGAGCGGATCGTCAACCGGCATGGCTGGTCAAACAGCTGGTC\
+                        GGGGAGTGTTTTTCCATACCATCATTATCACAGCAATACGCATGAAGTCCTGATTGCAGTTCGGGGAGAGGCTGTGATTC";
+        let seq = al
+            .seq(String::from(contig), 0, 2147483647, false)
+            .unwrap()
+            .unwrap();
+        assert!(seq == *expected);
+        // An end past the end of the contig is clamped to the contig length
+        let seq = al
+            .seq(String::from(contig), 100, 1000, false)
+            .unwrap()
+            .unwrap();
+        assert!(seq.len() == 300);
+        assert!(seq == expected[100..]);
+        assert!(al.seq(String::from(contig), 0, 100, true).is_err());
+        // Negative coordinates count back from the end of the contig, as python slicing does
+        let seq = al
+            .seq(String::from(contig), -10, -1, false)
+            .unwrap()
+            .unwrap();
+        assert!(seq == expected[390..399]);
+        let seq = al
+            .seq(String::from(contig), -10, 2147483647, false)
+            .unwrap()
+            .unwrap();
+        assert!(seq == expected[390..]);
+        let seq = al
+            .seq(String::from(contig), -1000, 10, false)
+            .unwrap()
+            .unwrap();
+        assert!(seq == expected[..10]);
+        assert!(al
+            .seq(String::from(contig), -1, -10, false)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn map_one() {
+        let al = get_test_aligner().unwrap();
+        let mappings = al.map(
+            String::from("AGAGCAGGTAGGATCGTTGAAAAAAGAGTACTCAGGATTCCATTCAACTTTTACTGATTTGAAGCGTACTGTTTATGGCC\
+                          AAGAATATTTACGTCTTTACAACCAATACGCAAAAAAAGGTTCATTGAGTTTGGTTGTGATTTGATGAAAATTACTGAGA\
+                          ATAACAGGATTATTAAGCTGATTGATGAACTAAATCAGCTTAATAAATATTCTTTGCAGATAGGAATATTTGGGGAAAAT\
+                          GATTCTTTTATGGCGATGTTGGCCCAAGTTCATGAATTTGGGGTGACTATTCGTCCCAAAGGTCGTTTTCTTGTTATACC\
+                          ACTTATGAAAAAGTATAGAGGTAAAAGTCCACGTCAATTTGATTTGTTTTTTATGCAAACTAAAGAAAATCACAAGTTTT"),
+            None, true, false, None, None, false, None,
+            None,
+            None,).unwrap();
+        assert!(mappings.len() == 1);
+        assert!(mappings[0].get_target_start().unwrap() == 0);
+        assert!(mappings[0].get_target_end().unwrap() == 400);
+    }
+
+    #[test]
+    fn map_trans_strand() {
+        let al = get_test_aligner().unwrap();
+        let seq = get_test_contig("Escherichia_coli_2");
+        let mappings = al
+            .map(
+                seq.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.iter().all(|m| m.trans_strand.is_none()));
+
+        let al = Aligner::new(AlignerOptions {
+            fn_idx_in: Some(get_test_file("test.fa")),
+            preset: Some("splice".to_string()),
+            n_threads: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        // Cut a GT-AG intron out of the contig, so the read aligns spliced on the forward transcript strand
+        let spliced = format!("{}{}", &seq[..151], &seq[261..]);
+        let mappings = al
+            .map(
+                spliced, None, false, false, None, None, false, None, None, None,
+            )
+            .unwrap();
+        assert!(mappings.len() == 1);
+        assert!(mappings[0].cigar.iter().any(|(_, op)| *op == 3));
+        assert!(mappings[0].trans_strand == Some(Strand::Forward));
+    }
+
+    #[test]
+    fn map_min_query_len() {
+        let al = get_test_aligner().unwrap();
+        let mappings = al
+            .map(
+                "ACGTA".to_string(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                Some(50),
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.is_empty());
+        // A read which would map is reported unmapped, so minimap2 is never called
+        let seq = get_test_contig("Bacillus_subtilis");
+        let mappings = al
+            .map(
+                seq.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                Some(500),
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.is_empty());
+        let mappings = al
+            .map(
+                seq,
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                Some(50),
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.len() == 1);
+    }
+
+    #[test]
+    fn paf_writer() {
+        let al = get_test_aligner().unwrap();
+        let chimera = get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1");
+        let mappings = al
+            .map(
+                chimera.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.len() > 1);
+        let mut writer = PafWriter::new(vec![]);
+        let mut expected = vec![];
+        for m in &mappings {
+            writer
+                .write_mapping("chimera", chimera.len() as i32, m)
+                .unwrap();
+            expected.extend(format!("chimera\t{}\t{m}\n", chimera.len()).into_bytes());
+        }
+        assert!(writer.into_inner() == expected);
+
+        let mut m = mappings[0].clone();
+        m.cigar = vec![];
+        let mut writer = PafWriter::new(vec![]);
+        writer.write_mapping("read", 10, &m).unwrap();
+        let line = String::from_utf8(writer.into_inner()).unwrap();
+        assert!(line.ends_with("\ttp:A:P\n"));
+    }
+
+    #[test]
+    fn paf_round_trip() {
+        let al = get_test_aligner().unwrap();
+        let chimera = get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1");
+        let mappings = al
+            .map(
+                chimera.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        for m in mappings {
+            let line = m.to_paf("chimera", chimera.len() as i32).unwrap();
+            let (query_name, query_len, parsed) = Mapping::from_paf(&line).unwrap();
+            assert!(query_name == "chimera");
+            assert!(query_len == chimera.len() as i32);
+            assert!(parsed.to_string() == m.to_string());
+            assert!(parsed.cigar == m.cigar);
+        }
+        let line =
+            "read\t100\t0\t100\t-\tcontig\t1000\t10\t110\t90\t100\t60\tNM:i:10\tcs:Z::90\tde:f:0.1";
+        let (_, _, parsed) = Mapping::from_paf(line).unwrap();
+        assert!(parsed.strand == Strand::Reverse);
+        assert!(parsed.NM == 10 && parsed.cs.as_deref() == Some(":90"));
+        assert!(parsed.is_primary && parsed.cigar.is_empty() && parsed.MD.is_none());
+        assert!(Mapping::from_paf("read\t100\t0").is_err());
+        assert!(Mapping::from_paf(&line.replace("\t60\t", "\tsixty\t")).is_err());
+        assert!(Mapping::from_paf(&format!("{line}\tcg:Z:10Q")).is_err());
+    }
+
+    #[test]
+    fn query_spanning_and_clips() {
+        let al = get_test_aligner().unwrap();
+        let contig = get_test_contig("Bacillus_subtilis");
+        let chimera = contig.clone() + &get_test_contig("Escherichia_coli_1");
+        let query_len = chimera.len() as i32;
+        for read in [chimera.clone(), reverse_complement(&chimera)] {
+            let mappings = al
+                .map(
+                    read, None, false, false, None, None, false, None, None, None,
+                )
+                .unwrap();
+            let by_target: HashMap<&str, &Mapping> = mappings
+                .iter()
+                .map(|m| (m.target_name.as_str(), m))
+                .collect();
+            // Each half of the chimera is clipped on the side of the other half, whichever strand it is read from.
+            // The junction can be aligned a few bases either way
+            let bacillus = by_target["Bacillus_subtilis"];
+            assert!(!bacillus.is_query_spanning(query_len));
+            assert!(bacillus.left_clip(query_len) == 0);
+            assert!((390..=410).contains(&bacillus.right_clip(query_len)));
+            let e_coli = by_target["Escherichia_coli_1"];
+            assert!(!e_coli.is_query_spanning(query_len));
+            assert!((390..=410).contains(&e_coli.left_clip(query_len)));
+            assert!(e_coli.right_clip(query_len) == 0);
+            for m in [bacillus, e_coli] {
+                let aligned = m.query_end - m.query_start;
+                assert!(m.left_clip(query_len) + aligned + m.right_clip(query_len) == query_len);
+            }
+        }
+        let mappings = al
+            .map(
+                contig, None, false, false, None, None, false, None, None, None,
+            )
+            .unwrap();
+        assert!(mappings[0].is_query_spanning(400));
+        assert!(mappings[0].left_clip(400) == 0 && mappings[0].right_clip(400) == 0);
+    }
+
+    #[test]
+    fn chimera_is_supplementary() {
+        let al = get_test_aligner().unwrap();
+        let chimera = get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1");
+        let mappings = al
+            .map(
+                chimera.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let primary: Vec<&Mapping> = mappings.iter().filter(|m| m.is_primary).collect();
+        assert!(primary.len() == 2);
+        // One part of the chimera is the representative mapping, the other is supplementary
+        assert!(primary.iter().filter(|m| m.is_supplementary).count() == 1);
+        assert!(mappings.iter().all(|m| m.is_primary || !m.is_supplementary));
+        for m in primary {
+            let flag: u32 = m
+                .to_sam("chimera", &chimera, None, false)
+                .unwrap()
+                .split('\t')
+                .nth(1)
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((flag & 0x800 != 0) == m.is_supplementary);
+        }
+        let single = al
+            .map(
+                get_test_contig("Bacillus_subtilis"),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(!single[0].is_supplementary);
+    }
+
+    #[test]
+    fn normalize_cigar() {
+        let mut m = Mapping {
+            cigar: vec![
+                (3, 0),
+                (2, 0),
+                (1, 1),
+                (1, 1),
+                (4, 0),
+                (2, 2),
+                (6, 0),
+                (1, 0),
+            ],
+            ..no_op_mappings().remove(0)
+        };
+        let normalized = vec![(5, 0), (2, 1), (4, 0), (2, 2), (7, 0)];
+        assert!(m.normalize_cigar() == normalized);
+        assert!(m.cigar.len() == 8);
+        m.normalize_cigar_in_place();
+        assert!(m.cigar == normalized);
+        assert!(m.get_cigar_str().unwrap() == "5M2I4M2D7M");
+        let empty = Mapping {
+            cigar: vec![],
+            ..no_op_mappings().remove(0)
+        };
+        assert!(empty.normalize_cigar().is_empty());
+    }
+
+    #[test]
+    fn cigar_str() {
+        let mut m = Mapping {
+            cigar: vec![(3, 0), (2, 0), (1, 1), (4, 0)],
+            ..no_op_mappings().remove(0)
+        };
+        let cigar_str = m.get_cigar_str().unwrap();
+        assert!(cigar_str == "3M2M1I4M");
+        for _ in 0..10 {
+            assert!(m.get_cigar_str().unwrap() == cigar_str);
+        }
+        // Always built from the current CIGAR
+        m.normalize_cigar_in_place();
+        assert!(m.get_cigar_str().unwrap() == "5M1I4M");
+        m.cigar = vec![(10, 0), (12345, 2)];
+        assert!(m.get_cigar_str().unwrap() == "10M12345D");
+        m.cigar = vec![(10, 15)];
+        assert!(m.get_cigar_str().is_err());
+    }
+
+    #[test]
+    fn map_s1_s2() {
+        let al = get_test_aligner().unwrap();
+        let mappings = al
+            .map(
+                get_test_contig("Bacillus_subtilis"),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.len() == 1);
+        assert!(mappings[0].s1 > 0);
+        assert!(mappings[0].s1 >= mappings[0].s2);
+    }
+
+    #[test]
+    fn mapping_trimmed_fastq() {
+        let al = get_test_aligner().unwrap();
+        let contig = get_test_contig("Bacillus_subtilis");
+        for seq in [
+            format!("{}{contig}{}", "N".repeat(20), "N".repeat(30)),
+            reverse_complement(&format!("{}{contig}", "N".repeat(20))),
+        ] {
+            let qual = "I".repeat(seq.len());
+            let mappings = al
+                .map(
+                    seq.clone(),
+                    None,
+                    false,
+                    false,
+                    None,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert!(mappings.len() == 1);
+            let m = &mappings[0];
+            let record = m.trimmed_fastq("read_1", &seq, &qual).unwrap();
+            let lines: Vec<&str> = record.lines().collect();
+            assert!(lines.len() == 4);
+            assert!(lines[0] == "@read_1");
+            assert!(lines[1].len() == (m.query_end - m.query_start) as usize);
+            assert!(lines[3].len() == lines[1].len());
+            // Trimmed back to the reference strand, so it matches the contig
+            assert!(contig.contains(lines[1]));
+        }
+        let m = &al
+            .map(
+                contig.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap()[0];
+        assert!(m.trimmed_fastq("read_1", "ACGT", "IIII").is_err());
+        assert!(m.trimmed_fastq("read_1", &contig, "IIII").is_err());
+    }
+
+    #[test]
+    fn mapping_target_subseq() {
+        let al = get_test_aligner().unwrap();
+        let contig = get_test_contig("Bacillus_subtilis");
+        let read = &contig[50..350];
+        let mappings = al
+            .map(
+                read.to_string(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(!mappings.is_empty());
+        for m in mappings {
+            let subseq = m.target_subseq(&al).unwrap();
+            assert!(subseq.len() == (m.target_end - m.target_start) as usize);
+            assert!(contig.contains(&subseq));
+        }
+        let mut m = al.no_op_map().remove(0);
+        assert!(m.target_subseq(&al).is_none());
+        m.target_name = String::from("Bacillus_subtilis");
+        m.target_end = 110;
+        assert!(m.target_subseq(&al).unwrap() == contig[10..110]);
+    }
+
+    #[test]
+    fn map_md_and_cs_only_when_requested() {
+        let al = get_test_aligner().unwrap();
+        let contig = get_test_contig("Bacillus_subtilis");
+        for (cs, md) in [(false, false), (true, false), (false, true), (true, true)] {
+            let mappings = al
+                .map(
+                    contig.clone(),
+                    None,
+                    cs,
+                    md,
+                    None,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert!(mappings.len() == 1);
+            assert!(mappings[0].cs.is_some() == cs);
+            assert!(mappings[0].MD.is_some() == md);
+        }
+    }
+
+    #[test]
+    fn map_query_len() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        let chimera = get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1");
+        let mappings = al
+            .map(
+                chimera.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.len() > 1);
+        assert!(mappings
+            .iter()
+            .all(|m| m.get_query_len().unwrap() == Some(chimera.len() as i32)));
+        assert!(al.no_op_map()[0].query_len.is_none());
+        let line = mappings[0].to_paf("chimera", 800).unwrap();
+        assert!(Mapping::from_paf(&line).unwrap().2.query_len == Some(800));
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let seqs = PyList::empty(py);
+            for len in [100, 200, 400] {
+                let read = PyDict::new(py);
+                read.set_item("seq", &get_test_contig("Escherichia_coli_2")[..len])
+                    .unwrap();
+                seqs.append(read).unwrap();
+            }
+            let mut res = al.map_batch_with(seqs, MapBatchOptions::default()).unwrap();
+            while let Some((mappings, data)) = res.next_result(py).unwrap() {
+                let len = data["seq"].as_ref(py).len().unwrap() as i32;
+                assert!(!mappings.is_empty());
+                assert!(mappings.iter().all(|m| m.query_len == Some(len)));
+            }
+        });
+    }
+
+    #[test]
+    fn map_frag_id() {
+        let al = Aligner::new(AlignerOptions {
+            fn_idx_in: Some(get_test_file("test.mmi")),
+            preset: Some(String::from("sr")),
+            n_threads: 1,
+            max_frag_len: Some(800),
+            ..Default::default()
+        })
+        .unwrap();
+        let mappings = al
+            .map(
+                get_test_contig("Bacillus_subtilis"),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(!mappings.is_empty());
+        assert!(mappings.iter().all(|m| m.frag_id == Some(0)));
+        assert!(al.no_op_map()[0].frag_id.is_none());
+    }
+
+    #[test]
+    fn map_timed_matches_map() {
+        let al = get_test_aligner().unwrap();
+        let contig = get_test_contig("Enterococcus_faecalis");
+        let (mappings, elapsed_ms) = al.map_timed(contig.clone(), true, false).unwrap();
+        assert!(elapsed_ms >= 0.0);
+        assert!(
+            mappings
+                == al
+                    .map(contig, None, true, false, None, None, false, None, None, None,)
+                    .unwrap()
+        );
+        assert!(al.map_timed(String::new(), false, false).is_err());
+    }
+
+    #[test]
+    fn mapping_sort_keys() {
+        let al = get_test_aligner().unwrap();
+        let chimera = get_test_contig("Escherichia_coli_1") + &get_test_contig("Bacillus_subtilis");
+        let mut mappings = al
+            .map(
+                chimera, None, false, false, None, None, false, None, None, None,
+            )
+            .unwrap();
+        assert!(mappings.len() > 1);
+        mappings.sort_by_key(|m| m.sort_key_position());
+        assert!(mappings[0].target_name == "Bacillus_subtilis");
+        assert!(mappings
+            .windows(2)
+            .all(|w| w[0].sort_key_position() <= w[1].sort_key_position()));
+        mappings.sort_by_key(|m| m.sort_key_score());
+        assert!(mappings.windows(2).all(|w| w[0].mapq <= w[1].mapq));
+    }
+
+    #[test]
+    fn map_max_hits() {
+        let al = get_test_aligner().unwrap();
+        // A chimeric read spanning two contigs, which returns more than one mapping
+        let chimera = get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1");
+        let mappings = al
+            .map(
+                chimera.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.len() > 1);
+        let mappings = al
+            .map(
+                chimera,
+                None,
+                false,
+                false,
+                Some(1),
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.len() == 1);
+        assert!(mappings[0].is_primary);
+    }
+
+    /// Check a version string starts with at least `major.minor`, with numeric parts
+    fn is_semver_ish(version: &str) -> bool {
+        let parts: Vec<&str> = version
+            .split(['-', '+'])
+            .next()
+            .unwrap()
+            .split('.')
+            .collect();
+        parts.len() >= 2
+            && parts
+                .iter()
+                .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+    }
+
+    #[test]
+    fn test_versions() {
+        let mm2_version = minimap2_version();
+        assert!(!mm2_version.is_empty());
+        assert!(is_semver_ish(&mm2_version));
+        let version = mappy_rs_version();
+        assert!(!version.is_empty());
+        assert!(is_semver_ish(&version));
+    }
+
+    #[test]
+    fn map_target_names() {
+        let al = get_test_aligner().unwrap();
+        let chimera = get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1");
+        let mappings = al
+            .map(
+                chimera,
+                None,
+                false,
+                false,
+                None,
+                Some(vec![String::from("Escherichia_coli_1")]),
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(!mappings.is_empty());
+        assert!(mappings
+            .iter()
+            .all(|m| m.target_name == "Escherichia_coli_1"));
+    }
+
+    #[test]
+    fn map_paf() {
+        let al = get_test_aligner().unwrap();
+        let seq = get_test_contig("Enterococcus_faecalis");
+        let hits = al
+            .map_paf(seq.clone(), String::from("read_1"), None)
+            .unwrap();
+        assert!(hits.len() == 1);
+        let hit = &hits[0];
+        assert!(hit.query_name == "read_1");
+        assert!(hit.query_length == 400);
+        assert!(hit.strand() == "+");
+        assert!(hit.target_name() == "Enterococcus_faecalis");
+        assert!(hit.target_length() == 400);
+        let m = &hit.mapping;
+        assert!(hit.query_start() == m.query_start && hit.query_end() == m.query_end);
+        assert!(hit.target_start() == m.target_start && hit.target_end() == m.target_end);
+        assert!(hit.residue_matches() == m.match_len);
+        assert!(hit.alignment_block_length() == m.block_len);
+        assert!(hit.mapping_quality() == m.mapq);
+        let cigar_str = format!("cg:Z:{}", m.get_cigar_str().unwrap());
+        assert!(hit.tags().unwrap() == vec!["tp:A:P", cigar_str.as_str()]);
+        let line = hit.__str__().unwrap();
+        assert!(line.starts_with("read_1\t400\t"));
+        let (query_name, query_len, parsed) = Mapping::from_paf(&line).unwrap();
+        assert!(query_name == "read_1" && query_len == 400);
+        assert!(parsed.to_string() == m.to_string());
+        // The query length can be given, e.g. for a trimmed read
+        let hits = al.map_paf(seq, String::from("read_1"), Some(500)).unwrap();
+        assert!(hits[0].query_length == 500);
+    }
+
+    #[test]
+    fn map_best() {
+        let al = get_test_aligner().unwrap();
+        let seq = get_test_contig("Enterococcus_faecalis");
+        let mappings = al
+            .map(
+                seq.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(mappings.len() == 1);
+        let best = al.map_best(seq, false, false).unwrap().unwrap();
+        assert!(best == mappings[0]);
+        assert!(al
+            .map_best(String::from("ACGT"), false, false)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn map_force_cigar() {
+        let al = get_test_aligner().unwrap();
+        let seq = get_test_contig("Enterococcus_faecalis");
+        let mappings = al
+            .map(seq, None, false, false, None, None, true, None, None, None)
+            .unwrap();
+        assert!(mappings.len() == 1);
+        assert!(!mappings[0].cigar.is_empty());
+    }
+
+    #[test]
+    fn enable_threading_handles() {
+        let mut al = get_test_aligner().unwrap();
+        assert!(al.enable_threading(0).is_err());
+        assert!(al._handles.lock().unwrap().is_empty());
+        al.enable_threading(2).unwrap();
+        assert!(al._handles.lock().unwrap().len() == 2);
+        assert!(al.thread_utilization() == vec![false, false]);
+        al.shutdown();
+        al.join().unwrap();
+        assert!(al._handles.lock().unwrap().is_empty());
+        assert!(al.thread_utilization().is_empty());
+        assert!(al.n_threads == 0);
+    }
+
+    #[test]
+    fn min_dp_score_and_min_dp_max() {
+        let path = get_test_file("test.mmi");
+        for (min_dp_score, min_dp_max) in [(Some(77), None), (None, Some(77))] {
+            let al = Aligner::new(AlignerOptions {
+                fn_idx_in: Some(path.clone()),
+                min_dp_score,
+                n_threads: 1,
+                min_dp_max,
+                ..Default::default()
+            })
+            .unwrap();
+            assert!(al.aligner.mapopt.min_dp_max == 77);
+        }
+    }
+
+    #[test]
+    fn wait_for_batch() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let seqs = PyList::empty(py);
+            for name in ["Bacillus_subtilis", "Escherichia_coli_1"] {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(name)).unwrap();
+                seqs.append(read).unwrap();
+            }
+            let mut res = AlignmentBatchResultIter::new();
+            res.set_n_threads(al.n_threads);
+            al._map_batch(
+                &mut res,
+                seqs,
+                Some(BackOff::default()),
+                Batch::new(MapFilter::default()),
+                false,
+            )
+            .unwrap();
+            al.wait(py);
+            assert!(al.work_queue.is_empty());
+            assert!(al.in_flight.load(Ordering::SeqCst) == 0);
+            for batch in al.batches.lock().unwrap().iter() {
+                if let Some(batch) = batch.upgrade() {
+                    assert!(batch.results_queue.is_empty());
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_align_to_sam() {
+        let al = get_test_aligner().unwrap();
+        let output = std::env::temp_dir().join("mappy_rs_test_align_to_sam.sam");
+        al.align_to_sam(get_test_file("test.fa"), output.clone(), false)
+            .unwrap();
+        let sam = std::fs::read_to_string(&output).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        let mut lines = sam.lines();
+        assert!(lines.next().unwrap().starts_with("@HD"));
+        assert!(lines.next().unwrap().starts_with("@SQ"));
+        let headers = sam.lines().filter(|l| l.starts_with('@')).count();
+        // @HD, one @SQ per contig and @PG
+        assert!(headers == 6);
+        let records: Vec<&str> = sam.lines().filter(|l| !l.starts_with('@')).collect();
+        // Each contig in the test fasta maps back to itself
+        assert!(records.len() == 4);
+        for record in records {
+            let fields: Vec<&str> = record.split('\t').collect();
+            assert!(fields[0] == fields[2]);
+        }
+    }
+
+    #[test]
+    fn map_batch_to_tsv() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        let output = std::env::temp_dir().join("mappy_rs_test_map_batch_to_tsv.tsv");
+        Python::with_gil(|py| {
+            let names = al.seq_names().unwrap();
+            let seqs = PyList::empty(py);
+            for i in 0..10 {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(&names[i % 4]))
+                    .unwrap();
+                seqs.append(read).unwrap();
+            }
+            // A read which doesn't map has no rows
+            let read = PyDict::new(py);
+            read.set_item("seq", "ACGT").unwrap();
+            seqs.append(read).unwrap();
+            let mut res = al.map_batch_with(seqs, MapBatchOptions::default()).unwrap();
+            assert!(res.to_tsv(py, output.clone()).unwrap() == 10);
+            assert!(res.next_result(py).unwrap().is_none());
+        });
+        let tsv = std::fs::read_to_string(&output).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        let mut lines = tsv.lines();
+        assert!(lines.next().unwrap() == Mapping::TSV_COLUMNS.join("\t"));
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split('\t').collect()).collect();
+        assert!(rows.len() == 10);
+        let names = al.seq_names().unwrap();
+        for row in rows {
+            assert!(row.len() == Mapping::TSV_COLUMNS.len());
+            let id = row[0].parse::<usize>().unwrap();
+            assert!(row[5] == names[id % 4]);
+            assert!(row[12] == "true");
+        }
+    }
+
+    #[test]
+    fn sam_hard_clip_secondary() {
+        let al = get_test_aligner().unwrap();
+        let chimera = get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1");
+        let primary = al
+            .map(
+                chimera.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap()
+            .remove(0);
+        let secondary = Mapping {
+            is_primary: false,
+            ..primary.clone()
+        };
+        let cigar = |m: &Mapping, hard_clip_secondary| {
+            let record = m
+                .to_sam("chimera", &chimera, None, hard_clip_secondary)
+                .unwrap();
+            record.split('\t').nth(5).unwrap().to_string()
+        };
+        // Half of the chimera is clipped from every mapping
+        assert!(cigar(&primary, true).contains('S') && !cigar(&primary, true).contains('H'));
+        assert!(cigar(&secondary, true).contains('H') && !cigar(&secondary, true).contains('S'));
+        assert!(cigar(&secondary, false) == cigar(&primary, true));
+    }
+
+    #[test]
+    fn splice_flank_and_no_end_flt() {
+        let splice_flank = minimap2_sys::MM_F_SPLICE_FLANK as i64;
+        let no_end_flt = minimap2_sys::MM_F_NO_END_FLT as i64;
+        for (set, expected) in [(None, true), (Some(true), true), (Some(false), false)] {
+            let al = Aligner::new(AlignerOptions {
+                fn_idx_in: Some(get_test_file("test.fa")),
+                preset: Some("splice".to_string()),
+                n_threads: 1,
+                splice_flank: set,
+                no_end_flt: Some(true),
+                ..Default::default()
+            })
+            .unwrap();
+            // The splice preset sets `MM_F_SPLICE_FLANK`
+            assert!((al.flag().unwrap() & splice_flank != 0) == expected);
+            assert!(al.flag().unwrap() & no_end_flt != 0);
+        }
+    }
+
+    #[test]
+    fn mappy_compat() {
+        let cigar = minimap2_sys::MM_F_CIGAR as i64;
+        for mappy_compat in [true, false] {
+            let al = Aligner::new(AlignerOptions {
+                fn_idx_in: Some(get_test_file("test.mmi")),
+                n_threads: 1,
+                mappy_compat,
+                ..Default::default()
+            })
+            .unwrap();
+            assert!((al.flag().unwrap() & cigar != 0) == mappy_compat);
+            let mappings = al
+                .map(
+                    get_test_contig("Bacillus_subtilis"),
+                    None,
+                    false,
+                    false,
+                    None,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert!(mappings.len() == 1);
+            assert!(mappings[0].cigar.is_empty() != mappy_compat);
+        }
+    }
+
+    #[test]
+    fn max_gap() {
+        let contig = get_test_contig("Bacillus_subtilis");
+        // 300 pseudo random bases inserted in the middle of the read
+        let mut state = 17_u32;
+        let insert: String = (0..300)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                b"ACGT"[(state >> 16) as usize % 4] as char
+            })
+            .collect();
+        let read = format!("{}{insert}{}", &contig[..200], &contig[200..]);
+        for (max_gap, spanned) in [(None, true), (Some(100), false)] {
+            let al = Aligner::new(AlignerOptions {
+                fn_idx_in: Some(get_test_file("test.mmi")),
+                n_threads: 1,
+                mappy_compat: false,
+                max_chain_iter: Some(100),
+                max_gap,
+                ..Default::default()
+            })
+            .unwrap();
+            assert!(al.aligner.mapopt.max_chain_iter == 100);
+            let mappings = al
+                .map(
+                    read.clone(),
+                    None,
+                    false,
+                    false,
+                    None,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert!(!mappings.is_empty());
+            // Only a chain across the insertion covers both halves of the read
+            let spans_insert = mappings
+                .iter()
+                .any(|m| m.query_start < 200 && m.query_end > 500);
+            assert!(spans_insert == spanned);
+        }
+    }
+
+    #[test]
+    fn reduce_rebuilds_aligner() {
+        pyo3::prepare_freethreaded_python();
+        let al = get_test_aligner().unwrap();
+        Python::with_gil(|py| {
+            let (callable, args) = al.__reduce__(py).unwrap();
+            let restored = callable.call1(py, args.as_ref(py)).unwrap();
+            let restored: PyRef<Aligner> = restored.extract(py).unwrap();
+            assert!(restored.seq_names().unwrap() == al.seq_names().unwrap());
+            assert!(restored.aligner.mapopt.flag == al.aligner.mapopt.flag);
+            let mappings = restored
+                .map(
+                    get_test_contig("Escherichia_coli_1"),
+                    None,
+                    false,
+                    false,
+                    None,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert!(mappings.len() == 1);
+            assert!(mappings[0].target_name == "Escherichia_coli_1");
+        });
+    }
+
+    #[test]
+    fn test_mapopt_getters() {
+        let al = Aligner::new(AlignerOptions {
+            fn_idx_in: Some(get_test_file("test.mmi")),
+            preset: Some(String::from("map-ont")),
+            n_threads: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(al.k().unwrap() == 15);
+        assert!(al.w().unwrap() == 10);
+        assert!(al.preset().unwrap().as_deref() == Some("map-ont"));
+        assert!(al.flag().unwrap() & minimap2_sys::MM_F_CIGAR as i64 != 0);
+        assert!(al.min_chain_score().unwrap() == al.aligner.mapopt.min_chain_score);
+        assert!(al.min_cnt().unwrap() == al.aligner.mapopt.min_cnt);
+        assert!(al.bw().unwrap() == al.aligner.mapopt.bw);
+        assert!(al.best_n().unwrap() == 5);
+        assert!(al.max_frag_len().unwrap() == al.aligner.mapopt.max_frag_len);
+    }
+
+    #[test]
+    fn test_clone_with_shared_index() {
+        let al = get_test_aligner().unwrap();
+        let al_2 = al.clone_with_shared_index();
+        assert!(Arc::strong_count(&al.index) == 2);
+        let seq = get_test_contig("Enterococcus_faecalis");
+        for aligner in [&al, &al_2] {
+            let mappings = aligner
+                .map(
+                    seq.clone(),
+                    None,
+                    false,
+                    false,
+                    None,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert!(mappings.len() == 1);
+        }
+        // Dropping the original must leave the index usable by the copy
+        drop(al);
+        assert!(Arc::strong_count(&al_2.index) == 1);
+        let mappings = al_2
+            .map(seq, None, false, false, None, None, false, None, None, None)
+            .unwrap();
+        assert!(mappings.len() == 1);
+    }
+
+    #[test]
+    fn push_work_back_off_attempts() {
+        let work_queue = ArrayQueue::new(1);
+        let batch = Arc::new(Batch::new(MapFilter::default()));
+        let back_off = BackOff {
+            max_attempts: 2,
+            initial_sleep: Duration::from_millis(1),
+            max_sleep: None,
+        };
+        assert!(push_work(
+            &work_queue,
+            &batch,
+            0,
+            String::from("ACGT"),
+            None,
+            Some(back_off)
+        )
+        .is_ok());
+        let err = push_work(
+            &work_queue,
+            &batch,
+            1,
+            String::from("ACGT"),
+            None,
+            Some(back_off),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("after 2 attempts"));
+        assert!(push_work(&work_queue, &batch, 1, String::from("ACGT"), None, None).is_err());
+        assert!(work_queue.len() == 1);
+    }
+
+    #[test]
+    fn mappy_error_exceptions() {
+        pyo3::prepare_freethreaded_python();
+        let al = get_test_aligner().unwrap();
+        let work_queue = ArrayQueue::new(1);
+        let batch = Arc::new(Batch::new(MapFilter::default()));
+        push_work(&work_queue, &batch, 0, String::from("ACGT"), None, None).unwrap();
+        let queue_full = PyErr::from(
+            push_work(&work_queue, &batch, 1, String::from("ACGT"), None, None).unwrap_err(),
+        );
+        Python::with_gil(|py| {
+            assert!(queue_full.is_instance_of::<QueueFull>(py));
+            assert!(queue_full.is_instance_of::<PyRuntimeError>(py));
+            let not_enabled = al.check_threading().unwrap_err();
+            assert!(not_enabled.is_instance_of::<ThreadingNotEnabled>(py));
+            assert!(!not_enabled.is_instance_of::<QueueFull>(py));
+            let no_index = PyErr::from(MappyError::NoIndex);
+            assert!(no_index.is_instance_of::<NoIndex>(py));
+            assert!(no_index.value(py).to_string() == "Index hasn't loaded");
+        });
+    }
+
+    #[test]
+    fn index_getters_without_index() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        assert!(al.k().unwrap() == 15 && al.w().unwrap() == 10 && al.n_seq().unwrap() == 4);
+        al.aligner.idx = None;
+        al.index = Arc::new(IndexHandle {
+            parts: vec![],
+            merge_parts: false,
+        });
+        Python::with_gil(|py| {
+            for err in [
+                al.k().unwrap_err(),
+                al.w().unwrap_err(),
+                al.n_seq().unwrap_err(),
+            ] {
+                assert!(err.is_instance_of::<NoIndex>(py));
+                assert!(err.is_instance_of::<PyRuntimeError>(py));
+            }
+        });
+    }
+
+    #[test]
+    fn contig_hit_counts() {
+        let al = get_test_aligner().unwrap();
+        for (name, n) in [("Bacillus_subtilis", 3), ("Escherichia_coli_2", 2)] {
+            for _ in 0..n {
+                al.map(
+                    get_test_contig(name),
+                    None,
+                    false,
+                    false,
+                    None,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            }
+        }
+        al.map(
+            String::from("ACGT"),
+            None,
+            false,
+            false,
+            None,
+            None,
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let counts = al.contig_hit_counts();
+        assert!(counts.len() == 2);
+        assert!(counts["Bacillus_subtilis"] == 3);
+        assert!(counts["Escherichia_coli_2"] == 2);
+        al.reset_contig_hit_counts();
+        assert!(al.contig_hit_counts().is_empty());
+        // Only the per contig counts are reset
+        assert!(al.total_reads().unwrap() == 6);
+    }
+
+    #[test]
+    fn map_batch_threaded() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        Python::with_gil(|py| {
+            let seqs = PyList::empty(py);
+            let read = PyDict::new(py);
+            read.set_item("seq", get_test_contig("Bacillus_subtilis"))
+                .unwrap();
+            seqs.append(read).unwrap();
+            let not_enabled = al.check_threading().unwrap_err().value(py).to_string();
+            assert!(not_enabled.contains("Please call `.enable_threading(n_threads)`"));
+            assert!(not_enabled.contains("not carried over to copies"));
+            let mut res = al
+                .map_batch_threaded(seqs, NonZeroUsize::new(2).unwrap())
+                .unwrap();
+            assert!(al.n_threads == 2);
+            let (mappings, _) = res.next_result(py).unwrap().unwrap();
+            assert!(mappings[0].target_name == "Bacillus_subtilis");
+            assert!(res.next_result(py).unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn strand_int() {
+        pyo3::prepare_freethreaded_python();
+        assert!(Strand::Forward as i32 == 1 && Strand::Reverse as i32 == -1);
+        Python::with_gil(|py| {
+            let forward = Py::new(py, Strand::Forward).unwrap().into_ref(py);
+            let reverse = Py::new(py, Strand::Reverse).unwrap().into_ref(py);
+            assert!(forward.eq(1).unwrap() && reverse.eq(-1).unwrap());
+            assert!(!reverse.eq(1).unwrap() && !forward.eq(reverse).unwrap());
+            let int = py.get_type::<pyo3::types::PyLong>();
+            assert!(int.call1((reverse,)).unwrap().extract::<i32>().unwrap() == -1);
+        });
+    }
+
+    #[test]
+    fn test_next_stops() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let seqs = PyList::empty(py);
+            let read = PyDict::new(py);
+            read.set_item("seq", get_test_contig("Bacillus_subtilis"))
+                .unwrap();
+            seqs.append(read).unwrap();
+            let mut res = AlignmentBatchResultIter::new();
+            res.set_n_threads(al.n_threads);
+            al._map_batch(
+                &mut res,
+                seqs,
+                Some(BackOff::default()),
+                Batch::new(MapFilter::default()),
+                false,
+            )
+            .unwrap();
+            let (mappings, data) = res.next_result(py).unwrap().unwrap();
+            assert!(mappings.len() == 1);
+            // No id was given, so the read's index in the batch is used
+            assert!(data["id"].extract::<usize>(py).unwrap() == 0);
+            assert!(res.next_result(py).unwrap().is_none());
+            assert!(res.next_result(py).unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn map_batch_rayon() {
+        pyo3::prepare_freethreaded_python();
+        // Two aligners sharing the global pool
+        let mut aligners = [get_test_aligner().unwrap(), get_test_aligner().unwrap()];
+        assert!(aligners[0].enable_threading_rayon(Some(0)).is_err());
+        for al in aligners.iter_mut() {
+            al.enable_threading_rayon(None).unwrap();
+        }
+        Python::with_gil(|py| {
+            for al in &aligners {
+                let seqs = PyList::empty(py);
+                for _ in 0..25 {
+                    for name in al.seq_names().unwrap() {
+                        let read = PyDict::new(py);
+                        read.set_item("seq", get_test_contig(&name)).unwrap();
+                        read.set_item("name", name).unwrap();
+                        seqs.append(read).unwrap();
+                    }
+                }
+                // The second batch is queued behind the first, and both finish
+                let mut batches =
+                    [(); 2].map(|_| al.map_batch_with(seqs, MapBatchOptions::default()).unwrap());
+                for res in batches.iter_mut() {
+                    let mut n = 0;
+                    while let Some((mappings, data)) = res.next_result(py).unwrap() {
+                        let name = data["name"].extract::<String>(py).unwrap();
+                        assert!(mappings.len() == 1);
+                        assert!(mappings[0].target_name == name);
+                        n += 1;
+                    }
+                    assert!(n == 100);
+                }
+            }
+        });
+        for al in aligners.iter_mut() {
+            al.shutdown();
+            al.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn map_batch_deterministic() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(4).unwrap();
+        Python::with_gil(|py| {
+            let names = al.seq_names().unwrap();
+            let run = || {
+                let seqs = PyList::empty(py);
+                for i in 0..400 {
+                    let read = PyDict::new(py);
+                    read.set_item("seq", get_test_contig(&names[i % 4]))
+                        .unwrap();
+                    seqs.append(read).unwrap();
+                }
+                let mut res = al
+                    .map_batch_with(
+                        seqs,
+                        MapBatchOptions {
+                            deterministic: true,
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+                let mut output = vec![];
+                while let Some((mappings, data)) = res.next_result(py).unwrap() {
+                    let id = data["id"].extract::<usize>(py).unwrap();
+                    let mappings: Vec<String> = mappings.iter().map(|m| m.to_string()).collect();
+                    output.push(format!("{id}\t{}", mappings.join(";")));
                 }
+                output
+            };
+            let first = run();
+            assert!(first.len() == 400);
+            // Results come back in the order of the reads
+            for (i, line) in first.iter().enumerate() {
+                assert!(line.starts_with(&format!("{i}\t")));
+            }
+            assert!(run() == first);
+        });
+    }
+
+    #[test]
+    fn map_batch_chunk_size() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(1).unwrap();
+        Python::with_gil(|py| {
+            let names = al.seq_names().unwrap();
+            let seqs = PyList::empty(py);
+            for i in 0..100 {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(&names[i % 4]))
+                    .unwrap();
+                seqs.append(read).unwrap();
+            }
+            let mut res = al
+                .map_batch_with(
+                    seqs,
+                    MapBatchOptions {
+                        chunk_size: 10,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            // Ten chunks and a `Done` at most, rather than a work item for every read
+            assert!(al.work_queue_len().unwrap() <= 11);
+            let mut ids = vec![];
+            while let Some((mappings, data)) = res.next_result(py).unwrap() {
+                let id = data["id"].extract::<usize>(py).unwrap();
+                assert!(mappings[0].target_name == names[id % 4]);
+                ids.push(id);
             }
+            ids.sort();
+            assert!(ids == (0..100).collect::<Vec<usize>>());
+        });
+    }
+
+    #[test]
+    fn map_reuses_buffer() {
+        let mut al = get_test_aligner().unwrap();
+        let seq = get_test_contig("Bacillus_subtilis");
+        let buf = al.buf.0;
+        let start = std::time::Instant::now();
+        for _ in 0..10000 {
+            let mappings = al
+                .map(
+                    seq.clone(),
+                    None,
+                    false,
+                    false,
+                    None,
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert!(mappings.len() == 1);
         }
-        // Now we add n_thread dones, one for each thread. When the threads see this they know to close as there is no more data
-        for _ in 0..self.n_threads {
-            work_queue.push(WorkQueue::Done).unwrap();
+        let reused = start.elapsed();
+        // No new buffer is allocated by any of the calls
+        assert!(al.buf.0 == buf);
+        let start = std::time::Instant::now();
+        for _ in 0..10000 {
+            let mappings = map_seq(
+                &al.aligner,
+                &al.index,
+                &ThreadBuffer::new(),
+                &seq,
+                false,
+                false,
+                None,
+                &MapFilter::default(),
+            )
+            .unwrap();
+            assert!(mappings.len() == 1);
         }
+        let allocated = start.elapsed();
+        println!(
+            "10000 maps: {reused:?} reusing the buffer, {allocated:?} allocating a buffer each"
+        );
+        al.reset_buffer();
+        assert!(al.buf.0 != buf);
+        assert!(
+            al.map(seq, None, false, false, None, None, false, None, None, None,)
+                .unwrap()
+                .len()
+                == 1
+        );
+    }
 
-        Ok(())
+    #[test]
+    fn aligner_rejects_bad_minimizer_params() {
+        pyo3::prepare_freethreaded_python();
+        for (k, w) in [
+            (Some(0), None),
+            (Some(29), None),
+            (None, Some(0)),
+            (None, Some(256)),
+        ] {
+            let err = Aligner::new(AlignerOptions {
+                fn_idx_in: Some(get_test_file("test.fa")),
+                k,
+                w,
+                ..Default::default()
+            })
+            .err()
+            .unwrap();
+            Python::with_gil(|py| {
+                assert!(err.is_instance_of::<PyValueError>(py));
+                assert!(err.value(py).to_string().contains("as for minimap2"));
+            });
+        }
     }
-}
 
-/// Python iterable types that are accepted by the `Aligner.map_batch()` function
-#[derive(FromPyObject)]
-enum SupportedTypes<'py> {
-    /// PyList wrapper
-    List(&'py PyList),
-    /// PyTuple wrapper
-    Tuple(&'py PyTuple),
-    /// PyIter wrapper
-    Iter(&'py PyIterator),
-    /// PySequence wrapper
-    Sequence(&'py PySequence),
-}
+    #[test]
+    fn reload_index() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        let contig = get_test_contig("Bacillus_subtilis");
+        let map = |al: &Aligner| {
+            al.map(
+                contig.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+            )
+            .unwrap()
+        };
+        assert!(map(&al)[0].target_name == "Bacillus_subtilis");
+        let reference = std::env::temp_dir().join("mappy_rs_test_reload_index.fa");
+        std::fs::write(&reference, format!(">reloaded\n{contig}\n")).unwrap();
+        let result = Python::with_gil(|py| al.reload_index(py, reference.clone()));
+        std::fs::remove_file(&reference).unwrap();
+        result.unwrap();
+        assert!(al.seq_names().unwrap() == vec!["reloaded"]);
+        assert!(map(&al)[0].target_name == "reloaded");
+        // The worker threads are restarted against the new index
+        assert!(al.n_threads == 2);
+        Python::with_gil(|py| {
+            let seqs = PyList::empty(py);
+            for _ in 0..10 {
+                let read = PyDict::new(py);
+                read.set_item("seq", &contig).unwrap();
+                seqs.append(read).unwrap();
+            }
+            let mut res = al.map_batch_with(seqs, MapBatchOptions::default()).unwrap();
+            let mut n_results = 0;
+            while let Some((mappings, _)) = res.next_result(py).unwrap() {
+                assert!(mappings[0].target_name == "reloaded");
+                n_results += 1;
+            }
+            assert!(n_results == 10);
+            assert!(al.reload_index(py, get_test_file("missing.mmi")).is_err());
+            // Reloading part way through a batch fails rather than waiting on its iterator
+            let mut res = al
+                .map_batch_with(
+                    seqs,
+                    MapBatchOptions {
+                        max_inflight: Some(1),
+                        ..MapBatchOptions::default()
+                    },
+                )
+                .unwrap();
+            assert!(res.next_result(py).unwrap().is_some());
+            let err = al.reload_index(py, reference.clone()).unwrap_err();
+            assert!(err.to_string().contains("still being mapped"));
+            while res.next_result(py).unwrap().is_some() {}
+        });
+        // A failed reload leaves the index as it was
+        assert!(map(&al)[0].target_name == "reloaded");
+    }
 
-/// Struct for returning data to the python runtime as an iterabled.
-#[pyclass]
-pub struct AlignmentBatchResultIter {
-    /// Sender of results into this scope
-    tx: Sender<WorkQueue<(Vec<Mapping>, usize)>>,
-    /// Receive the sent data
-    rx: Receiver<WorkQueue<(Vec<Mapping>, usize)>>,
-    /// HashMap for caching sent data
-    data: FnvHashMap<usize, HashMap<String, Py<PyAny>>>,
-    /// Number of threads, which checks against the number offinished threads
-    _n_threads: usize,
-    /// Number of finished threads, used to know when to close the receiver. Is unlocked in the worker threads.
-    _n_finished_threads: Arc<Mutex<usize>>,
-}
+    #[test]
+    fn mapq_clamp() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        let seq = get_test_contig("Bacillus_subtilis");
+        let map = |mapq_min, mapq_max| {
+            al.map(
+                seq.clone(),
+                None,
+                false,
+                false,
+                None,
+                None,
+                false,
+                None,
+                mapq_min,
+                mapq_max,
+            )
+        };
+        let mapq = map(None, None).unwrap()[0].mapq;
+        assert!(mapq > 10);
+        assert!(map(None, Some(10)).unwrap()[0].mapq == 10);
+        assert!(map(Some(mapq + 10), None).unwrap()[0].mapq == mapq + 10);
+        assert!(map(Some(10), Some(mapq + 10)).unwrap()[0].mapq == mapq);
+        assert!(map(Some(10), Some(5)).is_err());
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let names = al.seq_names().unwrap();
+            let seqs = PyList::empty(py);
+            for i in 0..20 {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(&names[i % 4]))
+                    .unwrap();
+                seqs.append(read).unwrap();
+            }
+            // A chimera has a mapping with a lower mapq too
+            let read = PyDict::new(py);
+            read.set_item(
+                "seq",
+                get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1"),
+            )
+            .unwrap();
+            seqs.append(read).unwrap();
+            let mut res = al
+                .map_batch_with(
+                    seqs,
+                    MapBatchOptions {
+                        mapq_max: Some(60),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let mut n_mappings = 0;
+            while let Some((mappings, _)) = res.next_result(py).unwrap() {
+                assert!(mappings.iter().all(|m| m.mapq <= 60));
+                n_mappings += mappings.len();
+            }
+            assert!(n_mappings >= 21);
+        });
+    }
 
-impl Default for AlignmentBatchResultIter {
-    /// Impl default for the `AlignmentBatchResultIter.`
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn map_batch_result_bound() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let names = al.seq_names().unwrap();
+            let seqs = PyList::empty(py);
+            for i in 0..200 {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(&names[i % 4]))
+                    .unwrap();
+                seqs.append(read).unwrap();
+            }
+            let mut res = al
+                .map_batch_with(
+                    seqs,
+                    MapBatchOptions {
+                        result_bound: 2,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            // Nothing is consumed yet, so the workers wait once both queues are full
+            py.allow_threads(|| std::thread::sleep(Duration::from_millis(500)));
+            let batch = res.batch.as_ref().unwrap();
+            assert!(batch.results_queue.capacity() == 2);
+            assert!(batch.results_queue.len() <= 2);
+            assert!(res.rx.len() <= 2);
+            let mut n = 0;
+            while let Some((mappings, data)) = res.next_result(py).unwrap() {
+                let id_num = data["id"].extract::<usize>(py).unwrap();
+                assert!(mappings[0].target_name == names[id_num % 4]);
+                n += 1;
+            }
+            assert!(n == 200);
+        });
     }
-}
 
-/// Iterator for the batch results from a multi threaded call to mapper
-#[pymethods]
-impl AlignmentBatchResultIter {
-    /// Initialise a new `AlignmentBatchResultIter`. Spawns the Send And Receive channels.
-    #[new]
-    pub fn new() -> Self {
-        let (tx, rx) = bounded(20000);
-        AlignmentBatchResultIter {
-            tx,
-            rx,
-            data: FnvHashMap::default(),
-            _n_threads: 0_usize,
-            _n_finished_threads: Arc::new(Mutex::new(0_usize)),
+    #[test]
+    fn map_batch_max_queue_bytes() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(1).unwrap();
+        // Stop the worker thread, so nothing is taken off the queue, while leaving threading enabled
+        *al.stop.lock().unwrap() = true;
+        for handle in al._handles.lock().unwrap().drain(..) {
+            handle.join().unwrap();
         }
+        Python::with_gil(|py| {
+            let names = al.seq_names().unwrap();
+            let seqs = PyList::empty(py);
+            for i in 0..10 {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(&names[i % 4]))
+                    .unwrap();
+                seqs.append(read).unwrap();
+            }
+            let mut res = al
+                .map_batch_with(
+                    seqs,
+                    MapBatchOptions {
+                        max_queue_bytes: Some(1000),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            py.allow_threads(|| std::thread::sleep(Duration::from_millis(200)));
+            // Two 400 base reads fit in the budget, the feeder blocks on the third
+            let batch = Arc::clone(res.batch.as_ref().unwrap());
+            assert!(al.work_queue_len().unwrap() == 2);
+            assert!(batch.queued_bytes.load(Ordering::SeqCst) == 800);
+            res.cancel();
+        });
+        // With a worker thread mapping, every read is fed and returned within the budget
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(1).unwrap();
+        Python::with_gil(|py| {
+            let names = al.seq_names().unwrap();
+            let seqs = PyList::empty(py);
+            for i in 0..100 {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(&names[i % 4]))
+                    .unwrap();
+                seqs.append(read).unwrap();
+            }
+            let mut res = al
+                .map_batch_with(
+                    seqs,
+                    MapBatchOptions {
+                        max_queue_bytes: Some(1000),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let batch = Arc::clone(res.batch.as_ref().unwrap());
+            let mut n_results = 0;
+            while let Some((mappings, data)) = res.next_result(py).unwrap() {
+                assert!(batch.queued_bytes.load(Ordering::SeqCst) <= 1000);
+                let id = data["id"].extract::<usize>(py).unwrap();
+                assert!(mappings[0].target_name == names[id % 4]);
+                n_results += 1;
+            }
+            assert!(n_results == 100);
+            assert!(batch.queued_bytes.load(Ordering::SeqCst) == 0);
+        });
     }
 
-    /// Set the number of threads on this mappy_rs instance
-    pub fn set_n_threads(&mut self, n_threads: usize) {
-        self._n_threads = n_threads;
+    #[test]
+    fn queue_full_releases_queue_bytes() {
+        let work_queue = ArrayQueue::new(1);
+        let mut batch = Batch::new(MapFilter::default());
+        batch.max_queue_bytes = Some(100);
+        let batch = Arc::new(batch);
+        push_work(&work_queue, &batch, 0, String::from("ACGT"), None, None).unwrap();
+        assert!(batch.queued_bytes.load(Ordering::SeqCst) == 4);
+        // The queue is full, so neither read is added, with or without a backoff
+        let back_off = BackOff {
+            max_attempts: 1,
+            initial_sleep: Duration::from_millis(1),
+            max_sleep: None,
+        };
+        for back_off in [None, Some(back_off)] {
+            let pushed = push_work(&work_queue, &batch, 1, String::from("ACG"), None, back_off);
+            assert!(matches!(pushed, Err(MappyError::QueueFull(_))));
+            assert!(batch.queued_bytes.load(Ordering::SeqCst) == 4);
+        }
     }
 
-    /// Returns the Iterable, in this case the struct itself.
-    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
-        slf
+    #[test]
+    fn map_batch_empty() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let start = std::time::Instant::now();
+            let mut res = al
+                .map_batch_with(PyList::empty(py), MapBatchOptions::default())
+                .unwrap();
+            assert!(res.batch.is_none());
+            assert!(al.work_queue_len().unwrap() == 0);
+            assert!(res.next_result(py).unwrap().is_none());
+            assert!(start.elapsed() < Duration::from_millis(100));
+            assert!(al
+                .map_batch_collect(py, PyTuple::empty(py), None)
+                .unwrap()
+                .is_empty());
+        });
     }
 
-    /// Returns the next element in the Iterator.
-    #[allow(clippy::type_complexity)]
-    fn __next__(&mut self) -> IterNextOutput<(Vec<Mapping>, HashMap<String, Py<PyAny>>), &str> {
-        let try_recv = self.rx.recv();
-        match try_recv {
-            Ok(work_queue_member) => match work_queue_member {
-                WorkQueue::Finished => IterNextOutput::Return("Finished"),
-                WorkQueue::Result((mapping, id_num)) => {
-                    let data = self.data.remove(&id_num).unwrap();
-                    IterNextOutput::Yield((mapping, data))
-                }
-                _ => {
-                    eprintln!("Received wrong variant as a Result");
-                    IterNextOutput::Return("Wrong variant")
-                }
-            },
-            Err(RecvError) => {
-                eprintln!("Receiver Error");
-                IterNextOutput::Return("Receiver error - channel was closed")
+    #[test]
+    fn map_batch_drop_without_flush() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(1).unwrap();
+        Python::with_gil(|py| {
+            let names = al.seq_names().unwrap();
+            let seqs = PyList::empty(py);
+            for i in 0..5000 {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(&names[i % 4]))
+                    .unwrap();
+                seqs.append(read).unwrap();
             }
-        }
+            let mut res = al.map_batch_with(seqs, MapBatchOptions::default()).unwrap();
+            assert!(res.flush_on_drop);
+            assert!(res.next_result(py).unwrap().is_some());
+            let batch = Arc::downgrade(res.batch.as_ref().unwrap());
+            res.flush_on_drop = false;
+            drop(res);
+            // The collector thread exits and the remaining reads are skipped, releasing the batch
+            let start = std::time::Instant::now();
+            while batch.strong_count() > 0 {
+                assert!(start.elapsed() < Duration::from_secs(1));
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        });
     }
-}
-
-/// Initialise the python module and add the Aligner class.
-#[pymodule]
-fn mappy_rs(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
-    m.add_class::<Aligner>()?;
-    Ok(())
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::path::PathBuf;
+    #[test]
+    fn read_batch_item_name_seq_tuple() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let item: PyObject = ("read_1", "ACGT").into_py(py);
+            let (data, seq) = read_batch_item(item.as_ref(py), 3).unwrap();
+            assert!(seq == "ACGT");
+            assert!(data["name"].extract::<String>(py).unwrap() == "read_1");
+            assert!(data["id"].extract::<usize>(py).unwrap() == 3);
+            let item: PyObject = ("read_1", "ACGT", "extra").into_py(py);
+            assert!(read_batch_item(item.as_ref(py), 3).is_err());
+        });
+    }
 
-    fn get_resource_dir() -> PathBuf {
-        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        path.push("resources/test");
-        path
+    #[test]
+    fn attach_query_name() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let seqs = PyList::empty(py);
+            let chimera =
+                get_test_contig("Bacillus_subtilis") + &get_test_contig("Escherichia_coli_1");
+            let read = PyDict::new(py);
+            read.set_item("seq", chimera).unwrap();
+            read.set_item("name", "chimera").unwrap();
+            seqs.append(read).unwrap();
+            let mut res = al
+                .map_batch_with(
+                    seqs,
+                    MapBatchOptions {
+                        attach_query_name: true,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let (mappings, _) = res.next_result(py).unwrap().unwrap();
+            assert!(mappings.len() > 1);
+            assert!(mappings
+                .iter()
+                .all(|m| m.query_name.as_deref() == Some("chimera")));
+        });
     }
 
-    fn get_test_file(file: &str) -> PathBuf {
-        let mut path = get_resource_dir();
-        path.push(file);
-        path
+    #[test]
+    fn carry_metadata_keeps_cache_empty() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let seqs = PyList::empty(py);
+            for i in 0..100000 {
+                let read = PyDict::new(py);
+                read.set_item("seq", "ACGT").unwrap();
+                read.set_item("read_number", i).unwrap();
+                seqs.append(read).unwrap();
+            }
+            let mut res = al
+                .map_batch_with(
+                    seqs,
+                    MapBatchOptions {
+                        no_op: true,
+                        carry_metadata: true,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            assert!(res.data.is_empty());
+            let mut n = 0;
+            while let Some((_, data)) = res.next_result(py).unwrap() {
+                assert!(res.data.is_empty());
+                let read_number = data["read_number"].extract::<usize>(py).unwrap();
+                assert!(data["id"].extract::<usize>(py).unwrap() == read_number);
+                n += 1;
+            }
+            assert!(n == 100000);
+        });
     }
 
-    fn get_test_aligner() -> Result<Aligner, PyErr> {
-        let path = get_test_file("test.mmi");
-        Aligner::py_new(
-            Some(path),
-            None,
-            None,
-            None,
-            None,
-            None,
-            None,
-            None,
-            None,
-            4_usize,
-            None,
-            None,
-            None,
-            None,
-            None,
-        )
+    #[test]
+    fn no_metadata_keeps_cache_empty() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let seqs = PyList::empty(py);
+            for name in al.seq_names().unwrap() {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(&name)).unwrap();
+                seqs.append(read).unwrap();
+            }
+            let mut res = al
+                .map_batch_with(
+                    seqs,
+                    MapBatchOptions {
+                        yield_metadata: false,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            assert!(res.data.is_empty());
+            let mut n = 0;
+            while let Some(mappings) = res.__next__(py).unwrap() {
+                assert!(res.data.is_empty());
+                assert!(mappings.extract::<Vec<Mapping>>(py).unwrap().len() == 1);
+                n += 1;
+            }
+            assert!(n == 4);
+        });
     }
 
     #[test]
-    fn load_index() {
-        let al = get_test_aligner().unwrap();
-        assert!(al.aligner.has_index());
+    fn fastx_reader() {
+        let input = ">réad_1 desc\nACGT\nAC\n@read_2\nACGT\n+\nIIII\n";
+        let records = FastxReader::new(input.as_bytes())
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert!(records.len() == 2);
+        assert!(records[0].name == "réad_1" && records[0].seq == "ACGTAC");
+        assert!(records[1].qual.as_deref() == Some("IIII"));
+        for bad in ["ACGT\n", "éACGT\n"] {
+            let err = FastxReader::new(bad.as_bytes())
+                .next()
+                .unwrap()
+                .unwrap_err();
+            assert!(err.kind() == std::io::ErrorKind::InvalidData);
+        }
     }
 
     #[test]
-    fn test_property_k() {
-        let al = get_test_aligner().unwrap();
-        assert!(al.k().unwrap() == 15);
+    fn map_through_channels() {
+        let mut al = get_test_aligner().unwrap();
+        assert!(al.channels().is_err());
+        al.enable_threading(2).unwrap();
+        let (tx, rx) = al.channels().unwrap();
+        let names = al.seq_names().unwrap();
+        let producer = std::thread::spawn(move || {
+            for id_num in 0..40 {
+                let name = &names[id_num % names.len()];
+                tx.send((id_num, get_test_contig(name))).unwrap();
+            }
+            // An empty read fails to map
+            tx.send((40, String::new())).unwrap();
+        });
+        let mut ids = vec![];
+        let mut errors = vec![];
+        for result in rx {
+            match result {
+                Ok((mappings, id_num)) => {
+                    assert!(mappings.len() == 1);
+                    let names = al.seq_names().unwrap();
+                    assert!(mappings[0].target_name == names[id_num % names.len()]);
+                    ids.push(id_num);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        producer.join().unwrap();
+        ids.sort();
+        assert!(ids == (0..40).collect::<Vec<_>>());
+        assert!(errors.len() == 1);
+        assert!(matches!(&errors[0], MappyError::MapFailed(reason) if reason.contains("read 40")));
     }
 
     #[test]
-    fn test_property_w() {
-        let al = get_test_aligner().unwrap();
-        assert!(al.w().unwrap() == 10);
+    fn worker_panic_is_reported() {
+        pyo3::prepare_freethreaded_python();
+        let mut al = get_test_aligner().unwrap();
+        al.enable_threading(2).unwrap();
+        Python::with_gil(|py| {
+            let names = al.seq_names().unwrap();
+            let seqs = PyList::empty(py);
+            for i in 0..20 {
+                let read = PyDict::new(py);
+                read.set_item("seq", get_test_contig(&names[i % 4]))
+                    .unwrap();
+                seqs.append(read).unwrap();
+            }
+            // The worker threads survive the panic, so a second batch is mapped as well
+            for _ in 0..2 {
+                let (mut res, mut batch, back_off) =
+                    al.prepare_batch(MapBatchOptions::default()).unwrap();
+                batch.panic_on = Some(7);
+                al._map_batch(&mut res, seqs, back_off, batch, false)
+                    .unwrap();
+                let mut n = 0;
+                let mut errors = vec![];
+                loop {
+                    match res.next_result(py) {
+                        Ok(Some(_)) => n += 1,
+                        Ok(None) => break,
+                        Err(e) => errors.push(e.to_string()),
+                    }
+                }
+                assert!(n == 19);
+                assert!(errors.len() == 1);
+                assert!(errors[0].contains("Failed to map read 7: Injected panic"));
+            }
+        });
     }
 
     #[test]
-    fn test_property_n_seq() {
-        let al = get_test_aligner().unwrap();
-        assert!(al.n_seq().unwrap() == 4);
+    fn failed_read_is_raised() {
+        pyo3::prepare_freethreaded_python();
+        let panic = std::panic::catch_unwind(|| panic!("minimap2 blew up")).unwrap_err();
+        assert!(panic_message(&*panic) == "minimap2 blew up");
+        Python::with_gil(|py| {
+            let mut res = AlignmentBatchResultIter::new();
+            res.data.insert(0, HashMap::new());
+            res.data.insert(1, HashMap::new());
+            res.tx
+                .send(WorkQueue::Failed(0, panic_message(&*panic), None))
+                .unwrap();
+            res.tx.send(WorkQueue::Result((vec![], 1, None))).unwrap();
+            res.tx.send(WorkQueue::Finished).unwrap();
+            let err = res.next_result(py).unwrap_err();
+            assert!(err
+                .to_string()
+                .contains("Failed to map read 0: minimap2 blew up"));
+            // The rest of the batch is still returned
+            assert!(res.next_result(py).unwrap().is_some());
+            assert!(res.next_result(py).unwrap().is_none());
+            assert!(res.data.is_empty());
+        });
     }
 
     #[test]
-    fn test_property_seq_names() {
-        let al = get_test_aligner().unwrap();
-        let expected = vec![
-            "Bacillus_subtilis",
-            "Enterococcus_faecalis",
-            "Escherichia_coli_1",
-            "Escherichia_coli_2",
-        ];
-        let mut seq_names = al.seq_names().unwrap();
-        seq_names.sort();
-        assert!(seq_names == expected);
+    fn on_error_modes() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            for on_error in ["raise", "warn", "skip"] {
+                let mut res = AlignmentBatchResultIter::new();
+                res.on_error = OnError::parse(on_error).unwrap();
+                res.data.insert(0, HashMap::new());
+                res.tx
+                    .send(WorkQueue::Failed(
+                        0,
+                        String::from("Sequence is empty"),
+                        None,
+                    ))
+                    .unwrap();
+                res.tx
+                    .send(WorkQueue::Result((vec![], 1, Some(HashMap::new()))))
+                    .unwrap();
+                res.tx.send(WorkQueue::Finished).unwrap();
+                let mut ids = vec![];
+                loop {
+                    match res.next_result(py) {
+                        Ok(Some((mappings, data))) => {
+                            assert!(mappings.is_empty());
+                            ids.push(data.get("id").map(|id| id.extract::<usize>(py).unwrap()));
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            assert!(on_error == "raise");
+                            assert!(e.to_string().contains("Sequence is empty"));
+                        }
+                    }
+                }
+                match on_error {
+                    "warn" => assert!(ids == vec![Some(0), None]),
+                    _ => assert!(ids == vec![None]),
+                }
+                assert!(res.data.is_empty());
+            }
+            assert!(OnError::parse("ignore").is_err());
+        });
     }
 
     #[test]
-    fn test_get_seq() {
+    fn read_counts() {
         let al = get_test_aligner().unwrap();
-        let contig = "Bacillus_subtilis";
-        let expected = "AGAGTGAAGCCAATATTCCGATAACGATTGCTTTCATGATATCCCTCATTCTGGCATTATTTTTTTATACTATACTATTC\
-                        GATATCGCACAGATCAATGGAGTCGTGAGAAAATAAACATGTTTTGCGAACCGCTATGTGTGGAAGACAAAAAATGGAGG\
-                        TGAAATTGATGGAAGCAAAGACACAGGCGTACTTTTTTCAGGATGATGGCAGGATTCCGAATCACCCTGATTTTCCGCTC\
-                        GTTGTGTATCAAAACGCACTCAAGGACACCGGTCAGGCAGAGCGGATCGTCAACCGGCATGGCTGGTCAAACAGCTGGTC\
-                        GGGGAGTGTTTTTCCATACCATCATTATCACAGCAATACGCATGAAGTCCTGATTGCAGTTCGGGGAGAGGCTGTGATTC";
-        let seq = al
-            .seq(String::from(contig), 0, 2147483647)
-            .unwrap()
+        for seq in [get_test_contig("Bacillus_subtilis"), String::from("ACGTA")] {
+            al.map(seq, None, false, false, None, None, false, None, None, None)
+                .unwrap();
+        }
+        al.map_best(get_test_contig("Escherichia_coli_1"), false, false)
             .unwrap();
-        assert!(seq == *expected);
+        assert!(al.total_reads().unwrap() == 3);
+        assert!(al.total_unmapped().unwrap() == 1);
+        assert!(al.clone_with_shared_index().total_reads().unwrap() == 0);
     }
 
     #[test]
-    fn map_one() {
+    fn total_ref_len() {
         let al = get_test_aligner().unwrap();
-        let mappings = al.map(
-            String::from("AGAGCAGGTAGGATCGTTGAAAAAAGAGTACTCAGGATTCCATTCAACTTTTACTGATTTGAAGCGTACTGTTTATGGCC\
-                          AAGAATATTTACGTCTTTACAACCAATACGCAAAAAAAGGTTCATTGAGTTTGGTTGTGATTTGATGAAAATTACTGAGA\
-                          ATAACAGGATTATTAAGCTGATTGATGAACTAAATCAGCTTAATAAATATTCTTTGCAGATAGGAATATTTGGGGAAAAT\
-                          GATTCTTTTATGGCGATGTTGGCCCAAGTTCATGAATTTGGGGTGACTATTCGTCCCAAAGGTCGTTTTCTTGTTATACC\
-                          ACTTATGAAAAAGTATAGAGGTAAAAGTCCACGTCAATTTGATTTGTTTTTTATGCAAACTAAAGAAAATCACAAGTTTT"),
-            None, true, false).unwrap();
+        assert!(al.total_ref_len().unwrap() == 1600);
+    }
+
+    #[test]
+    fn map_one_read() {
+        let contig = get_test_contig("Bacillus_subtilis");
+        let mappings = super::map_one(get_test_file("test.mmi"), contig).unwrap();
         assert!(mappings.len() == 1);
-        assert!(mappings[0].get_target_start().unwrap() == 0);
-        assert!(mappings[0].get_target_end().unwrap() == 400);
+        assert!(mappings[0].target_name == "Bacillus_subtilis");
+        assert!(super::map_one(get_test_file("missing.mmi"), String::from("ACGT")).is_err());
+    }
+
+    #[test]
+    fn mapping_overlaps() {
+        let mapping = |target_name: &str, target_start, target_end| Mapping {
+            target_name: String::from(target_name),
+            target_start,
+            target_end,
+            ..no_op_mappings().remove(0)
+        };
+        let a = mapping("contig_1", 100, 200);
+        let overlapping = mapping("contig_1", 150, 300);
+        assert!(a.overlaps(&overlapping) && overlapping.overlaps(&a));
+        assert!(a.overlap_len(&overlapping) == 50);
+        let adjacent = mapping("contig_1", 200, 300);
+        assert!(!a.overlaps(&adjacent));
+        assert!(a.overlap_len(&adjacent) == 0);
+        let disjoint = mapping("contig_1", 400, 500);
+        assert!(!a.overlaps(&disjoint));
+        assert!(a.overlap_len(&disjoint) == 0);
+        assert!(!a.overlaps(&mapping("contig_2", 100, 200)));
+        assert!(a.overlap_len(&a) == 100);
+    }
+
+    #[test]
+    fn test_try_next() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let mut res = AlignmentBatchResultIter::new();
+            assert!(res.try_next(py).unwrap().is_none());
+            res.data.insert(0, HashMap::new());
+            res.tx.send(WorkQueue::Result((vec![], 0, None))).unwrap();
+            let (mappings, data) = res.try_next(py).unwrap().unwrap();
+            assert!(mappings.is_empty());
+            assert!(data.is_empty());
+            assert!(res.try_next(py).unwrap().is_none());
+            res.tx.send(WorkQueue::Finished).unwrap();
+            assert!(res.try_next(py).unwrap().is_none());
+            assert!(res.finished);
+        });
     }
 }
@@ -4,7 +4,12 @@ This set of tests roughly recreate those that are in lib.rs but use the
 compiled package.
 """
 from pathlib import Path
+import asyncio
 import copy
+import pickle
+import random
+import time
+import warnings
 from itertools import repeat
 
 import pytest
@@ -14,6 +19,7 @@ import mappy_rs
 RESOURCES = Path(__file__).parent.resolve().parent.resolve() / "resources/test"
 MMI_FILE = RESOURCES / "test.mmi"
 FA_FILE = RESOURCES / "test.fa"
+FA_GZ_FILE = RESOURCES / "test.fa.gz"
 
 
 def read_fasta(fh):
@@ -95,6 +101,15 @@ def test_property_w(al):
     assert al.w == 10
 
 
+def test_index_stats(al):
+    stats = al.index_stats()
+    assert stats["k"] == 15
+    assert stats["w"] == 10
+    assert stats["n_seq"] == 4
+    assert stats["total_len"] == sum(al.seq_lens)
+    assert stats["mid_occ"] > 0
+
+
 def test_property_seq_names(al):
     expected = [
         "Bacillus_subtilis",
@@ -107,6 +122,19 @@ def test_property_seq_names(al):
     assert seq_names == expected
 
 
+def test_iter_seqs(al, fasta_file):
+    with open(fasta_file, "rt") as fh:
+        expected = [(name, len(seq)) for name, seq in read_fasta(fh)]
+    assert al.iter_seqs() == expected
+
+
+def test_seq_name_id_round_trip(al):
+    for name in al.seq_names:
+        assert al.id_to_seq_name(al.seq_name_to_id(name)) == name
+    assert al.seq_name_to_id("not_a_contig") is None
+    assert al.id_to_seq_name(al.n_seq) is None
+
+
 def test_get_seq(al):
     contig = "Bacillus_subtilis"
     expected = (
@@ -119,6 +147,14 @@ def test_get_seq(al):
     )
     seq = al.seq(contig)
     assert seq == expected
+    seq = al.seq(contig, 100, 1000)
+    assert len(seq) == 300
+    assert seq == expected[100:]
+    assert al.seq(contig, -10, -1) == expected[-10:-1]
+    assert al.seq(contig, -10) == expected[-10:]
+    assert al.seq(contig, -1, -10) is None
+    with pytest.raises(NotImplementedError):
+        al.seq(contig, mask=True)
 
 
 def test_map_one(al):
@@ -137,6 +173,16 @@ def test_map_one(al):
     assert mapping.target_end == 400
 
 
+def test_map_md_cs_none(al, fasta_list):
+    seq = fasta_list[0]["seq"]
+    (mapping,) = al.map(seq)
+    assert mapping.MD is None
+    assert mapping.cs is None
+    (mapping,) = al.map(seq, cs=True, MD=True)
+    assert mapping.MD
+    assert mapping.cs
+
+
 def test_map_batch_100000(al, fasta_iter):
     al.enable_threading(4)
     iter_ = repeat(next(fasta_iter), 100000)
@@ -159,7 +205,7 @@ def test_map_batch_100000_no_backoff(al, fasta_iter):
         excinfo
     )
     assert (
-        "Is your fastq batch larger than 50000? Perhaps try"
+        "could not be added as the queue is full. Perhaps try"
         " `map_batch` with back_off=True?" in str(excinfo)
     )
 
@@ -225,3 +271,1034 @@ def test_map_batch_fail_exhausted_iter(al, fasta_iter):
     al.enable_threading(2)
     mappings = al.map_batch(fasta_iter)
     assert len(list(mappings)) == 0
+
+
+def test_versions():
+    assert mappy_rs.minimap2_version().startswith("2.")
+    assert mappy_rs.mappy_rs_version()
+
+
+def test_map_batch_overlapping(al, fasta_list):
+    al.enable_threading(2)
+    first = [
+        {"id": f"first_{i}", "seq": d["seq"]}
+        for i, d in enumerate(fasta_list)
+    ]
+    second = [
+        {"id": f"second_{i}", "seq": d["seq"]}
+        for i, d in enumerate(fasta_list)
+    ]
+    first_results = al.map_batch(first)
+    second_results = al.map_batch(second)
+    second_ids = [data["id"] for _, data in second_results]
+    first_ids = [data["id"] for _, data in first_results]
+    assert sorted(first_ids) == sorted(d["id"] for d in first)
+    assert sorted(second_ids) == sorted(d["id"] for d in second)
+
+
+def test_copy_shares_index(al, fasta_list):
+    al_2 = copy.copy(al)
+    del al
+    mappings = al_2.map(fasta_list[0]["seq"])
+    assert len(mappings) == 1
+
+
+def test_mapq_clamp(al, fasta_list):
+    seq = fasta_list[0]["seq"]
+    assert al.map(seq, mapq_max=10)[0].mapq == 10
+    assert al.map(seq, mapq_min=255)[0].mapq == 255
+    with pytest.raises(ValueError):
+        al.map(seq, mapq_min=10, mapq_max=5)
+    al.enable_threading(2)
+    results = list(al.map_batch(fasta_list * 10, mapq_max=60))
+    assert len(results) == len(fasta_list) * 10
+    assert all(m.mapq <= 60 for mappings, _ in results for m in mappings)
+
+
+def test_map_batch_max_queue_bytes(al, fasta_list):
+    al.enable_threading(2)
+    results = list(al.map_batch(fasta_list * 10, max_queue_bytes=1000))
+    assert len(results) == len(fasta_list) * 10
+    assert all(mappings for mappings, _ in results)
+    # A single read longer than the budget is still mapped
+    results = list(al.map_batch(fasta_list, max_queue_bytes=100))
+    assert len(results) == len(fasta_list)
+    with pytest.raises(ValueError):
+        al.map_batch(fasta_list, max_queue_bytes=0)
+
+
+@pytest.mark.parametrize("empty", [[], ()])
+def test_map_batch_empty(al, empty):
+    al.enable_threading(2)
+    start = time.perf_counter()
+    assert list(al.map_batch(empty)) == []
+    assert time.perf_counter() - start < 0.1
+    assert al.map_batch(empty).collect() == []
+
+
+def test_map_batch_cancel(al, fasta_list):
+    al.enable_threading(2)
+    mappings = al.map_batch(fasta_list * 1000)
+    next(mappings)
+    mappings.cancel()
+    assert len(list(mappings)) == 0
+
+
+def test_map_batch_to_tsv(al, fasta_list, tmp_path):
+    al.enable_threading(2)
+    output = tmp_path / "mappings.tsv"
+    assert al.map_batch(fasta_list).to_tsv(output) == len(fasta_list)
+    header, *rows = output.read_text().splitlines()
+    assert header.split("\t")[:3] == ["read_id", "query_name", "query_start"]
+    assert len(rows) == len(fasta_list)
+    assert all(
+        len(row.split("\t")) == len(header.split("\t")) for row in rows
+    )
+
+
+def test_map_batch_drop_without_flush(al, fasta_list):
+    al.enable_threading(2)
+    mappings = al.map_batch(fasta_list * 1000)
+    assert mappings.flush_on_drop
+    next(mappings)
+    mappings.flush_on_drop = False
+    del mappings
+    # The cancelled batch frees up the workers straight away
+    mappings = list(al.map_batch(fasta_list))
+    assert len(mappings) == len(fasta_list)
+
+
+def test_cigar_str(al, fasta_list):
+    (mapping,) = al.map(fasta_list[0]["seq"])
+    cigar_str = mapping.cigar_str
+    assert cigar_str == "".join(
+        f"{n}{'MIDNSHP=X'[op]}" for n, op in mapping.cigar
+    )
+    assert all(mapping.cigar_str == cigar_str for _ in range(10))
+
+
+def test_map_paf(al, fasta_list):
+    seq = fasta_list[1]["seq"]
+    (hit,) = al.map_paf(seq, "read_1")
+    (mapping,) = al.map(seq)
+    assert isinstance(hit, mappy_rs.PafHit)
+    assert (hit.query_name, hit.query_length) == ("read_1", 400)
+    assert hit.target_name == "Enterococcus_faecalis"
+    assert hit.target_length == 400
+    assert hit.strand == "+"
+    assert (hit.target_start, hit.target_end) == (
+        mapping.target_start,
+        mapping.target_end,
+    )
+    assert hit.residue_matches == mapping.match_len
+    assert hit.alignment_block_length == mapping.block_len
+    assert hit.mapping_quality == mapping.mapq
+    assert hit.tags[0] == "tp:A:P"
+    assert str(hit) == mapping.to_paf("read_1", 400)
+    assert al.map_paf(seq, "read_1", query_len=500)[0].query_length == 500
+
+
+def test_reset_buffer(al, fasta_list):
+    def positions():
+        return [
+            (m.target_name, m.target_start, m.target_end, m.cigar_str)
+            for m in al.map(fasta_list[0]["seq"])
+        ]
+
+    before = positions()
+    al.reset_buffer()
+    assert positions() == before
+
+
+def test_aligner_bad_minimizer_params(fasta_file):
+    with pytest.raises(ValueError, match="`k` must be between 1 and 28"):
+        mappy_rs.Aligner(fasta_file, k=29)
+    with pytest.raises(ValueError, match="`w` must be between 1 and 255"):
+        mappy_rs.Aligner(fasta_file, w=0)
+
+
+def test_index_from_gzipped_fasta(fasta_list):
+    al = mappy_rs.Aligner(str(FA_GZ_FILE))
+    mappings = al.map(fasta_list[0]["seq"])
+    assert len(mappings) == 1
+
+
+def test_trans_strand(al, fasta_list):
+    seq = fasta_list[3]["seq"]
+    assert all(m.trans_strand is None for m in al.map(seq))
+    splice_al = mappy_rs.Aligner(str(FA_FILE), preset="splice")
+    mappings = splice_al.map(seq[:151] + seq[261:])
+    assert len(mappings) == 1
+    assert mappings[0].trans_strand == 1
+
+
+def test_map_batch_numpy(al, fasta_list):
+    np = pytest.importorskip("numpy")
+    al.enable_threading(2)
+    seqs = [fasta_list[0]["seq"], fasta_list[3]["seq"]]
+    arr = np.array([list(seq.encode()) for seq in seqs], dtype=np.uint8)
+    results = sorted(
+        al.map_batch_numpy(arr), key=lambda result: result[1]["row"]
+    )
+    assert [data["row"] for _, data in results] == [0, 1]
+    contigs = ["Bacillus_subtilis", "Escherichia_coli_2"]
+    for mappings, data in results:
+        assert len(mappings) == 1
+        assert mappings[0].ctg == contigs[data["row"]]
+    # The options of `map_batch` are honoured, including when fed lazily
+    results = al.map_batch_numpy(
+        arr, deterministic=True, yield_metadata=False, max_inflight=1
+    )
+    assert [mappings[0].ctg for mappings in results] == contigs
+    arr[1, 0] = 200
+    with pytest.raises(ValueError, match="Row 1 is not an ASCII sequence"):
+        al.map_batch_numpy(arr)
+    results = list(al.map_batch_numpy(arr, on_error="skip"))
+    assert [data["row"] for _, data in results] == [0]
+    with pytest.raises(TypeError, match="unexpected keyword argument"):
+        al.map_batch_numpy(arr, max_hit=1)
+
+
+def test_thread_utilization(al, fasta_list):
+    al.enable_threading(2)
+    assert al.thread_utilization() == [False, False]
+    mappings = al.map_batch(fasta_list * 1000)
+    seen_busy = False
+    for _ in mappings:
+        seen_busy = seen_busy or any(al.thread_utilization())
+    assert seen_busy
+    al.wait()
+    assert al.thread_utilization() == [False, False]
+
+
+def test_min_query_len(al, fasta_list):
+    assert al.map("ACGTA", min_query_len=50) == []
+    al.enable_threading(2)
+    seqs = [{"id": "short", "seq": "ACGTA"}, fasta_list[0]]
+    results = {
+        data["id"]: mappings
+        for mappings, data in al.map_batch(seqs, min_query_len=50)
+    }
+    assert len(results["short"]) == 0
+    assert len(results[fasta_list[0]["id"]]) == 1
+
+
+def test_map_batch_stop_iteration(al, fasta_list):
+    al.enable_threading(2)
+    mappings = al.map_batch(fasta_list[:1])
+    result, data = next(mappings)
+    assert len(result) == 1
+    assert data == fasta_list[0]
+    with pytest.raises(StopIteration) as stop:
+        next(mappings)
+    assert stop.value.value is None
+    with pytest.raises(StopIteration):
+        next(mappings)
+
+
+def test_map_batch_ids(al, fasta_list):
+    al.enable_threading(2)
+    seqs = [
+        {"id": f"read_{i}", "seq": d["seq"]} for i, d in enumerate(fasta_list)
+    ]
+    seqs.append({"seq": fasta_list[0]["seq"]})
+    ids = [data["id"] for _, data in al.map_batch(seqs)]
+    expected = [f"read_{i}" for i in range(len(fasta_list))]
+    expected.append(len(fasta_list))
+    assert sorted(ids, key=str) == sorted(expected, key=str)
+
+
+def test_splice_flank_no_end_flt(fasta_file):
+    splice_flank = 0x40000
+    no_end_flt = 0x10000000
+    al = mappy_rs.Aligner(fasta_file, preset="splice")
+    assert al.flag & splice_flank
+    assert not al.flag & no_end_flt
+    al = mappy_rs.Aligner(
+        fasta_file, preset="splice", splice_flank=False, no_end_flt=True
+    )
+    assert not al.flag & splice_flank
+    assert al.flag & no_end_flt
+
+
+def test_map_batch_async(al, fasta_list):
+    al.enable_threading(2)
+
+    async def reads():
+        for read in fasta_list:
+            await asyncio.sleep(0)
+            yield read
+
+    async def map_reads():
+        results = []
+        async for mappings, data in al.map_batch_async(reads()):
+            results.append((mappings, data))
+        return results
+
+    results = asyncio.run(map_reads())
+    assert sorted(data["id"] for _, data in results) == [
+        read["id"] for read in fasta_list
+    ]
+    assert all(len(mappings) == 1 for mappings, _ in results)
+
+
+def test_map_batch_async_on_error(al, fasta_list):
+    al.enable_threading(2)
+    seqs = fasta_list[:4] + [{"seq": ""}]
+
+    async def reads():
+        for read in seqs:
+            yield read
+
+    async def map_reads(**kwargs):
+        return [r async for r in al.map_batch_async(reads(), **kwargs)]
+
+    with pytest.raises(RuntimeError, match="Sequence is empty"):
+        asyncio.run(map_reads())
+    with pytest.warns(RuntimeWarning):
+        results = asyncio.run(map_reads(on_error="warn"))
+    unmapped = [data["id"] for mappings, data in results if not mappings]
+    assert unmapped == [4]
+    results = asyncio.run(map_reads(on_error="skip", yield_metadata=False))
+    assert len(results) == 4
+    assert all(len(mappings) == 1 for mappings in results)
+
+
+def test_map_batch_async_options(al, fasta_list):
+    al.enable_threading(2)
+
+    async def reads():
+        for read in fasta_list:
+            yield read
+
+    async def map_reads(**kwargs):
+        return [r async for r in al.map_batch_async(reads(), **kwargs)]
+
+    results = asyncio.run(map_reads(deterministic=True, max_inflight=4))
+    assert [data["id"] for _, data in results] == [
+        read["id"] for read in fasta_list
+    ]
+    results = asyncio.run(map_reads(as_paf=True))
+    assert sorted(int(line.split("\t")[0]) for line in results) == [
+        read["id"] for read in fasta_list
+    ]
+    with pytest.raises(TypeError, match="unexpected keyword argument"):
+        asyncio.run(map_reads(backoff=False))
+
+
+def test_s1_s2(al, fasta_list):
+    mappings = al.map(fasta_list[0]["seq"])
+    assert len(mappings) == 1
+    assert mappings[0].s1 >= mappings[0].s2
+
+
+def test_reset(al, fasta_list):
+    al.enable_threading(2)
+    abandoned = al.map_batch(fasta_list * 1000)
+    next(abandoned)
+    al.reset()
+    assert len(list(abandoned)) < len(fasta_list) * 1000
+    results = list(al.map_batch(fasta_list))
+    assert sorted(data["id"] for _, data in results) == [
+        read["id"] for read in fasta_list
+    ]
+    assert all(len(mappings) == 1 for mappings, _ in results)
+
+
+def test_trimmed_fastq(al, fasta_list):
+    seq = "N" * 20 + fasta_list[0]["seq"] + "N" * 30
+    qual = "I" * len(seq)
+    (mapping,) = al.map(seq)
+    name, trimmed_seq, plus, trimmed_qual = mapping.trimmed_fastq(
+        "read_1", seq, qual
+    ).splitlines()
+    assert name == "@read_1"
+    assert plus == "+"
+    assert len(trimmed_seq) == mapping.q_en - mapping.q_st
+    assert len(trimmed_qual) == len(trimmed_seq)
+
+
+def test_mappy_compat(mmi_file, fasta_list):
+    al = mappy_rs.Aligner(mmi_file, mappy_compat=False)
+    assert not al.flag & 4
+    (mapping,) = al.map(fasta_list[0]["seq"])
+    assert mapping.cigar == []
+    al = mappy_rs.Aligner(mmi_file)
+    assert al.flag & 4
+
+
+def test_attach_query_name(al, fasta_list):
+    al.enable_threading(2)
+    seqs = [{"name": f"read_{d['id']}", "seq": d["seq"]} for d in fasta_list]
+    mappings = [
+        m
+        for result, _ in al.map_batch(seqs, attach_query_name=True)
+        for m in result
+    ]
+    assert len(mappings) == len(fasta_list)
+    assert sorted(m.query_name for m in mappings) == sorted(
+        d["name"] for d in seqs
+    )
+    for result, _ in al.map_batch(fasta_list):
+        assert all(m.query_name is None for m in result)
+
+
+def test_map_batch_mapq_fn(al, fasta_list):
+    al.enable_threading(2)
+    scores = []
+
+    def mapq_fn(s1, s2, block_len):
+        scores.append((s1, s2, block_len))
+        return 42
+
+    results = list(al.map_batch(fasta_list, mapq_fn=mapq_fn))
+    mappings = [m for result, _ in results for m in result]
+    assert len(mappings) == len(fasta_list)
+    assert all(m.mapq == 42 for m in mappings)
+    assert sorted(scores) == sorted(
+        (m.s1, m.s2, m.block_len) for m in mappings
+    )
+
+
+def test_map_batch_backoff_config(al, fasta_list):
+    al.enable_threading(2)
+    results = list(
+        al.map_batch(fasta_list, max_backoff_attempts=2, initial_backoff_ms=1)
+    )
+    assert len(results) == len(fasta_list)
+
+
+def test_map_batch_name_seq_tuples(al, fasta_list):
+    al.enable_threading(2)
+    seqs = [(f"r{d['id']}", d["seq"]) for d in fasta_list]
+    results = list(al.map_batch(seqs))
+    assert len(results) == len(seqs)
+    assert sorted(data["name"] for _, data in results) == sorted(
+        name for name, _ in seqs
+    )
+    for mappings, data in results:
+        assert mappings
+        assert data["seq"] == seqs[data["id"]][1]
+
+
+def test_map_batch_no_op(al):
+    al.enable_threading(4)
+    seqs = [{"seq": "ACGT"} for _ in range(10000)]
+    results = list(al.map_batch(seqs, no_op=True))
+    assert sorted(data["id"] for _, data in results) == list(range(10000))
+    for mappings, _ in results:
+        assert len(mappings) == 1
+        assert mappings[0].target_name == "Hello"
+        assert mappings[0].mapq == 60
+
+
+def test_map_batch_result_bound(al, fasta_list):
+    al.enable_threading(2)
+    results = list(al.map_batch(fasta_list * 10, result_bound=1))
+    assert len(results) == len(fasta_list) * 10
+    # A slow consumer holds the workers back rather than failing the batch
+    mappings = al.map_batch(iter(fasta_list * 100), result_bound=2)
+    time.sleep(0.5)
+    assert al.work_queue_len > 0
+    assert len(list(mappings)) == len(fasta_list) * 100
+    with pytest.raises(ValueError):
+        al.map_batch(fasta_list, result_bound=0)
+
+
+def test_target_subseq(al, fasta_list):
+    for mapping in al.map(fasta_list[0]["seq"][50:350]):
+        subseq = mapping.target_subseq(al)
+        assert len(subseq) == mapping.target_end - mapping.target_start
+        assert subseq == al.seq(
+            mapping.target_name, mapping.target_start, mapping.target_end
+        )
+
+
+def test_map_batch_carry_metadata(al, fasta_list):
+    al.enable_threading(2)
+    seqs = [dict(d, name=f"read_{d['id']}") for d in fasta_list]
+    results = list(al.map_batch(seqs, carry_metadata=True))
+    assert len(results) == len(seqs)
+    for mappings, data in results:
+        assert mappings
+        assert data["name"] == f"read_{data['id']}"
+        assert data["seq"] == seqs[data["id"]]["seq"]
+
+
+def test_map_batch_on_error(al, fasta_list):
+    al.enable_threading(2)
+    seqs = fasta_list[:4] + [{"SEQ": "ACGT"}, {"seq": ""}]
+    with pytest.raises(KeyError):
+        al.map_batch(seqs)
+    with pytest.raises(RuntimeError, match="Sequence is empty"):
+        list(al.map_batch(seqs[:4] + seqs[5:]))
+    with pytest.warns(RuntimeWarning):
+        results = list(al.map_batch(seqs, on_error="warn"))
+    assert len(results) == 6
+    unmapped = sorted(data["id"] for mappings, data in results if not mappings)
+    assert unmapped == [4, 5]
+    results = list(al.map_batch(seqs, on_error="skip"))
+    assert sorted(data["id"] for _, data in results) == [0, 1, 2, 3]
+    with pytest.raises(ValueError):
+        al.map_batch(seqs, on_error="ignore")
+
+
+def test_map_batch_bad_option(al, fasta_list):
+    al.enable_threading(2)
+    with pytest.raises(TypeError, match="unexpected keyword argument"):
+        al.map_batch(fasta_list, max_hit=1)
+    with pytest.raises(TypeError, match="argument 'chunk_size'"):
+        al.map_batch(fasta_list, chunk_size="10")
+    # `back_off` can still be given positionally
+    assert len(list(al.map_batch(fasta_list, False))) == len(fasta_list)
+
+
+def test_frag_id(mmi_file, fasta_list):
+    al = mappy_rs.Aligner(mmi_file, preset="sr", max_frag_len=800)
+    mappings = al.map(fasta_list[0]["seq"])
+    assert mappings
+    assert all(m.frag_id == 0 for m in mappings)
+
+
+def test_q_len(al, fasta_list):
+    seq = fasta_list[0]["seq"]
+    mappings = al.map(seq)
+    assert mappings
+    assert all(m.q_len == len(seq) for m in mappings)
+    al.enable_threading(2)
+    for mappings, data in al.map_batch(fasta_list):
+        assert all(m.q_len == len(data["seq"]) for m in mappings)
+
+
+def test_map_timed(al, fasta_list):
+    seq = fasta_list[1]["seq"]
+    mappings, elapsed_ms = al.map_timed(seq)
+    assert elapsed_ms >= 0
+    assert [str(m) for m in mappings] == [str(m) for m in al.map(seq)]
+
+
+def test_index_batch_size(fasta_file, fasta_list):
+    al = mappy_rs.Aligner(fasta_file, index_batch_size=500)
+    assert al.n_parts > 1
+    assert sorted(al.seq_names) == sorted(
+        mappy_rs.Aligner(fasta_file).seq_names
+    )
+    for d in fasta_list[:4]:
+        assert any(m.is_primary for m in al.map(d["seq"]))
+
+
+def test_split_prefix(fasta_file, fasta_list):
+    chimera = fasta_list[0]["seq"] + fasta_list[2]["seq"]
+    for split_prefix in [None, "mappy_rs_split"]:
+        al = mappy_rs.Aligner(
+            fasta_file, index_batch_size=500, split_prefix=split_prefix
+        )
+        assert al.n_parts > 1
+        mappings = al.map(chimera)
+        assert sum(m.is_primary for m in mappings) == 2
+        n_supplementary = sum(m.is_supplementary for m in mappings)
+        assert n_supplementary == (split_prefix is not None)
+
+
+def test_query_spanning_and_clips(al, fasta_list):
+    chimera = fasta_list[0]["seq"] + fasta_list[2]["seq"]
+    by_target = {m.target_name: m for m in al.map(chimera)}
+    bacillus = by_target["Bacillus_subtilis"]
+    assert not bacillus.is_query_spanning(len(chimera))
+    assert bacillus.left_clip(len(chimera)) == 0
+    assert 390 <= bacillus.right_clip(len(chimera)) <= 410
+    (mapping,) = al.map(fasta_list[0]["seq"])
+    assert mapping.is_query_spanning(400)
+    assert mapping.left_clip(400) == mapping.right_clip(400) == 0
+
+
+def test_mapping_sort_keys(al, fasta_list):
+    chimera = fasta_list[2]["seq"] + fasta_list[0]["seq"]
+    mappings = al.map(chimera)
+    assert len(mappings) > 1
+    by_position = sorted(mappings, key=mappy_rs.Mapping.sort_key_position)
+    assert by_position[0].target_name == "Bacillus_subtilis"
+    assert [m.sort_key_position() for m in by_position] == sorted(
+        (m.target_name, m.target_start, m.target_end) for m in mappings
+    )
+    by_score = sorted(mappings, key=mappy_rs.Mapping.sort_key_score)
+    assert [m.mapq for m in by_score] == sorted(m.mapq for m in mappings)
+
+
+def test_read_counts(al, fasta_list):
+    al.map(fasta_list[0]["seq"])
+    al.map("ACGTA")
+    assert (al.total_reads, al.total_unmapped) == (2, 1)
+    al.enable_threading(2)
+    seqs = fasta_list[:4] + [{"seq": "ACGTA"}, {"seq": "ACGTA"}]
+    assert len(list(al.map_batch(seqs))) == 6
+    assert (al.total_reads, al.total_unmapped) == (8, 3)
+
+
+def test_map_batch_generator_lazy(al, fasta_list):
+    al.enable_threading(4)
+    yielded = 0
+
+    def reads():
+        nonlocal yielded
+        for i in range(50000):
+            yielded += 1
+            yield fasta_list[i % len(fasta_list)]
+
+    results = al.map_batch(reads())
+    peak = n = 0
+    for n, _ in enumerate(results, 1):
+        if n == 1:
+            # The generator is still being drained as results arrive
+            assert yielded < 50000
+        peak = max(peak, al.work_queue_len)
+    assert n == 50000
+    assert peak <= 50000
+
+
+def test_scoring_validation(mmi_file, fasta_list):
+    al = mappy_rs.Aligner(mmi_file, scoring=(2, 4, 4, 2, 24, 1, 1))
+    assert len(al.map(fasta_list[0]["seq"])) == 1
+    with pytest.raises(ValueError, match="4, 6 or 7"):
+        mappy_rs.Aligner(mmi_file, scoring=(2, 4, 4))
+    with pytest.raises(ValueError, match="only contain integers"):
+        mappy_rs.Aligner(mmi_file, scoring=(2, 4, "4", 2))
+
+
+def test_decode_flags(mmi_file):
+    al = mappy_rs.Aligner(mmi_file, extra_flags=4 | 64, mappy_compat=False)
+    flags = al.decode_flags()
+    assert "MM_F_CIGAR" in flags
+    assert "MM_F_OUT_CS" in flags
+    assert all(name.startswith("MM_F_") for name in flags)
+
+
+def test_map_batch_collect(al, fasta_list):
+    al.enable_threading(2)
+    collected = al.map_batch_collect(fasta_list)
+    assert sorted(collected) == list(range(len(fasta_list)))
+    assert all(len(mappings) == 1 for mappings in collected.values())
+    seqs = fasta_list + [{"seq": ""}]
+    with pytest.raises(mappy_rs.MapFailed):
+        al.map_batch_collect(seqs)
+    collected = al.map_batch_collect(seqs, on_error="skip", max_hits=0)
+    assert sorted(collected) == list(range(len(fasta_list)))
+    assert all(not mappings for mappings in collected.values())
+    with pytest.raises(TypeError, match="unexpected keyword argument"):
+        al.map_batch_collect(fasta_list, backoff=False)
+
+
+def test_prebuilt_index_ignores_k(mmi_file, fasta_file):
+    with pytest.warns(UserWarning, match="k=21 ignored"):
+        al = mappy_rs.Aligner(mmi_file, k=21)
+    assert al.k == 15
+    assert mappy_rs.Aligner(fasta_file, k=21).k == 21
+
+
+def test_map_batch_result_collect(al, fasta_list):
+    al.enable_threading(2)
+    results = al.map_batch(fasta_list).collect()
+    assert len(results) == len(fasta_list)
+    assert sorted(data["id"] for _, data in results) == sorted(
+        d["id"] for d in fasta_list
+    )
+
+
+def test_preset(mmi_file):
+    assert mappy_rs.Aligner(mmi_file, preset="map-ont").preset == "map-ont"
+    assert mappy_rs.Aligner(mmi_file).preset is None
+
+
+def test_seq_ambiguous_bases(tmp_path, fasta_list):
+    seq = fasta_list[0]["seq"][:100] + "RYKMSWN" + fasta_list[0]["seq"][100:]
+    ref = tmp_path / "ambiguous.fa"
+    ref.write_text(f">ambiguous\n{seq}\n")
+    al = mappy_rs.Aligner(str(ref))
+    # IUPAC codes are stored as N in the index, so are not warned about
+    with warnings.catch_warnings():
+        warnings.simplefilter("error")
+        retrieved = al.seq("ambiguous")
+    assert retrieved == seq[:100] + "N" * 7 + seq[107:]
+
+
+def test_add_seq(fasta_file, mmi_file):
+    al = mappy_rs.Aligner(fasta_file)
+    contig = "".join(random.Random(42).choices("ACGT", k=1000))
+    al.add_seq("random_contig", contig)
+    assert al.seq_names[-1] == "random_contig"
+    assert al.seq("random_contig") == contig
+    mappings = al.map(contig[200:700])
+    assert [m.target_name for m in mappings] == ["random_contig"]
+    with pytest.raises(ValueError):
+        al.add_seq("random_contig", contig)
+    with pytest.raises(RuntimeError, match="prebuilt"):
+        mappy_rs.Aligner(mmi_file).add_seq("random_contig", contig)
+    split = mappy_rs.Aligner(fasta_file, index_batch_size=500)
+    with pytest.raises(RuntimeError, match="parts"):
+        split.add_seq("random_contig", contig)
+
+
+def test_occ_frac(tmp_path):
+    rng = random.Random(7)
+    unit = "".join(rng.choices("ACGT", k=100))
+    flank = "".join(rng.choices("ACGT", k=2000))
+    ref = tmp_path / "repeats.fa"
+    ref.write_text(f">repeats\n{flank}{unit * 50}{flank[::-1]}\n")
+    default = mappy_rs.Aligner(str(ref))
+    masked = mappy_rs.Aligner(str(ref), occ_frac=0.5)
+    assert masked.index_stats()["mid_occ"] < default.index_stats()["mid_occ"]
+    query = unit * 3
+    assert [str(m) for m in masked.map(query)] != [
+        str(m) for m in default.map(query)
+    ]
+    with pytest.raises(ValueError):
+        mappy_rs.Aligner(str(ref), occ_frac=2.0)
+
+
+def test_enable_threading_validation(al, fasta_list):
+    with pytest.raises(ValueError, match="at least 1"):
+        al.enable_threading(0)
+    al.enable_threading(2)
+    assert len(list(al.map_batch(fasta_list))) == len(fasta_list)
+
+
+def test_map_batch_no_metadata(al, fasta_list):
+    al.enable_threading(2)
+    results = list(al.map_batch(fasta_list, yield_metadata=False))
+    assert len(results) == len(fasta_list)
+    assert all(isinstance(mappings, list) for mappings in results)
+    assert all(
+        isinstance(m, mappy_rs.Mapping)
+        for mappings in results
+        for m in mappings
+    )
+    collected = al.map_batch(fasta_list, yield_metadata=False).collect()
+    assert [len(mappings) for mappings in collected] == [1] * len(fasta_list)
+
+
+def test_for_only(mmi_file, fasta_list):
+    seq = fasta_list[0]["seq"]
+    rev_comp = seq.translate(str.maketrans("ACGT", "TGCA"))[::-1]
+    al = mappy_rs.Aligner(mmi_file)
+    assert [m.strand for m in al.map(rev_comp)] == [-1]
+    forward = mappy_rs.Aligner(mmi_file, for_only=True)
+    assert "MM_F_FOR_ONLY" in forward.decode_flags()
+    assert all(m.strand == 1 for m in forward.map(rev_comp))
+    assert [m.strand for m in forward.map(seq)] == [1]
+
+
+def test_mapping_overlaps(al, fasta_list):
+    chimera = fasta_list[2]["seq"] + fasta_list[0]["seq"]
+    mappings = al.map(chimera)
+    for m in mappings:
+        assert m.overlaps(m)
+        assert m.overlap_len(m) == m.target_end - m.target_start
+    by_target = {m.target_name: m for m in mappings}
+    bacillus = by_target["Bacillus_subtilis"]
+    others = [m for m in mappings if m.target_name != "Bacillus_subtilis"]
+    assert not any(bacillus.overlaps(m) for m in others)
+    assert all(bacillus.overlap_len(m) == 0 for m in others)
+
+
+def test_map_one(mmi_file, fasta_list):
+    mappings = mappy_rs.map_one(mmi_file, fasta_list[0]["seq"])
+    assert [m.target_name for m in mappings] == ["Bacillus_subtilis"]
+
+
+def test_mappy_exceptions(al, fasta_list):
+    for exc in ("NoIndex", "MapFailed", "ThreadingNotEnabled", "QueueFull"):
+        assert issubclass(getattr(mappy_rs, exc), RuntimeError)
+    with pytest.raises(mappy_rs.ThreadingNotEnabled):
+        al.map_batch(fasta_list)
+    al.enable_threading(2)
+    with pytest.raises(mappy_rs.QueueFull):
+        al.map_batch(fasta_list * 2500, back_off=False)
+    with pytest.raises(mappy_rs.MapFailed):
+        al.map("")
+
+
+def test_map_batch_callback(al, fasta_list):
+    al.enable_threading(2)
+    results = []
+    al.map_batch_callback(
+        fasta_list, lambda mappings, data: results.append(data["id"])
+    )
+    assert sorted(results) == sorted(d["id"] for d in fasta_list)
+
+    def on_result(mappings, data):
+        raise ValueError("Stop")
+
+    with pytest.raises(ValueError, match="Stop"):
+        al.map_batch_callback(fasta_list, on_result)
+
+    names = []
+    al.map_batch_callback(
+        fasta_list,
+        lambda mappings, data: names.extend(m.query_name for m in mappings),
+        attach_query_name=True,
+        deterministic=True,
+    )
+    assert names == [str(d["id"]) for d in fasta_list]
+
+
+def test_map_batch_max_inflight(al, fasta_list):
+    al.enable_threading(2)
+    yielded = 0
+
+    def reads():
+        nonlocal yielded
+        for d in fasta_list:
+            yielded += 1
+            yield d
+
+    results = al.map_batch(reads(), max_inflight=4)
+    next(results)
+    time.sleep(0.2)
+    # One read has been returned, so at most 4 more can have been fed
+    assert yielded <= 5
+    assert len(list(results)) == len(fasta_list) - 1
+    results = al.map_batch(fasta_list, max_inflight=4)
+    assert len(list(results)) == len(fasta_list)
+    with pytest.raises(ValueError):
+        al.map_batch(fasta_list, max_inflight=0)
+
+
+def test_total_ref_len(al):
+    assert al.total_ref_len == 4 * 400
+    assert al.total_ref_len == sum(al.seq_lens)
+
+
+def test_mapping_paf_round_trip(al, fasta_list):
+    chimera = fasta_list[2]["seq"] + fasta_list[0]["seq"]
+    for m in al.map(chimera):
+        line = m.to_paf("chimera", len(chimera))
+        assert line.startswith(f"chimera\t{len(chimera)}\t")
+        name, length, parsed = mappy_rs.Mapping.from_paf(line)
+        assert (name, length) == ("chimera", len(chimera))
+        assert str(parsed) == str(m)
+        assert parsed.cigar == m.cigar
+    with pytest.raises(ValueError):
+        mappy_rs.Mapping.from_paf("chimera\t800\t0")
+
+
+def test_to_sam_hard_clip_secondary(al, fasta_list):
+    chimera = fasta_list[2]["seq"] + fasta_list[0]["seq"]
+    m = al.map(chimera)[0]
+    _, _, secondary = mappy_rs.Mapping.from_paf(
+        m.to_paf("chimera", len(chimera)).replace("tp:A:P", "tp:A:S")
+    )
+    assert not secondary.is_primary
+    primary_cigar = m.to_sam("chimera", chimera, None, True).split("\t")[5]
+    secondary_cigar = secondary.to_sam(
+        "chimera", chimera, hard_clip_secondary=True
+    ).split("\t")[5]
+    assert "S" in primary_cigar and "H" not in primary_cigar
+    assert "H" in secondary_cigar and "S" not in secondary_cigar
+
+
+def test_enable_threading_rayon(al, fasta_list):
+    al.enable_threading_rayon()
+    results = list(al.map_batch(fasta_list))
+    assert len(results) == len(fasta_list)
+    for mappings, data in results:
+        expected = al.seq_names[data["id"] % 4]
+        assert [m.target_name for m in mappings] == [expected]
+    with pytest.raises(ValueError):
+        al.enable_threading_rayon(0)
+
+
+def test_max_gap(mmi_file, fasta_list):
+    rng = random.Random(17)
+    insert = "".join(rng.choice("ACGT") for _ in range(300))
+    contig = fasta_list[0]["seq"]
+    read = contig[:200] + insert + contig[200:]
+    for max_gap, spanned in [(None, True), (100, False)]:
+        al = mappy_rs.Aligner(
+            mmi_file, mappy_compat=False, max_chain_iter=100, max_gap=max_gap
+        )
+        mappings = al.map(read)
+        assert mappings
+        # Only a chain across the insertion covers both halves of the read
+        spans_insert = any(
+            m.query_start < 200 and m.query_end > 500 for m in mappings
+        )
+        assert spans_insert == spanned
+
+
+def test_reload_index(al, fasta_list, tmp_path):
+    seq = fasta_list[0]["seq"]
+    assert al.map(seq)[0].target_name == "Bacillus_subtilis"
+    al.enable_threading(2)
+    reference = tmp_path / "reloaded.fa"
+    reference.write_text(f">reloaded\n{seq}\n")
+    al.reload_index(reference)
+    assert al.seq_names == ["reloaded"]
+    assert al.map(seq)[0].target_name == "reloaded"
+    results = list(al.map_batch([{"seq": seq}] * 10))
+    assert len(results) == 10
+    assert all(m[0].target_name == "reloaded" for m, _ in results)
+
+
+def test_reload_index_during_iteration(al, fasta_list, tmp_path):
+    seq = fasta_list[0]["seq"]
+    al.enable_threading(2)
+    reference = tmp_path / "reloaded.fa"
+    reference.write_text(f">reloaded\n{seq}\n")
+    results = al.map_batch([{"seq": seq}] * 10, max_inflight=1)
+    next(results)
+    with pytest.raises(RuntimeError, match="still being mapped"):
+        al.reload_index(reference)
+    assert len(list(results)) == 9
+    al.reload_index(reference)
+    assert al.seq_names == ["reloaded"]
+
+
+def test_pickle_aligner(fasta_file, fasta_list):
+    al = mappy_rs.Aligner(fasta_file, preset="map-ont", scoring=(2, 4, 4, 2))
+    restored = pickle.loads(pickle.dumps(al))
+    assert isinstance(restored, mappy_rs.Aligner)
+    assert restored.seq_names == al.seq_names
+    assert restored.preset == "map-ont"
+    mappings = restored.map(fasta_list[1]["seq"])
+    assert [m.target_name for m in mappings] == [al.seq_names[1]]
+    al.add_seq("random_contig", "".join(random.choices("ACGT", k=1000)))
+    with pytest.raises(TypeError):
+        pickle.dumps(al)
+
+
+def test_map_batch_deterministic(al, fasta_list):
+    al.enable_threading(4)
+
+    def run():
+        results = al.map_batch(fasta_list * 10, deterministic=True)
+        return [
+            (data["id"], [str(m) for m in mappings])
+            for mappings, data in results
+        ]
+
+    first = run()
+    assert [i for i, _ in first] == [d["id"] for d in fasta_list * 10]
+    assert run() == first
+
+
+def test_map_batch_dry_run(al, fasta_list):
+    al.enable_threading(2)
+    results = list(al.map_batch(fasta_list, dry_run=True))
+    assert len(results) == len(fasta_list)
+    assert all(len(mappings) == 0 for mappings, _ in results)
+    assert al.total_reads == 0
+    malformed = fasta_list[:5] + [{"sequence": "ACGT"}] + fasta_list[5:]
+    with pytest.raises(KeyError):
+        al.map_batch(malformed, dry_run=True)
+
+
+def test_contig_hit_counts(al, fasta_list):
+    al.map(fasta_list[0]["seq"])
+    al.enable_threading(2)
+    for _ in al.map_batch(fasta_list):
+        pass
+    counts = al.contig_hit_counts()
+    names = al.seq_names
+    assert counts == {
+        names[0]: 11,
+        names[1]: 10,
+        names[2]: 10,
+        names[3]: 10,
+    }
+    al.reset_contig_hit_counts()
+    assert al.contig_hit_counts() == {}
+
+
+def test_map_batch_as_paf(al, fasta_list):
+    al.enable_threading(2)
+    reads = [dict(d, name=f"read_{d['id']}") for d in fasta_list]
+    results = list(al.map_batch(reads, as_paf=True))
+    assert len(results) == len(reads)
+    for paf in results:
+        assert isinstance(paf, str)
+        fields = paf.split("\t")
+        assert len(fields) >= 12
+        assert fields[0].startswith("read_")
+        assert fields[1] == "400"
+        assert fields[5] in al.seq_names
+
+
+def test_threading_not_enabled_hint(al, fasta_list):
+    with pytest.raises(mappy_rs.ThreadingNotEnabled) as excinfo:
+        al.map_batch(fasta_list)
+    message = str(excinfo.value)
+    assert "Please call `.enable_threading(n_threads)`" in message
+    assert "not carried over to copies" in message
+
+
+@pytest.mark.parametrize("chunk_size", [1, 10, 64])
+def test_map_batch_chunk_size(al, fasta_list, chunk_size):
+    al.enable_threading(2)
+    reads = fasta_list * 10
+    results = list(al.map_batch(iter(reads), chunk_size=chunk_size))
+    assert sorted(data["id"] for _, data in results) == sorted(
+        d["id"] for d in reads
+    )
+    for mappings, data in results:
+        assert mappings[0].target_name == al.seq_names[data["id"] % 4]
+    with pytest.raises(ValueError):
+        al.map_batch(reads, chunk_size=0)
+    with pytest.raises(ValueError):
+        al.map_batch(reads, chunk_size=10, max_inflight=5)
+
+
+def test_strand_int():
+    assert int(mappy_rs.Strand.Forward) == 1
+    assert int(mappy_rs.Strand.Reverse) == -1
+    assert mappy_rs.Strand.Reverse == -1
+    assert mappy_rs.Strand.Forward != -1
+    assert mappy_rs.Strand.Forward == mappy_rs.Strand.Forward
+    assert mappy_rs.Strand.Forward != mappy_rs.Strand.Reverse
+
+
+def test_is_supplementary(al, fasta_list):
+    chimera = fasta_list[2]["seq"] + fasta_list[0]["seq"]
+    primary = [m for m in al.map(chimera) if m.is_primary]
+    assert len(primary) == 2
+    assert sum(m.is_supplementary for m in primary) == 1
+    assert not al.map(fasta_list[0]["seq"])[0].is_supplementary
+
+
+def test_map_batch_df(al, fasta_list):
+    pd = pytest.importorskip("pandas")
+    al.enable_threading(2)
+    df = pd.DataFrame(
+        {
+            "sequence": [d["seq"] for d in fasta_list[:8]],
+            "read_id": [f"read_{i}" for i in range(8)],
+            "channel": list(range(100, 108)),
+        }
+    )
+    results = list(al.map_batch_df(df, "sequence"))
+    assert len(results) == 8
+    for mappings, data in results:
+        i = data["id"]
+        assert data["read_id"] == f"read_{i}"
+        assert data["channel"] == 100 + i
+        assert data["seq"] == fasta_list[i]["seq"]
+        assert "sequence" not in data
+        assert mappings[0].target_name == al.seq_names[i % 4]
+    with pytest.raises(KeyError):
+        al.map_batch_df(df, "seq")
+    results = al.map_batch_df(
+        df, "sequence", deterministic=True, yield_metadata=False
+    )
+    assert [mappings[0].target_name for mappings in results] == [
+        al.seq_names[i % 4] for i in range(8)
+    ]
+
+
+def test_cs_bytes(al, fasta_list):
+    seq = fasta_list[0]["seq"]
+    (mapping,) = al.map(seq[:150] + "A" + seq[151:], cs=True, MD=True)
+    assert isinstance(mapping.cs_bytes, bytes)
+    assert mapping.cs_bytes.decode() == mapping.cs
+    assert bytes(memoryview(mapping.cs_bytes)) == mapping.cs.encode()
+    assert mapping.md_bytes.decode() == mapping.MD
+    (mapping,) = al.map(seq)
+    assert mapping.cs_bytes is None and mapping.md_bytes is None